@@ -19,10 +19,23 @@ pub enum Error {
         offset: u32,
         size: usize,
     },
+    ShortRead {
+        sector: u32,
+        offset: u32,
+        expected: usize,
+        read: usize,
+    },
     BadBlockGroupCount {
         by_blocks: u32,
         by_inodes: u32,
     },
+    BadInodeSize {
+        size: usize,
+    },
+    UnsupportedFeatures {
+        required: u32,
+    },
+    ReadOnly,
     InodeNotFound {
         inode: u32,
     },
@@ -36,6 +49,45 @@ pub enum Error {
     NotFound {
         name: String,
     },
+    NoSpaceLeft {
+        group_count: usize,
+    },
+    BadSeek {
+        base: u64,
+        offset: i64,
+    },
+    AlreadyExists {
+        name: String,
+    },
+    IsADirectory {
+        inode: u32,
+        name: String,
+    },
+    DirectoryNotEmpty {
+        inode: u32,
+        name: String,
+    },
+    NameTooLong {
+        name: String,
+    },
+    NotASymlink {
+        inode: u32,
+        name: String,
+    },
+    SymlinkLoop {
+        name: String,
+    },
+    CorruptDirectory {
+        inode: u32,
+        block: usize,
+        offset: usize,
+    },
+    BadFormatOptions {
+        reason: String,
+    },
+    BadResize {
+        reason: String,
+    },
     #[cfg(any(test, not(feature = "no_std")))]
     Io {
         inner: io::Error,
@@ -58,10 +110,24 @@ impl Display for Error {
                 size,
             } => write!(f, "address ouf of bounds: {}:{} with a block size of: {}",
                    sector, offset, size),
+            Error::ShortRead {
+                sector,
+                offset,
+                expected,
+                read,
+            } => write!(f, "short read at: {}:{}, expected {} bytes but got: {}",
+                   sector, offset, expected, read),
             Error::BadBlockGroupCount {
                 by_blocks,
                 by_inodes,
             } => write!(f, "conflicting block group count data; by blocks: {}, by inodes: {}", by_blocks, by_inodes),
+            Error::BadInodeSize {
+                size,
+            } => write!(f, "invalid inode size: {}", size),
+            Error::UnsupportedFeatures {
+                required,
+            } => write!(f, "unsupported required features: {:#x}", required),
+            Error::ReadOnly => write!(f, "filesystem is read-only"),
             Error::InodeNotFound {
                 inode,
             } => write!(f, "couldn't find inode no. {}", &inode),
@@ -75,6 +141,45 @@ impl Display for Error {
             Error::NotFound {
                 ref name,
             } => write!(f, "couldn't find {}", &name),
+            Error::NoSpaceLeft {
+                group_count,
+            } => write!(f, "no space left in any of {} block groups", group_count),
+            Error::BadSeek {
+                base,
+                offset,
+            } => write!(f, "invalid seek to {} + {}", base, offset),
+            Error::AlreadyExists {
+                ref name,
+            } => write!(f, "{} already exists", &name),
+            Error::IsADirectory {
+                inode,
+                ref name,
+            } => write!(f, "inode no. {} at: {} is a directory", inode, &name),
+            Error::DirectoryNotEmpty {
+                inode,
+                ref name,
+            } => write!(f, "directory inode no. {} at: {} is not empty", inode, &name),
+            Error::NameTooLong {
+                ref name,
+            } => write!(f, "file name too long: {}", &name),
+            Error::NotASymlink {
+                inode,
+                ref name,
+            } => write!(f, "inode no. {} at: {} is not a symbolic link", inode, &name),
+            Error::SymlinkLoop {
+                ref name,
+            } => write!(f, "too many levels of symbolic links at: {}", &name),
+            Error::CorruptDirectory {
+                inode,
+                block,
+                offset,
+            } => write!(f, "corrupt entry in directory inode no. {} at block: {}, offset: {}", inode, block, offset),
+            Error::BadFormatOptions {
+                ref reason,
+            } => write!(f, "invalid format options: {}", &reason),
+            Error::BadResize {
+                ref reason,
+            } => write!(f, "can't resize: {}", &reason),
             #[cfg(any(test, not(feature = "no_std")))]
             Error::Io {
                 ref inner,
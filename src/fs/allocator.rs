@@ -0,0 +1,473 @@
+use alloc::Vec;
+
+use error::Error;
+use sector::SectorSize;
+use volume::{Volume, WriteClass};
+
+use super::Ext2;
+
+/// The block or inode usage bitmap of a single block group
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Bitmap {
+    group: usize,
+    block: u32,
+    len: usize,
+    inner: Vec<u8>,
+}
+
+impl Bitmap {
+    /// Fails if `len` bits don't fit in `inner`, as happens with a corrupted
+    /// count of blocks or inodes per group
+    pub fn new(
+        group: usize,
+        block: u32,
+        len: usize,
+        inner: Vec<u8>,
+    ) -> Result<Bitmap, Error> {
+        if inner.len() * 8 < len {
+            return Err(Error::Other(format!(
+                "bitmap of block group {} doesn't fit in its block",
+                group
+            )));
+        }
+        Ok(Bitmap {
+            group,
+            block,
+            len,
+            inner,
+        })
+    }
+
+    /// Index of the block group this bitmap belongs to
+    pub fn group(&self) -> usize {
+        self.group
+    }
+
+    /// Address of the block this bitmap is stored in
+    pub fn block(&self) -> u32 {
+        self.block
+    }
+
+    /// Number of meaningful bits in this bitmap
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, index: usize) -> Result<bool, Error> {
+        self.check_index(index).map(|_| self.bit(index))
+    }
+
+    pub fn set(&mut self, index: usize) -> Result<(), Error> {
+        self.check_index(index)?;
+        self.inner[index >> 3] |= 1 << (index & 7);
+        Ok(())
+    }
+
+    pub fn clear(&mut self, index: usize) -> Result<(), Error> {
+        self.check_index(index)?;
+        self.inner[index >> 3] &= !(1 << (index & 7));
+        Ok(())
+    }
+
+    fn check_index(&self, index: usize) -> Result<(), Error> {
+        if index < self.len {
+            Ok(())
+        } else {
+            Err(Error::OutOfBounds { index })
+        }
+    }
+
+    /// The bit at `index`, which has to be below `len`
+    fn bit(&self, index: usize) -> bool {
+        self.inner[index >> 3] & (1 << (index & 7)) != 0
+    }
+
+    /// Finds the first unset bit at or after `goal`, wrapping around to the
+    /// beginning of the bitmap
+    pub fn find_free(&self, goal: usize) -> Option<usize> {
+        let goal = if goal < self.len { goal } else { 0 };
+        (goal..self.len)
+            .chain(0..goal)
+            .find(|&index| !self.bit(index))
+    }
+
+    /// Finds the first run of `len` consecutive unset bits
+    pub fn find_free_run(&self, len: usize) -> Option<usize> {
+        let mut start = 0;
+        for index in 0..self.len {
+            if self.bit(index) {
+                start = index + 1;
+            } else if index + 1 - start == len {
+                return Some(start);
+            }
+        }
+        None
+    }
+
+    pub fn count_free(&self) -> usize {
+        (0..self.len).filter(|&index| !self.bit(index)).count()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.inner
+    }
+}
+
+impl AsRef<[u8]> for Bitmap {
+    fn as_ref(&self) -> &[u8] {
+        &self.inner
+    }
+}
+
+impl<S: SectorSize, V: Volume<u8, S>> Ext2<S, V> {
+    /// Number of the first inode not reserved by the filesystem
+    pub fn first_inode(&self) -> u32 {
+        if self.version().0 == 0 {
+            11
+        } else {
+            self.superblock.inner.first_inode
+        }
+    }
+
+    /// Number of the first block of the block group `group`
+    pub fn group_first_block(&self, group: usize) -> u32 {
+        self.superblock.inner.first_data_block
+            + group as u32 * self.superblock.inner.blocks_per_group
+    }
+
+    /// Number of blocks in the block group `group`. Only the last group can
+    /// be shorter than `blocks_per_group`.
+    pub fn group_block_count(&self, group: usize) -> usize {
+        let first_block = self.group_first_block(group) as usize;
+        let blocks_per_group = self.superblock.inner.blocks_per_group as usize;
+        blocks_per_group.min(self.total_block_count() - first_block)
+    }
+
+    /// Block group containing the block `block`
+    pub fn block_group_of_block(&self, block: u32) -> usize {
+        let first_data_block = self.superblock.inner.first_data_block;
+        ((block - first_data_block) / self.superblock.inner.blocks_per_group)
+            as usize
+    }
+
+    /// Block group containing the inode `inode`
+    pub fn block_group_of_inode(&self, inode: u32) -> usize {
+        ((inode - 1) / self.superblock.inner.inodes_per_group) as usize
+    }
+
+    pub fn block_bitmap(&self, group: usize) -> Result<Bitmap, Error> {
+        let block = self.block_groups.inner[group].block_usage_addr;
+        let len = self.group_block_count(group);
+        Bitmap::new(group, block, len, self.read_block(block)?)
+    }
+
+    pub fn inode_bitmap(&self, group: usize) -> Result<Bitmap, Error> {
+        let block = self.block_groups.inner[group].inode_usage_addr;
+        let len = self.inodes_count();
+        Bitmap::new(group, block, len, self.read_block(block)?)
+    }
+
+    pub(crate) fn commit_bitmap(
+        &mut self,
+        bitmap: &Bitmap,
+    ) -> Result<(), Error> {
+        let data = bitmap.inner.clone();
+        self.write_block_as(bitmap.block, data, WriteClass::Bitmap)
+    }
+
+    /// Allocates a single block as close to `goal` as possible. The search
+    /// starts at `goal` in its own block group and continues with the
+    /// following groups.
+    pub fn allocate_block(&mut self, goal: u32) -> Result<u32, Error> {
+        let group_count = self.block_groups.inner.len();
+        let goal = if goal < self.superblock.inner.first_data_block
+            || goal as usize >= self.total_block_count()
+        {
+            self.superblock.inner.first_data_block
+        } else {
+            goal
+        };
+        let goal_group = self.block_group_of_block(goal);
+
+        for i in 0..group_count {
+            let group = (goal_group + i) % group_count;
+            if self.block_groups.inner[group].free_blocks_count == 0 {
+                continue;
+            }
+
+            let mut bitmap = self.block_bitmap(group)?;
+            let start = if i == 0 {
+                (goal - self.group_first_block(group)) as usize
+            } else {
+                0
+            };
+            let bit = match bitmap.find_free(start) {
+                Some(bit) => bit,
+                None => continue,
+            };
+
+            bitmap.set(bit)?;
+            self.commit_bitmap(&bitmap)?;
+
+            // the counts may be inconsistent with the bitmaps on a damaged
+            // filesystem, which is for the checker to report
+            {
+                let descr = &mut self.block_groups.inner[group];
+                descr.free_blocks_count =
+                    descr.free_blocks_count.saturating_sub(1);
+                let superblock = &mut self.superblock.inner;
+                superblock.free_blocks_count =
+                    superblock.free_blocks_count.saturating_sub(1);
+            }
+            self.commit_block_group(group)?;
+            self.commit_superblock()?;
+
+            return Ok(self.group_first_block(group) + bit as u32);
+        }
+
+        Err(Error::NoSpaceLeft { group_count })
+    }
+
+    pub fn free_block(&mut self, block: u32) -> Result<(), Error> {
+        if block < self.superblock.inner.first_data_block
+            || block as usize >= self.total_block_count()
+        {
+            return Err(Error::OutOfBounds {
+                index: block as usize,
+            });
+        }
+
+        let group = self.block_group_of_block(block);
+        let bit = (block - self.group_first_block(group)) as usize;
+        let mut bitmap = self.block_bitmap(group)?;
+        if !bitmap.get(bit)? {
+            return Err(Error::Other(format!(
+                "block {} is already free",
+                block
+            )));
+        }
+
+        bitmap.clear(bit)?;
+        self.commit_bitmap(&bitmap)?;
+
+        self.block_groups.inner[group].free_blocks_count += 1;
+        self.superblock.inner.free_blocks_count += 1;
+        self.commit_block_group(group)?;
+        self.commit_superblock()
+    }
+
+    /// Allocates an inode for a child of a directory in `parent_group`.
+    ///
+    /// Files are kept in the group of their parent whenever possible.
+    /// Directories are spread out instead, similarly to the Orlov allocator
+    /// of Linux: the group with the fewest directories that still has an
+    /// above-average share of free inodes and blocks is preferred.
+    pub fn allocate_inode(
+        &mut self,
+        parent_group: usize,
+        directory: bool,
+    ) -> Result<u32, Error> {
+        let group_count = self.block_groups.inner.len();
+        let first_inode = self.first_inode() as usize;
+        let inodes_per_group = self.inodes_count();
+
+        let preferred = if directory {
+            self.find_directory_group()
+        } else {
+            None
+        };
+        let start = preferred.unwrap_or(parent_group % group_count);
+
+        for i in 0..group_count {
+            let group = (start + i) % group_count;
+            if self.block_groups.inner[group].free_inodes_count == 0 {
+                continue;
+            }
+
+            let mut bitmap = self.inode_bitmap(group)?;
+            let reserved = first_inode
+                .saturating_sub(group * inodes_per_group + 1)
+                .min(inodes_per_group);
+            let bit = (reserved..bitmap.len()).find(|&bit| !bitmap.bit(bit));
+            let bit = match bit {
+                Some(bit) => bit,
+                None => continue,
+            };
+
+            bitmap.set(bit)?;
+            self.commit_bitmap(&bitmap)?;
+
+            {
+                let descr = &mut self.block_groups.inner[group];
+                descr.free_inodes_count =
+                    descr.free_inodes_count.saturating_sub(1);
+                if directory {
+                    descr.dirs_count += 1;
+                }
+                let superblock = &mut self.superblock.inner;
+                superblock.free_inodes_count =
+                    superblock.free_inodes_count.saturating_sub(1);
+            }
+            self.commit_block_group(group)?;
+            self.commit_superblock()?;
+
+            return Ok((group * inodes_per_group + bit + 1) as u32);
+        }
+
+        Err(Error::NoSpaceLeft { group_count })
+    }
+
+    pub fn free_inode(
+        &mut self,
+        inode: u32,
+        directory: bool,
+    ) -> Result<(), Error> {
+        if inode == 0 || inode as usize > self.total_inodes_count() {
+            return Err(Error::InodeNotFound { inode });
+        }
+
+        let group = self.block_group_of_inode(inode);
+        let bit = (inode as usize - 1) % self.inodes_count();
+        let mut bitmap = self.inode_bitmap(group)?;
+        if !bitmap.get(bit)? {
+            return Err(Error::Other(format!(
+                "inode {} is already free",
+                inode
+            )));
+        }
+
+        bitmap.clear(bit)?;
+        self.commit_bitmap(&bitmap)?;
+
+        {
+            let descr = &mut self.block_groups.inner[group];
+            descr.free_inodes_count += 1;
+            if directory {
+                descr.dirs_count = descr.dirs_count.saturating_sub(1);
+            }
+        }
+        self.superblock.inner.free_inodes_count += 1;
+        self.commit_block_group(group)?;
+        self.commit_superblock()
+    }
+
+    fn find_directory_group(&self) -> Option<usize> {
+        let group_count = self.block_groups.inner.len();
+        let average_inodes =
+            self.superblock.inner.free_inodes_count as usize / group_count;
+        let average_blocks =
+            self.superblock.inner.free_blocks_count as usize / group_count;
+
+        (0..group_count)
+            .filter(|&group| {
+                let descr = &self.block_groups.inner[group];
+                descr.free_inodes_count > 0
+                    && descr.free_inodes_count as usize >= average_inodes
+                    && descr.free_blocks_count as usize >= average_blocks
+            })
+            .min_by_key(|&group| self.block_groups.inner[group].dirs_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+
+    use alloc::Vec;
+
+    use sector::Size512;
+
+    use fs::Ext2;
+    use fs::mount::MountOptions;
+    use super::Bitmap;
+
+    #[test]
+    fn bitmap() {
+        let bits = vec![0b1011_1111, 0b0000_0110];
+        let mut bitmap = Bitmap::new(0, 3, 12, bits).unwrap();
+        assert!(bitmap.get(0).unwrap());
+        assert!(!bitmap.get(6).unwrap());
+        assert!(bitmap.get(9).unwrap());
+        assert_eq!(bitmap.count_free(), 12 - 9);
+
+        assert_eq!(bitmap.find_free(0), Some(6));
+        assert_eq!(bitmap.find_free(7), Some(8));
+        assert_eq!(bitmap.find_free(11), Some(11));
+        bitmap.set(11).unwrap();
+        assert_eq!(bitmap.find_free(11), Some(6));
+
+        bitmap.clear(0).unwrap();
+        assert_eq!(bitmap.find_free(11), Some(0));
+        for &bit in [0, 6, 8, 10].iter() {
+            bitmap.set(bit).unwrap();
+        }
+        assert_eq!(bitmap.find_free(0), None);
+        assert_eq!(bitmap.count_free(), 0);
+
+        // indices past the end are errors, not panics
+        assert!(bitmap.get(12).is_err());
+        assert!(bitmap.set(16).is_err());
+        assert!(bitmap.clear(100).is_err());
+        assert!(Bitmap::new(0, 3, 17, vec![0; 2]).is_err());
+
+        let bits = vec![0b1100_1011, 0b0000_0000];
+        let bitmap = Bitmap::new(0, 3, 12, bits).unwrap();
+        assert_eq!(bitmap.find_free_run(1), Some(2));
+        assert_eq!(bitmap.find_free_run(2), Some(4));
+        assert_eq!(bitmap.find_free_run(4), Some(8));
+        assert_eq!(bitmap.find_free_run(5), None);
+    }
+
+    #[test]
+    fn policy() {
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+        let mut fs = Ext2::<Size512, _>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap();
+        assert_eq!(fs.block_groups.inner.len(), 3);
+
+        // blocks are taken from the goal onwards
+        let goal = fs.group_first_block(2) + 1024;
+        assert_eq!(fs.allocate_block(goal).unwrap(), goal);
+        assert_eq!(fs.allocate_block(goal).unwrap(), goal + 1);
+        let free = fs.block_groups.inner[2].free_blocks_count;
+        fs.free_block(goal).unwrap();
+        let freed = fs.block_groups.inner[2].free_blocks_count;
+        assert_eq!(freed, free + 1);
+        assert_eq!(fs.allocate_block(goal).unwrap(), goal);
+
+        // files stay in the group of their parent
+        let inodes_per_group = fs.inodes_count() as u32;
+        let file = fs.allocate_inode(1, false).unwrap();
+        assert_eq!(fs.block_group_of_inode(file), 1);
+        let file = fs.allocate_inode(0, false).unwrap();
+        assert_eq!(fs.block_group_of_inode(file), 0);
+        assert!(file > fs.first_inode() && file <= inodes_per_group);
+
+        // directories leave the first group, which holds every directory of
+        // the image, for the ones with fewer directories
+        let dirs = |fs: &Ext2<Size512, Vec<u8>>| {
+            (0..3)
+                .map(|group| fs.block_groups.inner[group].dirs_count)
+                .collect::<Vec<_>>()
+        };
+        let before = dirs(&fs);
+        for _ in 0..4 {
+            let dir = fs.allocate_inode(0, true).unwrap();
+            assert!(fs.block_group_of_inode(dir) != 0);
+        }
+        let after = dirs(&fs);
+        assert_eq!(after[0], before[0]);
+        assert_eq!(after[1] + after[2], before[1] + before[2] + 4);
+
+        // a corrupted block count per group is an error, not a panic
+        fs.superblock.inner.blocks_per_group = 100_000;
+        assert!(fs.block_bitmap(0).is_err());
+    }
+}
@@ -0,0 +1,959 @@
+//! Consistency checks of a whole filesystem, similar to `e2fsck -n`.
+//!
+//! The checks run in the same passes as e2fsck: inodes and their block
+//! pointers, duplicate blocks, directory structure, connectivity, link counts
+//! and finally bitmaps and free counts.
+
+use alloc::{BTreeMap, BTreeSet, Vec};
+
+use error::Error;
+use sector::SectorSize;
+use volume::Volume;
+use sys::superblock::{FeaturesOptional, FeaturesRequired, Superblock};
+use sys::block_group::BlockGroupDescriptor;
+use sys::inode::{Inode as RawInode, TypePerm};
+
+use super::Ext2;
+use super::dir;
+use super::sync::Synced;
+
+/// Inode number of the root directory
+pub const ROOT_INODE: u32 = 2;
+
+/// Inode owning the reserved descriptor blocks of `SELF_RESIZE`
+pub const RESIZE_INODE: u32 = 7;
+/// Mask of the file type bits in `TypePerm`
+const TYPE_MASK: u16 = 0xf000;
+
+/// A single inconsistency found by `check`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Problem {
+    /// The root inode is not a directory in use
+    BadRoot,
+    /// The file type bits of the mode of an inode in use are invalid
+    BadMode { inode: u32, mode: u16 },
+    /// An inode in use has a deletion time
+    DeletionTimeSet { inode: u32 },
+    /// A block pointer points outside of the filesystem
+    BadBlock { inode: u32, block: u32 },
+    /// A block pointer points to a superblock, block group descriptor table,
+    /// bitmap or inode table
+    MetadataBlock { inode: u32, block: u32 },
+    /// The number of sectors of an inode doesn't match the blocks it points
+    /// to
+    BadSectorCount {
+        inode: u32,
+        stored: u32,
+        counted: u32,
+    },
+    /// A block is used more than once, by all of `inodes`
+    DuplicateBlock { block: u32, inodes: Vec<u32> },
+    /// A block of a directory doesn't hold a valid chain of entries; `index`
+    /// is the index of the block within the directory
+    CorruptDirectoryBlock { dir: u32, index: usize },
+    /// A directory entry points to an inode which isn't in use
+    DanglingEntry {
+        dir: u32,
+        name: Vec<u8>,
+        inode: u32,
+    },
+    /// The type indicator of a directory entry doesn't match its inode
+    BadEntryType {
+        dir: u32,
+        name: Vec<u8>,
+        ty: u8,
+        expected: u8,
+    },
+    /// The first entry of a directory isn't `.` pointing to itself
+    BadDotEntry { dir: u32 },
+    /// The second entry of a directory isn't `..`
+    MissingParentEntry { dir: u32 },
+    /// The `..` entry of a directory doesn't point to its parent
+    BadParentEntry {
+        dir: u32,
+        parent: u32,
+        expected: u32,
+    },
+    /// A directory is linked from more than one directory
+    DirectoryHardLink { dir: u32, parent: u32 },
+    /// An inode in use isn't linked from any reachable directory
+    Unreachable { inode: u32 },
+    /// The link count of an inode doesn't match the number of entries
+    /// pointing to it
+    LinkCount {
+        inode: u32,
+        stored: u16,
+        counted: u32,
+    },
+    /// A block is marked in the block bitmap but unused, or the other way
+    /// around
+    BlockBitmap { block: u32, marked: bool },
+    /// An inode is marked in the inode bitmap but unused, or the other way
+    /// around
+    InodeBitmap { inode: u32, marked: bool },
+    GroupFreeBlocks {
+        group: usize,
+        stored: u16,
+        counted: u32,
+    },
+    GroupFreeInodes {
+        group: usize,
+        stored: u16,
+        counted: u32,
+    },
+    GroupDirectories {
+        group: usize,
+        stored: u16,
+        counted: u32,
+    },
+    FreeBlocks { stored: u32, counted: u32 },
+    FreeInodes { stored: u32, counted: u32 },
+}
+
+/// The result of `check`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    /// Number of inodes in use
+    pub inodes_used: usize,
+    /// Number of directories in use
+    pub directories: usize,
+    /// Number of blocks in use, including filesystem metadata
+    pub blocks_used: usize,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks the consistency of the filesystem without modifying it
+pub fn check<S: SectorSize, V: Volume<u8, S>>(
+    fs: &Synced<Ext2<S, V>>,
+) -> Result<Report, Error> {
+    let mut checker = Checker::new(fs);
+    checker.run()?;
+    Ok(checker.report())
+}
+
+/// What the checker knows about a single inode
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct InodeInfo {
+    pub in_use: bool,
+    pub mode: u16,
+    pub links: u16,
+    /// Number of entries pointing to this inode, including `.` and `..`
+    pub counted: u32,
+    /// Inode the `..` entry points to, for directories
+    pub parent_entry: Option<u32>,
+    /// The directory this directory was found in
+    pub found_parent: Option<u32>,
+    pub unreachable: bool,
+}
+
+impl InodeInfo {
+    pub fn is_dir(&self) -> bool {
+        self.in_use && self.mode & TYPE_MASK == TypePerm::DIRECTORY.bits()
+    }
+}
+
+/// State shared by all passes, also used by the repair mode
+pub(crate) struct Checker<'a, S: 'a + SectorSize, V: 'a + Volume<u8, S>> {
+    pub fs: &'a Synced<Ext2<S, V>>,
+    pub superblock: Superblock,
+    pub groups: Vec<BlockGroupDescriptor>,
+    pub first_inode: u32,
+    pub block_size: usize,
+    pub problems: Vec<Problem>,
+    /// Blocks in use, indexed by block number
+    pub used: Vec<u8>,
+    /// Blocks holding filesystem metadata, indexed by block number
+    pub metadata: Vec<u8>,
+    /// Indexed by inode number, the first entry is unused
+    pub inodes: Vec<InodeInfo>,
+}
+
+fn get_bit(bits: &[u8], index: usize) -> bool {
+    bits[index >> 3] & (1 << (index & 7)) != 0
+}
+
+fn set_bit(bits: &mut [u8], index: usize) {
+    bits[index >> 3] |= 1 << (index & 7);
+}
+
+/// Whether the block pointers of an inode point to data blocks, unlike
+/// those of device files and fast symbolic links
+pub(crate) fn has_blocks(raw: &RawInode, block_size: usize) -> bool {
+    let ty = raw.type_perm.bits() & TYPE_MASK;
+    let ea_sectors = if raw.ext_attribute_block != 0 {
+        block_size as u32 >> 9
+    } else {
+        0
+    };
+    let fast_symlink =
+        ty == TypePerm::SYMLINK.bits() && raw.sectors_count == ea_sectors;
+
+    !fast_symlink
+        && (ty == TypePerm::FILE.bits()
+            || ty == TypePerm::DIRECTORY.bits()
+            || ty == TypePerm::SYMLINK.bits()
+            || ty == 0)
+}
+
+impl<'a, S: SectorSize, V: Volume<u8, S>> Checker<'a, S, V> {
+    pub fn new(fs: &'a Synced<Ext2<S, V>>) -> Checker<'a, S, V> {
+        let inner = fs.inner();
+        let superblock = inner.superblock.inner;
+        let blocks_count = superblock.blocks_count as usize;
+        let inodes_count = superblock.inodes_count as usize;
+
+        Checker {
+            fs,
+            superblock,
+            groups: inner.block_groups.inner.clone(),
+            first_inode: inner.first_inode(),
+            block_size: inner.block_size(),
+            problems: Vec::new(),
+            used: vec![0; (blocks_count + 7) / 8],
+            metadata: vec![0; (blocks_count + 7) / 8],
+            inodes: vec![InodeInfo::default(); inodes_count + 1],
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        self.mark_metadata();
+        self.pass1()?;
+        self.pass2()?;
+        self.pass3();
+        self.pass4();
+        self.pass5()
+    }
+
+    pub fn report(self) -> Report {
+        let blocks_count = self.superblock.blocks_count as usize;
+        let first_data_block = self.superblock.first_data_block as usize;
+        let inodes_used = self.inodes.iter().filter(|info| info.in_use).count();
+        let directories =
+            self.inodes.iter().filter(|info| info.is_dir()).count();
+        let blocks_used = (first_data_block..blocks_count)
+            .filter(|&block| get_bit(&self.used, block))
+            .count();
+
+        Report {
+            problems: self.problems,
+            inodes_used,
+            directories,
+            blocks_used,
+        }
+    }
+
+    pub fn is_used(&self, block: u32) -> bool {
+        get_bit(&self.used, block as usize)
+    }
+
+    pub fn is_metadata(&self, block: u32) -> bool {
+        get_bit(&self.metadata, block as usize)
+    }
+
+    fn is_reserved(&self, inode: u32) -> bool {
+        inode < self.first_inode && inode != ROOT_INODE
+    }
+
+    /// Whether blocks are reserved after each descriptor table for growing
+    /// it, owned by the resize inode
+    fn has_reserved_gdt(&self) -> bool {
+        let features_opt = self.superblock.features_opt;
+        features_opt.contains(FeaturesOptional::SELF_RESIZE)
+    }
+
+    /// Marks the superblocks, descriptor tables and the blocks reserved for
+    /// their growth, bitmaps and inode tables
+    fn mark_metadata(&mut self) {
+        let group_count = self.groups.len();
+        let descr_size = ::core::mem::size_of::<BlockGroupDescriptor>();
+        let mut table_blocks =
+            (group_count * descr_size + self.block_size - 1) / self.block_size;
+        if self.has_reserved_gdt() {
+            table_blocks += self.superblock.reserved_gdt_blocks as usize;
+        }
+        let inode_size = if self.superblock.rev_major == 0 {
+            ::core::mem::size_of::<RawInode>()
+        } else {
+            self.superblock.inode_size as usize
+        };
+        let inode_table_blocks = (self.superblock.inodes_per_group as usize
+            * inode_size + self.block_size - 1)
+            / self.block_size;
+
+        let mut blocks = Vec::new();
+        for group in 0..group_count {
+            if self.superblock.has_backup(group as u32) {
+                let first = self.superblock.first_data_block
+                    + group as u32 * self.superblock.blocks_per_group;
+                blocks.extend(first..first + 1 + table_blocks as u32);
+            }
+
+            let descr = self.groups[group];
+            blocks.push(descr.block_usage_addr);
+            blocks.push(descr.inode_usage_addr);
+            let table = descr.inode_table_block;
+            blocks.extend(table..table + inode_table_blocks as u32);
+        }
+
+        for block in blocks {
+            if block < self.superblock.blocks_count {
+                set_bit(&mut self.metadata, block as usize);
+                set_bit(&mut self.used, block as usize);
+            }
+        }
+    }
+
+    /// Pointers of an inode are only followed if they're inside the
+    /// filesystem and don't point to metadata
+    fn is_valid(&self, block: u32) -> bool {
+        block >= self.superblock.first_data_block
+            && block < self.superblock.blocks_count
+            && !get_bit(&self.metadata, block as usize)
+    }
+
+    /// All blocks referenced by an inode, including indirect blocks and its
+    /// extended attribute block, and all invalid pointers
+    pub fn inode_blocks(
+        &self,
+        fs: &Ext2<S, V>,
+        raw: &RawInode,
+    ) -> Result<(Vec<u32>, Vec<u32>), Error> {
+        let mut blocks = Vec::new();
+        let mut invalid = Vec::new();
+
+        let direct = raw.direct_pointer;
+        let roots = [
+            (raw.indirect_pointer, 1),
+            (raw.doubly_indirect, 2),
+            (raw.triply_indirect, 3),
+        ];
+        let pointers = direct
+            .iter()
+            .map(|&block| (block, 0))
+            .chain(roots.iter().cloned());
+
+        let mut stack = pointers
+            .filter(|&(block, _)| block != 0)
+            .collect::<Vec<_>>();
+        while let Some((block, depth)) = stack.pop() {
+            if !self.is_valid(block) {
+                invalid.push(block);
+                continue;
+            }
+
+            blocks.push(block);
+            if depth > 0 {
+                let table = fs.read_block(block)?;
+                stack.extend(
+                    table
+                        .chunks(4)
+                        .map(|entry| dir::read_u32(entry, 0))
+                        .filter(|&pointer| pointer != 0)
+                        .map(|pointer| (pointer, depth - 1)),
+                );
+            }
+        }
+
+        Ok((blocks, invalid))
+    }
+
+    /// Inode sanity, block pointer ranges and duplicate blocks
+    fn pass1(&mut self) -> Result<(), Error> {
+        let mut duplicates = BTreeSet::new();
+        let mut ea_blocks = BTreeSet::new();
+
+        for inode in self.fs.inodes() {
+            let inode = inode?;
+            let num = inode.num();
+            let raw = *inode.raw();
+            let in_use = raw.hard_links > 0;
+            let reserved = self.is_reserved(num);
+            self.inodes[num as usize] = InodeInfo {
+                in_use,
+                mode: raw.type_perm.bits(),
+                links: raw.hard_links,
+                ..InodeInfo::default()
+            };
+
+            if !in_use && !reserved {
+                continue;
+            }
+
+            // the reserved descriptor blocks are already marked as
+            // metadata; only the doubly indirect block is owned by the inode
+            if num == RESIZE_INODE && self.has_reserved_gdt() {
+                let block = raw.doubly_indirect;
+                if block != 0 && !self.is_valid(block) {
+                    self.problems.push(Problem::BadBlock { inode: num, block });
+                } else if block != 0 {
+                    set_bit(&mut self.used, block as usize);
+                }
+                continue;
+            }
+
+            let ty = raw.type_perm.bits() & TYPE_MASK;
+            if in_use && !reserved {
+                let valid =
+                    [0x1000, 0x2000, 0x4000, 0x6000, 0x8000, 0xa000, 0xc000];
+                if !valid.contains(&ty) {
+                    self.problems.push(Problem::BadMode {
+                        inode: num,
+                        mode: raw.type_perm.bits(),
+                    });
+                }
+                if raw.dtime != 0 {
+                    self.problems.push(Problem::DeletionTimeSet { inode: num });
+                }
+            }
+
+            if !has_blocks(&raw, self.block_size) {
+                continue;
+            }
+
+            let (blocks, invalid) = {
+                let fs = self.fs.inner();
+                self.inode_blocks(&fs, &raw)?
+            };
+            for block in invalid {
+                let problem = if block < self.superblock.blocks_count
+                    && block >= self.superblock.first_data_block
+                {
+                    Problem::MetadataBlock { inode: num, block }
+                } else {
+                    Problem::BadBlock { inode: num, block }
+                };
+                self.problems.push(problem);
+            }
+
+            let mut counted = blocks.len() as u32;
+            for &block in blocks.iter() {
+                if self.is_used(block) {
+                    duplicates.insert(block);
+                }
+                set_bit(&mut self.used, block as usize);
+            }
+
+            // extended attribute blocks are shared between inodes
+            let ea_block = raw.ext_attribute_block;
+            if ea_block != 0 {
+                counted += 1;
+                if !self.is_valid(ea_block) {
+                    self.problems.push(Problem::BadBlock {
+                        inode: num,
+                        block: ea_block,
+                    });
+                } else if ea_blocks.insert(ea_block) {
+                    if self.is_used(ea_block) {
+                        duplicates.insert(ea_block);
+                    }
+                    set_bit(&mut self.used, ea_block as usize);
+                }
+            }
+
+            let counted = counted * (self.block_size as u32 >> 9);
+            if in_use && raw.sectors_count != counted {
+                self.problems.push(Problem::BadSectorCount {
+                    inode: num,
+                    stored: raw.sectors_count,
+                    counted,
+                });
+            }
+        }
+
+        // a corrupted `inodes_count` might not even cover the root
+        let root = self.inodes.get(ROOT_INODE as usize);
+        if !root.map(|root| root.is_dir()).unwrap_or(false) {
+            self.problems.push(Problem::BadRoot);
+        }
+
+        if !duplicates.is_empty() {
+            self.pass1b(&duplicates)?;
+        }
+        Ok(())
+    }
+
+    /// Finds all owners of duplicate blocks
+    fn pass1b(&mut self, duplicates: &BTreeSet<u32>) -> Result<(), Error> {
+        let mut owners: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+
+        for inode in self.fs.inodes() {
+            let inode = inode?;
+            let num = inode.num();
+            let raw = *inode.raw();
+            if raw.hard_links == 0 && !self.is_reserved(num)
+                || !has_blocks(&raw, self.block_size)
+            {
+                continue;
+            }
+
+            let (blocks, _) = {
+                let fs = self.fs.inner();
+                self.inode_blocks(&fs, &raw)?
+            };
+            for block in blocks {
+                if duplicates.contains(&block) {
+                    owners.entry(block).or_insert_with(Vec::new).push(num);
+                }
+            }
+        }
+
+        for &block in duplicates {
+            self.problems.push(Problem::DuplicateBlock {
+                block,
+                inodes: owners.remove(&block).unwrap_or_else(Vec::new),
+            });
+        }
+        Ok(())
+    }
+
+    /// Directory structure, `.` and `..` entries and counting of links
+    fn pass2(&mut self) -> Result<(), Error> {
+        let filetype = self.superblock.rev_major > 0
+            && self.superblock
+                .features_req
+                .contains(FeaturesRequired::REQ_DIRECTORY_TYPE);
+        let total = self.inodes.len() as u32 - 1;
+
+        for num in 1..total + 1 {
+            if !self.inodes[num as usize].is_dir() {
+                continue;
+            }
+            let inode = self.fs.try_inode_nth(num as usize)?;
+
+            for (index, block) in inode.blocks().enumerate() {
+                let block = match block {
+                    Ok(Some((block, _))) => block,
+                    // directories can't be sparse
+                    Ok(None) => {
+                        self.problems.push(Problem::CorruptDirectoryBlock {
+                            dir: num,
+                            index,
+                        });
+                        continue;
+                    }
+                    Err(_) => {
+                        self.problems.push(Problem::CorruptDirectoryBlock {
+                            dir: num,
+                            index,
+                        });
+                        break;
+                    }
+                };
+                let mut offset = 0;
+                let mut position = 0;
+
+                while offset < block.len() {
+                    let record = match dir::read_record(&block, offset) {
+                        Some(ref record) if record.rec_len % 4 == 0 => *record,
+                        _ => {
+                            self.problems.push(Problem::CorruptDirectoryBlock {
+                                dir: num,
+                                index,
+                            });
+                            break;
+                        }
+                    };
+                    offset += record.rec_len;
+                    position += 1;
+
+                    let name = record.name(&block);
+                    if index == 0 && position == 1 {
+                        if record.inode != num || name != b"." {
+                            self.problems
+                                .push(Problem::BadDotEntry { dir: num });
+                        }
+                    } else if index == 0 && position == 2 {
+                        if record.inode == 0 || name != b".." {
+                            self.problems
+                                .push(Problem::MissingParentEntry { dir: num });
+                        } else {
+                            self.inodes[num as usize].parent_entry =
+                                Some(record.inode);
+                        }
+                    }
+
+                    if record.inode == 0 {
+                        continue;
+                    }
+
+                    if record.inode > total
+                        || !self.inodes[record.inode as usize].in_use
+                    {
+                        self.problems.push(Problem::DanglingEntry {
+                            dir: num,
+                            name: name.to_vec(),
+                            inode: record.inode,
+                        });
+                        continue;
+                    }
+
+                    self.inodes[record.inode as usize].counted += 1;
+                    let target = self.inodes[record.inode as usize];
+
+                    let type_perm = TypePerm::from_bits_truncate(target.mode);
+                    let expected = dir::entry_type(type_perm);
+                    if filetype && record.ty != expected {
+                        self.problems.push(Problem::BadEntryType {
+                            dir: num,
+                            name: name.to_vec(),
+                            ty: record.ty,
+                            expected,
+                        });
+                    }
+
+                    if name == b"." || name == b".." || !target.is_dir() {
+                        continue;
+                    }
+                    match target.found_parent {
+                        None => {
+                            self.inodes[record.inode as usize].found_parent =
+                                Some(num)
+                        }
+                        Some(_) => self.problems.push(
+                            Problem::DirectoryHardLink {
+                                dir: record.inode,
+                                parent: num,
+                            },
+                        ),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Connectivity of directories: every directory must be reachable from
+    /// the root and `..` must point to its parent
+    fn pass3(&mut self) {
+        let count = self.inodes.len();
+
+        // propagate reachability down from the root
+        let mut reachable = vec![false; count];
+        let root = ROOT_INODE as usize;
+        if root < count {
+            reachable[root] = self.inodes[root].is_dir();
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for num in 1..count {
+                let info = self.inodes[num];
+                let parent_reachable = info
+                    .found_parent
+                    .map(|parent| reachable[parent as usize])
+                    .unwrap_or(false);
+                if info.is_dir() && !reachable[num] && parent_reachable {
+                    reachable[num] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut unconnected = BTreeSet::new();
+        for num in 1..count {
+            let info = self.inodes[num];
+            if !info.is_dir() || self.is_reserved(num as u32) {
+                continue;
+            }
+
+            if !reachable[num] {
+                self.inodes[num].unreachable = true;
+
+                // only report the topmost directory of an unreachable tree,
+                // or a single directory of a cycle
+                let mut visited = BTreeSet::new();
+                let mut top = num as u32;
+                while let Some(parent) = self.inodes[top as usize].found_parent
+                {
+                    if !visited.insert(top) || visited.contains(&parent) {
+                        break;
+                    }
+                    top = parent;
+                }
+                unconnected.insert(top);
+                continue;
+            }
+
+            let expected = if num as u32 == ROOT_INODE {
+                ROOT_INODE
+            } else {
+                info.found_parent.unwrap_or(ROOT_INODE)
+            };
+            if let Some(parent) = info.parent_entry {
+                if parent != expected {
+                    self.problems.push(Problem::BadParentEntry {
+                        dir: num as u32,
+                        parent,
+                        expected,
+                    });
+                }
+            }
+        }
+
+        for inode in unconnected {
+            self.problems.push(Problem::Unreachable { inode });
+        }
+    }
+
+    /// Link counts and unattached inodes
+    fn pass4(&mut self) {
+        for num in 1..self.inodes.len() {
+            let info = self.inodes[num];
+            if !info.in_use || self.is_reserved(num as u32) || info.unreachable
+            {
+                continue;
+            }
+
+            if info.counted == 0 {
+                self.inodes[num].unreachable = true;
+                self.problems.push(Problem::Unreachable { inode: num as u32 });
+            } else if info.counted != info.links as u32 {
+                self.problems.push(Problem::LinkCount {
+                    inode: num as u32,
+                    stored: info.links,
+                    counted: info.counted,
+                });
+            }
+        }
+    }
+
+    /// Bitmaps and free counts against the usage found in the other passes
+    fn pass5(&mut self) -> Result<(), Error> {
+        let inodes_per_group = self.superblock.inodes_per_group as usize;
+        let mut free_blocks = 0;
+        let mut free_inodes = 0;
+
+        for group in 0..self.groups.len() {
+            let (block_bitmap, inode_bitmap, first_block) = {
+                let fs = self.fs.inner();
+                (
+                    fs.block_bitmap(group)?,
+                    fs.inode_bitmap(group)?,
+                    fs.group_first_block(group),
+                )
+            };
+
+            let mut group_free_blocks = 0;
+            for bit in 0..block_bitmap.len() {
+                let block = first_block + bit as u32;
+                let used = self.is_used(block);
+                let marked = block_bitmap.get(bit)?;
+                if used != marked {
+                    self.problems.push(Problem::BlockBitmap { block, marked });
+                }
+                if !used {
+                    group_free_blocks += 1;
+                }
+            }
+
+            let mut group_free_inodes = 0;
+            let mut group_directories = 0;
+            for bit in 0..inodes_per_group {
+                let inode = (group * inodes_per_group + bit + 1) as u32;
+                if inode as usize >= self.inodes.len() {
+                    break;
+                }
+                let info = self.inodes[inode as usize];
+                let used = info.in_use || inode < self.first_inode;
+                let marked = inode_bitmap.get(bit)?;
+                if used != marked {
+                    self.problems.push(Problem::InodeBitmap { inode, marked });
+                }
+                if !used {
+                    group_free_inodes += 1;
+                }
+                if info.is_dir() {
+                    group_directories += 1;
+                }
+            }
+
+            let descr = self.groups[group];
+            if descr.free_blocks_count as u32 != group_free_blocks {
+                self.problems.push(Problem::GroupFreeBlocks {
+                    group,
+                    stored: descr.free_blocks_count,
+                    counted: group_free_blocks,
+                });
+            }
+            if descr.free_inodes_count as u32 != group_free_inodes {
+                self.problems.push(Problem::GroupFreeInodes {
+                    group,
+                    stored: descr.free_inodes_count,
+                    counted: group_free_inodes,
+                });
+            }
+            if descr.dirs_count as u32 != group_directories {
+                self.problems.push(Problem::GroupDirectories {
+                    group,
+                    stored: descr.dirs_count,
+                    counted: group_directories,
+                });
+            }
+
+            free_blocks += group_free_blocks;
+            free_inodes += group_free_inodes;
+        }
+
+        if self.superblock.free_blocks_count != free_blocks {
+            self.problems.push(Problem::FreeBlocks {
+                stored: self.superblock.free_blocks_count,
+                counted: free_blocks,
+            });
+        }
+        if self.superblock.free_inodes_count != free_inodes {
+            self.problems.push(Problem::FreeInodes {
+                stored: self.superblock.free_inodes_count,
+                counted: free_inodes,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+
+    use genfs::{DirOptions, Fs};
+
+    use fs::Ext2;
+    use fs::mount::MountOptions;
+    use fs::sync::Synced;
+    use sector::Size512;
+
+    use super::{check, get_bit, Checker, Problem};
+
+    #[test]
+    fn check_image() {
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+
+        let mut fs = Synced::<Ext2<Size512, _>>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap();
+        let report = check(&fs).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert!(report.directories >= 2);
+
+        // changes made through the filesystem must keep it consistent
+        assert!(fs.create_dir(b"/check", &DirOptions::new()).is_ok());
+        assert!(fs.symlink(b"/check", b"/check/link").is_ok());
+        let report = check(&fs).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        {
+            let mut inner = fs.inner();
+            inner.superblock.inner.free_inodes_count += 1;
+            inner.block_groups.inner[0].dirs_count += 1;
+        }
+        let report = check(&fs).unwrap();
+        assert!(report.problems.iter().any(|problem| match *problem {
+            Problem::FreeInodes { .. } => true,
+            _ => false,
+        }));
+        assert!(report.problems.iter().any(|problem| match *problem {
+            Problem::GroupDirectories { group: 0, .. } => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn linux_entry_types() {
+        use fs::dir;
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+        let mut fs = Synced::<Ext2<Size512, _>>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap();
+        assert!(fs.create_dir(b"/linux", &DirOptions::new()).is_ok());
+        assert!(fs.create_dir(b"/linux/sub", &DirOptions::new()).is_ok());
+        assert!(fs.symlink(b"/linux", b"/linux/link").is_ok());
+        let mut linux = fs.find_inode(b"/linux").unwrap();
+        let sub = fs.find_inode(b"/linux/sub").unwrap().num();
+        let link = fs.find_inode_nofollow(b"/linux/link").unwrap().num();
+
+        // the block as Linux writes it, with EXT2_FT_DIR = 2 and
+        // EXT2_FT_SYMLINK = 7
+        let mut block = vec![0; 1024];
+        dir::write_record(&mut block, 0, linux.num(), 12, b".", 2);
+        dir::write_record(&mut block, 12, 2, 12, b"..", 2);
+        dir::write_record(&mut block, 24, sub, 12, b"sub", 2);
+        dir::write_record(&mut block, 36, link, 1024 - 36, b"link", 7);
+        assert_eq!(linux.write_at(0, &block).unwrap(), 1024);
+        let report = check(&fs).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        // EXT2_FT_REG_FILE
+        block[36 + 7] = 1;
+        assert_eq!(linux.write_at(0, &block).unwrap(), 1024);
+        let report = check(&fs).unwrap();
+        assert!(report.problems.iter().any(|problem| match *problem {
+            Problem::BadEntryType {
+                ty: 1, expected: 7, ..
+            } => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn reserved_gdt_blocks() {
+        use sys::superblock::FeaturesOptional;
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+        let fs = Synced::<Ext2<Size512, _>>::new(volume).unwrap();
+        let mut checker = Checker::new(&fs);
+        let first = checker.superblock.first_data_block;
+        let reserved = checker.superblock.reserved_gdt_blocks as u32;
+        assert!(reserved > 0);
+        // the last reserved block after the superblock and the single block
+        // of the descriptor table
+        let last = first + 1 + reserved;
+        checker.mark_metadata();
+        assert!(get_bit(&checker.metadata, last as usize));
+
+        let mut checker = Checker::new(&fs);
+        let mut features = checker.superblock.features_opt;
+        features.remove(FeaturesOptional::SELF_RESIZE);
+        checker.superblock.features_opt = features;
+        checker.mark_metadata();
+        assert!(!get_bit(&checker.metadata, last as usize));
+    }
+
+    #[test]
+    fn tiny_inodes_count() {
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+        let fs = Synced::<Ext2<Size512, _>>::new(volume).unwrap();
+        fs.inner().superblock.inner.inodes_count = 1;
+        let report = check(&fs).unwrap();
+        assert!(report.problems.iter().any(|problem| match *problem {
+            Problem::BadRoot => true,
+            _ => false,
+        }));
+    }
+}
@@ -0,0 +1,242 @@
+//! Manipulation of the linked list of directory entries inside of a single
+//! directory block
+
+use sys::inode::TypePerm;
+
+use super::metadata::FileType;
+
+/// Size of the fixed part of a directory entry: inode, record length, name
+/// length and type indicator
+pub const HEADER_SIZE: usize = 8;
+
+/// A single record in a directory block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Record {
+    pub offset: usize,
+    pub inode: u32,
+    pub rec_len: usize,
+    pub name_len: usize,
+    pub ty: u8,
+}
+
+impl Record {
+    pub fn name<'a>(&self, block: &'a [u8]) -> &'a [u8] {
+        let start = self.offset + HEADER_SIZE;
+        &block[start..start + self.name_len]
+    }
+}
+
+/// Minimum length of a record holding a name of `name_len` bytes. Records
+/// are always aligned to 4 bytes.
+pub fn record_len(name_len: usize) -> usize {
+    (HEADER_SIZE + name_len + 3) & !3
+}
+
+pub fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    buf[offset] as u16 | (buf[offset + 1] as u16) << 8
+}
+
+pub fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    buf[offset] as u32
+        | (buf[offset + 1] as u32) << 8
+        | (buf[offset + 2] as u32) << 16
+        | (buf[offset + 3] as u32) << 24
+}
+
+pub fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset] = value as u8;
+    buf[offset + 1] = (value >> 8) as u8;
+}
+
+pub fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset] = value as u8;
+    buf[offset + 1] = (value >> 8) as u8;
+    buf[offset + 2] = (value >> 16) as u8;
+    buf[offset + 3] = (value >> 24) as u8;
+}
+
+/// Reads the record at `offset`, returning `None` if its header doesn't fit
+/// in the block, its length isn't aligned, would reach past the end of the
+/// block or is too short for its name
+pub fn read_record(block: &[u8], offset: usize) -> Option<Record> {
+    if offset + HEADER_SIZE > block.len() {
+        return None;
+    }
+
+    let record = Record {
+        offset,
+        inode: read_u32(block, offset),
+        rec_len: read_u16(block, offset + 4) as usize,
+        name_len: block[offset + 6] as usize,
+        ty: block[offset + 7],
+    };
+
+    if record.rec_len < HEADER_SIZE
+        || record.rec_len % 4 != 0
+        || offset + record.rec_len > block.len()
+        || HEADER_SIZE + record.name_len > record.rec_len
+    {
+        None
+    } else {
+        Some(record)
+    }
+}
+
+pub fn write_record(
+    block: &mut [u8],
+    offset: usize,
+    inode: u32,
+    rec_len: usize,
+    name: &[u8],
+    ty: u8,
+) {
+    write_u32(block, offset, inode);
+    write_u16(block, offset + 4, rec_len as u16);
+    block[offset + 6] = name.len() as u8;
+    block[offset + 7] = ty;
+    let start = offset + HEADER_SIZE;
+    block[start..start + name.len()].copy_from_slice(name);
+}
+
+/// Inserts a new record into the first slot with enough slack, either an
+/// empty record or the unused tail of a live one. Returns `false` if there's
+/// no room left in this block.
+pub fn insert(block: &mut [u8], inode: u32, name: &[u8], ty: u8) -> bool {
+    let needed = record_len(name.len());
+    let mut offset = 0;
+
+    while let Some(record) = read_record(block, offset) {
+        let used = if record.inode == 0 {
+            0
+        } else {
+            record_len(record.name_len)
+        };
+
+        if record.rec_len >= used + needed {
+            if used == 0 {
+                write_record(block, offset, inode, record.rec_len, name, ty);
+            } else {
+                write_u16(block, offset + 4, used as u16);
+                write_record(
+                    block,
+                    offset + used,
+                    inode,
+                    record.rec_len - used,
+                    name,
+                    ty,
+                );
+            }
+            return true;
+        }
+
+        offset += record.rec_len;
+    }
+
+    false
+}
+
+/// Removes the record named `name` by merging it into the previous record,
+/// or by clearing its inode if it's the first record in the block. Returns
+/// the inode the record pointed to.
+pub fn remove(block: &mut [u8], name: &[u8]) -> Option<u32> {
+    let mut prev: Option<Record> = None;
+    let mut offset = 0;
+
+    while let Some(record) = read_record(block, offset) {
+        if record.inode != 0 && record.name(block) == name {
+            match prev {
+                Some(prev) => {
+                    let rec_len = prev.rec_len + record.rec_len;
+                    write_u16(block, prev.offset + 4, rec_len as u16);
+                }
+                None => write_u32(block, offset, 0),
+            }
+            return Some(record.inode);
+        }
+
+        prev = Some(record);
+        offset += record.rec_len;
+    }
+
+    None
+}
+
+/// Points the record named `name` at `inode`, returning the inode it pointed
+/// to before
+pub fn replace(block: &mut [u8], name: &[u8], inode: u32) -> Option<u32> {
+    let mut offset = 0;
+
+    while let Some(record) = read_record(block, offset) {
+        if record.inode != 0 && record.name(block) == name {
+            write_u32(block, offset, inode);
+            return Some(record.inode);
+        }
+
+        offset += record.rec_len;
+    }
+
+    None
+}
+
+/// Type indicator of a directory entry pointing to an inode of type
+/// `type_perm`
+pub fn entry_type(type_perm: TypePerm) -> u8 {
+    FileType::from_type_perm(type_perm).entry_type()
+}
+
+#[cfg(test)]
+mod tests {
+    use sys::inode;
+
+    use super::*;
+
+    #[test]
+    fn insert_remove() {
+        let mut block = vec![0_u8; 64];
+        write_record(&mut block, 0, 2, 12, b".", inode::DIRECTORY);
+        write_record(&mut block, 12, 2, 52, b"..", inode::DIRECTORY);
+
+        assert!(insert(&mut block, 11, b"foo", inode::FILE));
+        assert!(insert(&mut block, 12, b"bar_baz", inode::FILE));
+        assert!(!insert(&mut block, 13, b"no room left", inode::FILE));
+
+        let foo = read_record(&block, 24).unwrap();
+        assert_eq!(foo.inode, 11);
+        assert_eq!(foo.rec_len, 12);
+        assert_eq!(foo.name(&block), b"foo");
+        let bar = read_record(&block, 36).unwrap();
+        assert_eq!(bar.inode, 12);
+        assert_eq!(bar.rec_len, 28);
+
+        assert_eq!(remove(&mut block, b"foo"), Some(11));
+        assert_eq!(remove(&mut block, b"foo"), None);
+        assert_eq!(read_record(&block, 12).unwrap().rec_len, 24);
+
+        assert_eq!(remove(&mut block, b"."), Some(2));
+        assert_eq!(read_record(&block, 0).unwrap().inode, 0);
+        assert!(insert(&mut block, 14, b"abc", inode::FILE));
+        assert_eq!(read_record(&block, 0).unwrap().inode, 14);
+        assert_eq!(read_record(&block, 0).unwrap().rec_len, 12);
+
+        assert_eq!(replace(&mut block, b"..", 5), Some(2));
+        assert_eq!(read_record(&block, 12).unwrap().inode, 5);
+    }
+
+    #[test]
+    fn entry_types() {
+        // EXT2_FT_* as written by Linux and expected by e2fsck
+        let types = [
+            (TypePerm::FILE | TypePerm::U_READ, 1),
+            (TypePerm::DIRECTORY, 2),
+            (TypePerm::CHAR_DEVICE, 3),
+            (TypePerm::BLOCK_DEVICE, 4),
+            (TypePerm::FIFO, 5),
+            (TypePerm::SOCKET, 6),
+            (TypePerm::SYMLINK, 7),
+            (TypePerm::empty(), 0),
+        ];
+        for &(type_perm, ty) in types.iter() {
+            assert_eq!(entry_type(type_perm), ty);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use sys::superblock::Superblock;
+use sys::superblock::{FeaturesOptional, FeaturesROnly, FeaturesRequired};
+
+/// The feature set of a filesystem, as parsed from its superblock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Features {
+    pub optional: FeaturesOptional,
+    pub required: FeaturesRequired,
+    pub read_only: FeaturesROnly,
+}
+
+impl Features {
+    /// Revision 0 filesystems have no feature fields, they are all treated
+    /// as empty
+    pub fn new(superblock: &Superblock) -> Features {
+        if superblock.rev_major == 0 {
+            Features::empty()
+        } else {
+            Features {
+                optional: superblock.features_opt,
+                required: superblock.features_req,
+                read_only: superblock.features_ronly,
+            }
+        }
+    }
+
+    /// No features at all, as on revision 0 filesystems
+    pub fn empty() -> Features {
+        Features {
+            optional: FeaturesOptional::empty(),
+            required: FeaturesRequired::empty(),
+            read_only: FeaturesROnly::empty(),
+        }
+    }
+
+    /// Bits of required features that are not supported, including bits
+    /// unknown to `FeaturesRequired`. A filesystem using any of them can't be
+    /// mounted.
+    pub fn unsupported_required(&self) -> u32 {
+        let supported = FeaturesRequired::REQ_DIRECTORY_TYPE;
+        self.required.bits() & !supported.bits()
+    }
+
+    /// Bits of read-only features that are not supported, including bits
+    /// unknown to `FeaturesROnly`. A filesystem using any of them can only be
+    /// mounted read-only.
+    pub fn unsupported_read_only(&self) -> u32 {
+        let supported = FeaturesROnly::RONLY_SPARSE
+            | FeaturesROnly::RONLY_FILE_SIZE_64
+            | FeaturesROnly::RONLY_BTREE_DIRECTORY;
+        self.read_only.bits() & !supported.bits()
+    }
+
+    /// Whether the filesystem can be mounted at all
+    pub fn can_mount(&self) -> bool {
+        self.unsupported_required() == 0
+    }
+
+    /// Whether the filesystem can be mounted read-write
+    pub fn can_write(&self) -> bool {
+        self.can_mount() && self.unsupported_read_only() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sys::superblock::{FeaturesOptional, FeaturesROnly, FeaturesRequired};
+
+    use super::Features;
+
+    #[test]
+    fn features() {
+        let mut features = Features {
+            optional: FeaturesOptional::empty(),
+            required: FeaturesRequired::REQ_DIRECTORY_TYPE,
+            read_only: FeaturesROnly::RONLY_SPARSE
+                | FeaturesROnly::RONLY_FILE_SIZE_64,
+        };
+        assert!(features.can_mount());
+        assert!(features.can_write());
+
+        features.read_only.insert(FeaturesROnly::RONLY_BTREE_DIRECTORY);
+        assert!(features.can_write());
+        features.read_only = unsafe { ::core::mem::transmute(0x0009_u32) };
+        assert_eq!(features.unsupported_read_only(), 0x0008);
+        assert!(features.can_mount());
+        assert!(!features.can_write());
+
+        features.required.insert(FeaturesRequired::REQ_REPLAY_JOURNAL);
+        assert_eq!(features.unsupported_required(), 0x0004);
+        assert!(!features.can_mount());
+        assert!(!features.can_write());
+    }
+}
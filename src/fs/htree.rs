@@ -0,0 +1,787 @@
+//! Hashed B-tree indexes of directories flagged with `Flags::HASH_DIR`.
+//!
+//! The first block of an indexed directory starts with the `.` and `..`
+//! records, the latter spanning the rest of the block so that the index is
+//! invisible to drivers that don't know about it. The root of the index
+//! follows, mapping ranges of name hashes to blocks of the directory: either
+//! leaves holding ordinary records or, with `indirect_levels` set, interior
+//! nodes hidden behind an empty record of their own.
+
+use alloc::Vec;
+
+use error::Error;
+
+use super::dir;
+
+/// Offset of the `dx_root_info` in the first block of the directory
+pub const ROOT_INFO_OFFSET: usize = 24;
+/// Offset of the entries of the root
+pub const ROOT_ENTRIES_OFFSET: usize = 32;
+/// Offset of the entries of an interior node
+pub const NODE_ENTRIES_OFFSET: usize = 8;
+/// Size of a single index entry
+pub const ENTRY_SIZE: usize = 8;
+/// Maximum number of interior node levels below the root
+pub const MAX_INDIRECT_LEVELS: u8 = 1;
+
+/// Hash functions mapping names to their place in the index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashVersion {
+    Legacy,
+    HalfMd4,
+    Tea,
+    LegacyUnsigned,
+    HalfMd4Unsigned,
+    TeaUnsigned,
+}
+
+impl HashVersion {
+    /// Interprets the hash version stored in an index root. Whether names are
+    /// signed or unsigned is determined by the superblock flags instead.
+    pub fn from_raw(version: u8, unsigned: bool) -> Option<HashVersion> {
+        match (version, unsigned) {
+            (0, false) => Some(HashVersion::Legacy),
+            (1, false) => Some(HashVersion::HalfMd4),
+            (2, false) => Some(HashVersion::Tea),
+            (0, true) => Some(HashVersion::LegacyUnsigned),
+            (1, true) => Some(HashVersion::HalfMd4Unsigned),
+            (2, true) => Some(HashVersion::TeaUnsigned),
+            _ => None,
+        }
+    }
+
+    fn is_unsigned(&self) -> bool {
+        match *self {
+            HashVersion::LegacyUnsigned
+            | HashVersion::HalfMd4Unsigned
+            | HashVersion::TeaUnsigned => true,
+            _ => false,
+        }
+    }
+}
+
+/// Hashes `name`, returning the major and the minor hash. The lowest bit of
+/// the major hash is always clear, as it marks collisions in the index.
+pub fn hash(name: &[u8], version: HashVersion, seed: &[u32; 4]) -> (u32, u32) {
+    let mut buf = if seed.iter().any(|&word| word != 0) {
+        *seed
+    } else {
+        [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476]
+    };
+    let unsigned = version.is_unsigned();
+
+    let (hash, minor) = match version {
+        HashVersion::Legacy | HashVersion::LegacyUnsigned => {
+            (legacy_hash(name, unsigned), 0)
+        }
+        HashVersion::HalfMd4 | HashVersion::HalfMd4Unsigned => {
+            let mut input = [0; 8];
+            for (index, chunk) in name.chunks(32).enumerate() {
+                let len = name.len() - index * 32;
+                str_to_words(chunk, len, unsigned, &mut input);
+                half_md4_transform(&mut buf, &input);
+            }
+            (buf[1], buf[2])
+        }
+        HashVersion::Tea | HashVersion::TeaUnsigned => {
+            let mut input = [0; 4];
+            for (index, chunk) in name.chunks(16).enumerate() {
+                let len = name.len() - index * 16;
+                str_to_words(chunk, len, unsigned, &mut input);
+                tea_transform(&mut buf, &input);
+            }
+            (buf[0], buf[1])
+        }
+    };
+
+    // the largest hash is reserved as the end of the index
+    let hash = hash & !1;
+    if hash == 0xffff_fffe {
+        (0xffff_fffc, minor)
+    } else {
+        (hash, minor)
+    }
+}
+
+fn char_value(byte: u8, unsigned: bool) -> u32 {
+    if unsigned {
+        byte as u32
+    } else {
+        byte as i8 as i32 as u32
+    }
+}
+
+fn legacy_hash(name: &[u8], unsigned: bool) -> u32 {
+    let mut hash0: u32 = 0x12a3fe2d;
+    let mut hash1: u32 = 0x37abe8f9;
+    for &byte in name {
+        let value = char_value(byte, unsigned).wrapping_mul(7152373);
+        let mut hash = hash1.wrapping_add(hash0 ^ value);
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7fff_ffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// Packs `chunk` into big-endian words, padded with `len`, the length of the
+/// rest of the name
+fn str_to_words(chunk: &[u8], len: usize, unsigned: bool, words: &mut [u32]) {
+    let mut pad = len as u32 | (len as u32) << 8;
+    pad |= pad << 16;
+
+    let mut value = pad;
+    let mut index = 0;
+    for (i, &byte) in chunk.iter().take(words.len() * 4).enumerate() {
+        value = char_value(byte, unsigned).wrapping_add(value << 8);
+        if i % 4 == 3 {
+            words[index] = value;
+            index += 1;
+            value = pad;
+        }
+    }
+    if index < words.len() {
+        words[index] = value;
+        index += 1;
+    }
+    for word in words[index..].iter_mut() {
+        *word = pad;
+    }
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+
+    fn f(x: u32, y: u32, z: u32) -> u32 {
+        z ^ (x & (y ^ z))
+    }
+    fn g(x: u32, y: u32, z: u32) -> u32 {
+        (x & y).wrapping_add((x ^ y) & z)
+    }
+    fn h(x: u32, y: u32, z: u32) -> u32 {
+        x ^ y ^ z
+    }
+
+    let (mut a, mut b, mut c, mut d) = (buf[0], buf[1], buf[2], buf[3]);
+    macro_rules! round {
+        (
+            $f:ident,
+            $a:ident,
+            $b:ident,
+            $c:ident,
+            $d:ident,
+            $x:expr,
+            $s:expr
+        ) => {
+            $a = $a.wrapping_add($f($b, $c, $d)).wrapping_add($x);
+            $a = $a.rotate_left($s);
+        };
+    }
+
+    round!(f, a, b, c, d, input[0], 3);
+    round!(f, d, a, b, c, input[1], 7);
+    round!(f, c, d, a, b, input[2], 11);
+    round!(f, b, c, d, a, input[3], 19);
+    round!(f, a, b, c, d, input[4], 3);
+    round!(f, d, a, b, c, input[5], 7);
+    round!(f, c, d, a, b, input[6], 11);
+    round!(f, b, c, d, a, input[7], 19);
+
+    round!(g, a, b, c, d, input[1].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[3].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[5].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[7].wrapping_add(K2), 13);
+    round!(g, a, b, c, d, input[0].wrapping_add(K2), 3);
+    round!(g, d, a, b, c, input[2].wrapping_add(K2), 5);
+    round!(g, c, d, a, b, input[4].wrapping_add(K2), 9);
+    round!(g, b, c, d, a, input[6].wrapping_add(K2), 13);
+
+    round!(h, a, b, c, d, input[3].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[7].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[2].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[6].wrapping_add(K3), 15);
+    round!(h, a, b, c, d, input[1].wrapping_add(K3), 3);
+    round!(h, d, a, b, c, input[5].wrapping_add(K3), 9);
+    round!(h, c, d, a, b, input[0].wrapping_add(K3), 11);
+    round!(h, b, c, d, a, input[4].wrapping_add(K3), 15);
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    const DELTA: u32 = 0x9e3779b9;
+
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let (a, b, c, d) = (input[0], input[1], input[2], input[3]);
+    let mut sum: u32 = 0;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a)
+                ^ b1.wrapping_add(sum)
+                ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c)
+                ^ b0.wrapping_add(sum)
+                ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+/// A single entry of an index node, covering the hashes from `hash` up to
+/// the `hash` of the next entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Entry {
+    pub hash: u32,
+    /// Index of the block within the directory
+    pub block: u32,
+}
+
+/// The root of an index, stored in the first block of the directory
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Root {
+    pub hash_version: u8,
+    pub indirect_levels: u8,
+    pub entries: Vec<Entry>,
+}
+
+/// Parses the root in the first block of a directory, returning `None` if it
+/// isn't a valid one
+pub fn read_root(block: &[u8]) -> Option<Root> {
+    let dot = dir::read_record(block, 0)?;
+    let dotdot = dir::read_record(block, dot.rec_len)?;
+    if dot.name(block) != b"."
+        || dotdot.name(block) != b".."
+        || dotdot.offset != ROOT_INFO_OFFSET - 12
+        || dotdot.offset + dotdot.rec_len != block.len()
+    {
+        return None;
+    }
+
+    let reserved = dir::read_u32(block, ROOT_INFO_OFFSET);
+    let hash_version = block[ROOT_INFO_OFFSET + 4];
+    let info_length = block[ROOT_INFO_OFFSET + 5];
+    let indirect_levels = block[ROOT_INFO_OFFSET + 6];
+    if reserved != 0
+        || info_length as usize != ROOT_ENTRIES_OFFSET - ROOT_INFO_OFFSET
+        || indirect_levels > MAX_INDIRECT_LEVELS
+    {
+        return None;
+    }
+
+    read_entries(block, ROOT_ENTRIES_OFFSET).map(|entries| Root {
+        hash_version,
+        indirect_levels,
+        entries,
+    })
+}
+
+/// Parses an interior node, returning `None` if it isn't a valid one
+pub fn read_node(block: &[u8]) -> Option<Vec<Entry>> {
+    let record = dir::read_record(block, 0)?;
+    if record.inode != 0 || record.rec_len != block.len() {
+        return None;
+    }
+    read_entries(block, NODE_ENTRIES_OFFSET)
+}
+
+/// Parses the entries at `offset`. The first one stores their count and
+/// capacity in place of its hash, which is implicitly 0.
+fn read_entries(block: &[u8], offset: usize) -> Option<Vec<Entry>> {
+    let limit = dir::read_u16(block, offset) as usize;
+    let count = dir::read_u16(block, offset + 2) as usize;
+    if count == 0 || count > limit || offset + limit * ENTRY_SIZE > block.len()
+    {
+        return None;
+    }
+
+    let entries = (0..count)
+        .map(|index| {
+            let start = offset + index * ENTRY_SIZE;
+            Entry {
+                hash: if index == 0 {
+                    0
+                } else {
+                    dir::read_u32(block, start)
+                },
+                block: dir::read_u32(block, start + 4) & 0x0fff_ffff,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let sorted = entries.windows(2).all(|pair| pair[0].hash <= pair[1].hash);
+    if sorted {
+        Some(entries)
+    } else {
+        None
+    }
+}
+
+/// Index of the entry covering `hash`
+pub fn find(entries: &[Entry], hash: u32) -> usize {
+    // the first entry covers everything below the second one
+    match entries[1..].binary_search_by(|entry| entry.hash.cmp(&hash)) {
+        Ok(index) => {
+            // the first of several equal hashes
+            let mut index = index + 1;
+            while index > 1 && entries[index - 1].hash == hash {
+                index -= 1;
+            }
+            index
+        }
+        Err(index) => index,
+    }
+}
+
+/// A node on the path from the root to a leaf
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Frame {
+    /// Index of the block of the node within the directory, 0 for the root
+    pub block: u32,
+    pub entries: Vec<Entry>,
+    /// Index of the entry taken
+    pub index: usize,
+}
+
+impl Frame {
+    /// Index of the block the taken entry points to
+    pub fn target(&self) -> u32 {
+        self.entries[self.index].block
+    }
+}
+
+/// Descends from the last frame of `path` down to a leaf, taking the entries
+/// covering `hash` or the first ones if it's `None`. Returns `false` if an
+/// interior node is corrupted.
+fn descend<F>(
+    path: &mut Vec<Frame>,
+    indirect_levels: u8,
+    hash: Option<u32>,
+    read: &mut F,
+) -> Result<bool, Error>
+where
+    F: FnMut(u32) -> Result<Option<Vec<u8>>, Error>,
+{
+    while path.len() <= indirect_levels as usize {
+        let block = path.last().unwrap().target();
+        let entries =
+            match read(block)?.as_ref().and_then(|node| read_node(node)) {
+                Some(entries) => entries,
+                None => return Ok(false),
+            };
+        let index = hash.map_or(0, |hash| find(&entries, hash));
+        path.push(Frame {
+            block,
+            entries,
+            index,
+        });
+    }
+    Ok(true)
+}
+
+/// Walks from the root down to the leaf covering `hash`, returning the path
+/// taken or `None` if the index is corrupted
+pub fn probe<F>(
+    root: &Root,
+    hash: u32,
+    mut read: F,
+) -> Result<Option<Vec<Frame>>, Error>
+where
+    F: FnMut(u32) -> Result<Option<Vec<u8>>, Error>,
+{
+    let mut path = vec![Frame {
+        block: 0,
+        entries: root.entries.clone(),
+        index: find(&root.entries, hash),
+    }];
+    if descend(&mut path, root.indirect_levels, Some(hash), &mut read)? {
+        Ok(Some(path))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Finds the leaf blocks that may hold `name`, in the order they have to be
+/// searched: names with colliding hashes may continue in the following leaves,
+/// which mark this by setting the lowest bit of their hash. `read` loads a
+/// block of the directory by its index, returning `None` if it's missing.
+///
+/// Returns `None` if the index is corrupted, in which case the directory has
+/// to be searched linearly.
+pub fn lookup<F>(
+    root: &[u8],
+    name: &[u8],
+    seed: &[u32; 4],
+    unsigned: bool,
+    mut read: F,
+) -> Result<Option<Vec<u32>>, Error>
+where
+    F: FnMut(u32) -> Result<Option<Vec<u8>>, Error>,
+{
+    let root = match read_root(root) {
+        Some(root) => root,
+        None => return Ok(None),
+    };
+    let version = match HashVersion::from_raw(root.hash_version, unsigned) {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    let (hash, _) = self::hash(name, version, seed);
+
+    let mut path = match probe(&root, hash, &mut read)? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let mut leaves = Vec::new();
+    loop {
+        leaves.push(path.last().unwrap().target());
+
+        // move on to the next leaf, if it continues the same hash
+        while path
+            .last()
+            .map_or(false, |frame| frame.index + 1 >= frame.entries.len())
+        {
+            path.pop();
+        }
+        let next = match path.last_mut() {
+            Some(frame) => {
+                frame.index += 1;
+                frame.entries[frame.index].hash
+            }
+            None => break,
+        };
+        if next & !1 != hash {
+            break;
+        }
+
+        if !descend(&mut path, root.indirect_levels, None, &mut read)? {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(leaves))
+}
+
+/// Number of entries fitting in the root of a directory with blocks of
+/// `block_size` bytes
+pub fn root_capacity(block_size: usize) -> usize {
+    (block_size - ROOT_ENTRIES_OFFSET) / ENTRY_SIZE
+}
+
+/// Number of entries fitting in an interior node
+pub fn node_capacity(block_size: usize) -> usize {
+    (block_size - NODE_ENTRIES_OFFSET) / ENTRY_SIZE
+}
+
+/// Writes `entries` at `offset`, along with their count and the number of
+/// entries fitting in the rest of the block
+fn write_entries(block: &mut [u8], offset: usize, entries: &[Entry]) {
+    let limit = (block.len() - offset) / ENTRY_SIZE;
+    dir::write_u16(block, offset, limit as u16);
+    dir::write_u16(block, offset + 2, entries.len() as u16);
+    for (index, entry) in entries.iter().enumerate() {
+        let start = offset + index * ENTRY_SIZE;
+        if index > 0 {
+            dir::write_u32(block, start, entry.hash);
+        }
+        dir::write_u32(block, start + 4, entry.block);
+    }
+}
+
+/// Writes the root into the first block of a directory, which must already
+/// hold the `.` and `..` records
+pub fn write_root(
+    block: &mut [u8],
+    hash_version: u8,
+    indirect_levels: u8,
+    entries: &[Entry],
+) {
+    dir::write_u32(block, ROOT_INFO_OFFSET, 0);
+    block[ROOT_INFO_OFFSET + 4] = hash_version;
+    block[ROOT_INFO_OFFSET + 5] =
+        (ROOT_ENTRIES_OFFSET - ROOT_INFO_OFFSET) as u8;
+    block[ROOT_INFO_OFFSET + 6] = indirect_levels;
+    block[ROOT_INFO_OFFSET + 7] = 0;
+    write_entries(block, ROOT_ENTRIES_OFFSET, entries);
+}
+
+/// Builds an interior node holding `entries`
+pub fn node_block(block_size: usize, entries: &[Entry]) -> Vec<u8> {
+    let mut block = vec![0; block_size];
+    dir::write_record(&mut block, 0, 0, block_size, b"", 0);
+    write_entries(&mut block, NODE_ENTRIES_OFFSET, entries);
+    block
+}
+
+/// A live record of a leaf, along with the hash of its name
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LeafEntry {
+    pub hash: u32,
+    pub inode: u32,
+    pub name: Vec<u8>,
+    pub ty: u8,
+}
+
+/// Reads the live records of `block` starting at `offset`
+pub fn leaf_entries(
+    block: &[u8],
+    offset: usize,
+    version: HashVersion,
+    seed: &[u32; 4],
+) -> Vec<LeafEntry> {
+    let mut entries = Vec::new();
+    let mut offset = offset;
+    while let Some(record) = dir::read_record(block, offset) {
+        if record.inode != 0 {
+            let name = record.name(block);
+            entries.push(LeafEntry {
+                hash: hash(name, version, seed).0,
+                inode: record.inode,
+                name: name.to_vec(),
+                ty: record.ty,
+            });
+        }
+        offset += record.rec_len;
+    }
+    entries
+}
+
+/// Packs `entries` into a leaf of `block_size` bytes
+fn leaf_block(block_size: usize, entries: &[LeafEntry]) -> Vec<u8> {
+    let mut block = vec![0; block_size];
+    let mut offset = 0;
+    for (index, entry) in entries.iter().enumerate() {
+        let rec_len = if index + 1 == entries.len() {
+            block_size - offset
+        } else {
+            dir::record_len(entry.name.len())
+        };
+        dir::write_record(
+            &mut block,
+            offset,
+            entry.inode,
+            rec_len,
+            &entry.name,
+            entry.ty,
+        );
+        offset += rec_len;
+    }
+    if entries.is_empty() {
+        dir::write_record(&mut block, 0, 0, block_size, b"", 0);
+    }
+    block
+}
+
+/// Sorts `entries` by hash and splits them in two leaves holding about half
+/// of the records each. Returns both leaves and the hash the upper one
+/// starts at, with the lowest bit set if it continues the hash of the last
+/// record of the lower one.
+pub fn split_leaf(
+    mut entries: Vec<LeafEntry>,
+    block_size: usize,
+) -> (Vec<u8>, Vec<u8>, u32) {
+    entries.sort_by_key(|entry| entry.hash);
+
+    let total: usize = entries
+        .iter()
+        .map(|entry| dir::record_len(entry.name.len()))
+        .sum();
+    let mut size = 0;
+    let mut split = entries.len();
+    for (index, entry) in entries.iter().enumerate() {
+        size += dir::record_len(entry.name.len());
+        if size * 2 >= total {
+            split = index + 1;
+            break;
+        }
+    }
+    // keep both leaves in use if there's more than a single record
+    let split = if entries.len() > 1 {
+        split.min(entries.len() - 1).max(1)
+    } else {
+        entries.len()
+    };
+
+    let last = split.checked_sub(1).and_then(|index| entries.get(index));
+    let hash = match (last, entries.get(split)) {
+        (Some(last), Some(first)) if last.hash == first.hash => first.hash | 1,
+        (_, Some(first)) => first.hash,
+        (Some(last), None) => last.hash | 1,
+        (None, None) => 0,
+    };
+    let upper = entries.split_off(split);
+    (
+        leaf_block(block_size, &entries),
+        leaf_block(block_size, &upper),
+        hash,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::Vec;
+
+    use fs::dir;
+
+    use super::*;
+
+    #[test]
+    fn hashes() {
+        let seed = [0; 4];
+        let hashes = [
+            (HashVersion::Legacy, &b"lost+found"[..], 0x5e2aba24, 0),
+            (HashVersion::Legacy, b"a", 0xe74b53e2, 0),
+            (HashVersion::HalfMd4, b"lost+found", 0x591de422, 0x6ffc56e0),
+            (HashVersion::HalfMd4, b"a", 0xd5fa7d7a, 0xacb48187),
+            (HashVersion::Tea, b"lost+found", 0x2dbf9e80, 0xbfebee4f),
+            (HashVersion::Tea, b"a", 0x6d0ea4c0, 0xc18922df),
+        ];
+        for &(version, name, major, minor) in hashes.iter() {
+            assert_eq!(hash(name, version, &seed), (major, minor));
+        }
+
+        // longer than a single chunk of every hash
+        let name = b"hello_world_this_is_a_longer_name_over_32_bytes_long";
+        assert_eq!(hash(name, HashVersion::Legacy, &seed).0, 0x37587384);
+        assert_eq!(
+            hash(name, HashVersion::HalfMd4, &seed),
+            (0xec8dce30, 0x61065fcc)
+        );
+        assert_eq!(
+            hash(name, HashVersion::Tea, &seed),
+            (0x0c075838, 0xed92ad9d)
+        );
+
+        // only names with non-ASCII characters differ in signedness
+        let name = "é".as_bytes();
+        let hashes = [
+            (HashVersion::Legacy, 0x11083c86, 0),
+            (HashVersion::HalfMd4, 0x89d4704e, 0x75d52d82),
+            (HashVersion::Tea, 0x591e9bd6, 0xf780721f),
+            (HashVersion::LegacyUnsigned, 0x878ca486, 0),
+            (HashVersion::HalfMd4Unsigned, 0xfda9f3f8, 0x69788442),
+            (HashVersion::TeaUnsigned, 0x6daf7c00, 0xdc9b6b19),
+        ];
+        for &(version, major, minor) in hashes.iter() {
+            assert_eq!(hash(name, version, &seed), (major, minor));
+        }
+
+        let seed = [0x33221100, 0x77665544, 0xbbaa9988, 0xffeeddcc];
+        assert_eq!(
+            hash(b"lost+found", HashVersion::HalfMd4, &seed),
+            (0x1efb822c, 0x2a3bb222)
+        );
+        assert_eq!(
+            hash(b"lost+found", HashVersion::Tea, &seed),
+            (0xd06209f0, 0x605a7d48)
+        );
+    }
+
+    fn entries(entries: &[(u32, u32)]) -> Vec<Entry> {
+        entries
+            .iter()
+            .map(|&(hash, block)| Entry { hash, block })
+            .collect()
+    }
+
+    fn root(entries: &[(u32, u32)]) -> Vec<u8> {
+        let mut root = vec![0; 1024];
+        dir::write_record(&mut root, 0, 2, 12, b".", 2);
+        dir::write_record(&mut root, 12, 2, 1024 - 12, b"..", 2);
+        write_root(&mut root, 2, 1, &self::entries(entries));
+        root
+    }
+
+    fn node(entries: &[(u32, u32)]) -> Vec<u8> {
+        node_block(1024, &self::entries(entries))
+    }
+
+    #[test]
+    fn lookup_leaves() {
+        let name = b"lost+found";
+        let seed = [0; 4];
+        let (hash, _) = hash(name, HashVersion::Tea, &seed);
+
+        let root1 = root(&[(0, 1), (hash - 0x100, 2)]);
+        let nodes = [
+            node(&[(0, 3), (hash - 0x200, 4)]),
+            node(&[(0, 5), (hash, 6)]),
+        ];
+        let read = |block: u32| match block {
+            1 | 2 => Ok(Some(nodes[block as usize - 1].clone())),
+            _ => Ok(None),
+        };
+        let leaves = lookup(&root1, name, &seed, false, read).unwrap();
+        assert_eq!(leaves, Some(vec![6]));
+
+        // a collision continues into the following node
+        let root2 = root(&[(0, 1), (hash | 1, 2)]);
+        let nodes = [
+            node(&[(0, 3), (hash, 4)]),
+            node(&[(0, 5), (hash + 0x10, 6)]),
+        ];
+        let read = |block: u32| match block {
+            1 | 2 => Ok(Some(nodes[block as usize - 1].clone())),
+            _ => Ok(None),
+        };
+        let leaves = lookup(&root2, name, &seed, false, read).unwrap();
+        assert_eq!(leaves, Some(vec![4, 5]));
+
+        // corrupted indexes fall back to a linear search
+        let read = |_| Ok(Some(vec![0xff; 1024]));
+        assert_eq!(lookup(&root2, name, &seed, false, read).unwrap(), None);
+        let read = |_| Ok(None);
+        assert_eq!(lookup(&root2, name, &seed, false, read).unwrap(), None);
+        let mut root3 = root2.clone();
+        root3[ROOT_INFO_OFFSET + 6] = 2;
+        let read = |block: u32| Ok(Some(nodes[block as usize - 1].clone()));
+        assert_eq!(lookup(&root3, name, &seed, false, read).unwrap(), None);
+    }
+
+    #[test]
+    fn split() {
+        let seed = [0; 4];
+        let version = HashVersion::HalfMd4;
+        let mut block = vec![0; 1024];
+        dir::write_record(&mut block, 0, 0, 1024, b"", 0);
+        for index in 0..40 {
+            let name = format!("file{}", index);
+            assert!(dir::insert(&mut block, index + 11, name.as_bytes(), 1));
+        }
+
+        let entries = leaf_entries(&block, 0, version, &seed);
+        assert_eq!(entries.len(), 40);
+        let (lower, upper, split_hash) = split_leaf(entries, 1024);
+        let lower = leaf_entries(&lower, 0, version, &seed);
+        let upper = leaf_entries(&upper, 0, version, &seed);
+        assert_eq!(lower.len() + upper.len(), 40);
+        assert!(lower.len() >= 15 && upper.len() >= 15);
+        assert!(lower.iter().all(|entry| entry.hash < split_hash));
+        assert!(upper.iter().all(|entry| entry.hash >= split_hash & !1));
+
+        // equal hashes continue in the upper leaf
+        let entries = (0..4)
+            .map(|index| LeafEntry {
+                hash: 10,
+                inode: index + 11,
+                name: vec![b'a' + index as u8],
+                ty: 1,
+            })
+            .collect();
+        let (lower, upper, split_hash) = split_leaf(entries, 1024);
+        assert_eq!(split_hash, 11);
+        assert_eq!(dir::read_record(&lower, 0).unwrap().inode, 11);
+        assert_eq!(dir::read_record(&upper, 0).unwrap().inode, 13);
+        assert_eq!(dir::read_record(&upper, 12).unwrap().rec_len, 1012);
+    }
+}
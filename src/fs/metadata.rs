@@ -0,0 +1,364 @@
+use sys::inode::{self, Flags, Inode as RawInode, InodeExtra, TypePerm};
+
+/// Mask of the file type bits in `TypePerm`
+const TYPE_MASK: u16 = 0xf000;
+/// Mask of the permission bits (including setuid, setgid and sticky) in
+/// `TypePerm`
+const PERM_MASK: u16 = 0x0fff;
+
+/// Type of a file, as stored in the mode of its inode or in the directory
+/// entries pointing to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileType {
+    Unknown,
+    Fifo,
+    CharDevice,
+    Directory,
+    BlockDevice,
+    File,
+    Symlink,
+    Socket,
+}
+
+impl FileType {
+    /// Decodes the type indicator of a directory entry, one of the
+    /// `EXT2_FT_*` values
+    pub fn from_entry_type(ty: u8) -> FileType {
+        match ty {
+            inode::FILE => FileType::File,
+            inode::DIRECTORY => FileType::Directory,
+            inode::CHAR_DEVICE => FileType::CharDevice,
+            inode::BLOCK_DEVICE => FileType::BlockDevice,
+            inode::FIFO => FileType::Fifo,
+            inode::SOCKET => FileType::Socket,
+            inode::SYMLINK => FileType::Symlink,
+            _ => FileType::Unknown,
+        }
+    }
+
+    /// Decodes the file type bits of an inode mode
+    pub fn from_type_perm(type_perm: TypePerm) -> FileType {
+        match type_perm.bits() & TYPE_MASK {
+            0x1000 => FileType::Fifo,
+            0x2000 => FileType::CharDevice,
+            0x4000 => FileType::Directory,
+            0x6000 => FileType::BlockDevice,
+            0x8000 => FileType::File,
+            0xa000 => FileType::Symlink,
+            0xc000 => FileType::Socket,
+            _ => FileType::Unknown,
+        }
+    }
+
+    /// The type indicator stored in directory entries for this type
+    pub fn entry_type(&self) -> u8 {
+        match *self {
+            FileType::Unknown => inode::UNKNOWN,
+            FileType::Fifo => inode::FIFO,
+            FileType::CharDevice => inode::CHAR_DEVICE,
+            FileType::Directory => inode::DIRECTORY,
+            FileType::BlockDevice => inode::BLOCK_DEVICE,
+            FileType::File => inode::FILE,
+            FileType::Symlink => inode::SYMLINK,
+            FileType::Socket => inode::SOCKET,
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        *self == FileType::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        *self == FileType::Directory
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        *self == FileType::Symlink
+    }
+}
+
+/// Information about an inode, as returned by `Fs::metadata`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Metadata {
+    inode: u32,
+    type_perm: TypePerm,
+    uid: u16,
+    gid: u16,
+    size: u64,
+    atime: i64,
+    ctime: i64,
+    mtime: i64,
+    dtime: u32,
+    atime_nsec: u32,
+    ctime_nsec: u32,
+    mtime_nsec: u32,
+    crtime: Option<(i64, u32)>,
+    hard_links: u16,
+    sectors: u32,
+    flags: Flags,
+}
+
+impl Metadata {
+    pub fn new(
+        inode: u32,
+        raw: &RawInode,
+        extra: Option<&InodeExtra>,
+    ) -> Metadata {
+        let type_perm = raw.type_perm;
+        // in directories, `size_high` holds the directory ACL instead
+        let size = if type_perm.bits() & TYPE_MASK == TypePerm::DIRECTORY.bits()
+        {
+            raw.size_low as u64
+        } else {
+            raw.size_low as u64 | (raw.size_high as u64) << 32
+        };
+
+        // the extra fields hold the nanoseconds and the epoch extending the
+        // seconds past 2038, the creation time only if the extended part is
+        // long enough to hold it; fields past `extra_isize` read as zero
+        let (atime_extra, ctime_extra, mtime_extra, crtime) = match extra {
+            Some(extra) => (
+                extra.atime_extra,
+                extra.ctime_extra,
+                extra.mtime_extra,
+                if extra.extra_isize >= 24 {
+                    Some(InodeExtra::decode_time(
+                        extra.crtime,
+                        extra.crtime_extra,
+                    ))
+                } else {
+                    None
+                },
+            ),
+            None => (0, 0, 0, None),
+        };
+        let (atime, atime_nsec) =
+            InodeExtra::decode_time(raw.atime, atime_extra);
+        let (ctime, ctime_nsec) =
+            InodeExtra::decode_time(raw.ctime, ctime_extra);
+        let (mtime, mtime_nsec) =
+            InodeExtra::decode_time(raw.mtime, mtime_extra);
+
+        Metadata {
+            inode,
+            type_perm,
+            uid: raw.uid,
+            gid: raw.gid,
+            size,
+            atime,
+            ctime,
+            mtime,
+            dtime: raw.dtime,
+            atime_nsec,
+            ctime_nsec,
+            mtime_nsec,
+            crtime,
+            hard_links: raw.hard_links,
+            sectors: raw.sectors_count,
+            flags: raw.flags,
+        }
+    }
+
+    /// Number of the inode this metadata was read from
+    pub fn inode(&self) -> u32 {
+        self.inode
+    }
+
+    /// The file type bits of the mode, e.g. `TypePerm::DIRECTORY`
+    pub fn file_type(&self) -> TypePerm {
+        TypePerm::from_bits_truncate(self.type_perm.bits() & TYPE_MASK)
+    }
+
+    /// The file type of the inode
+    pub fn kind(&self) -> FileType {
+        FileType::from_type_perm(self.type_perm)
+    }
+
+    /// The permission bits of the mode, including setuid, setgid and sticky
+    pub fn permissions(&self) -> TypePerm {
+        TypePerm::from_bits_truncate(self.type_perm.bits() & PERM_MASK)
+    }
+
+    /// The full mode: file type and permissions
+    pub fn mode(&self) -> TypePerm {
+        self.type_perm
+    }
+
+    pub fn uid(&self) -> u16 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u16 {
+        self.gid
+    }
+
+    /// Size in bytes
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+
+    /// Last access time (in POSIX time). Inodes without an extended part
+    /// can only store times between 1901 and 2038.
+    pub fn accessed(&self) -> i64 {
+        self.atime
+    }
+
+    /// Last inode change time (in POSIX time), updated when the contents or
+    /// the metadata of the inode change. The creation time is `birth`.
+    pub fn changed(&self) -> i64 {
+        self.ctime
+    }
+
+    /// Last modification time (in POSIX time)
+    pub fn modified(&self) -> i64 {
+        self.mtime
+    }
+
+    /// Nanoseconds of the last access time, 0 on filesystems with 128-byte
+    /// inodes
+    pub fn accessed_nsec(&self) -> u32 {
+        self.atime_nsec
+    }
+
+    /// Nanoseconds of the last inode change time (see `accessed_nsec`)
+    pub fn changed_nsec(&self) -> u32 {
+        self.ctime_nsec
+    }
+
+    /// Nanoseconds of the last modification time (see `accessed_nsec`)
+    pub fn modified_nsec(&self) -> u32 {
+        self.mtime_nsec
+    }
+
+    /// Birth time of the inode (in POSIX time) and its nanoseconds, stored
+    /// in the extended part of large inodes only
+    pub fn birth(&self) -> Option<(i64, u32)> {
+        self.crtime
+    }
+
+    /// Deletion time (in POSIX time), 0 for live inodes
+    pub fn deleted(&self) -> u32 {
+        self.dtime
+    }
+
+    pub fn hard_links(&self) -> u16 {
+        self.hard_links
+    }
+
+    /// Number of 512-byte sectors allocated to this inode, including
+    /// indirect blocks
+    pub fn sectors(&self) -> u32 {
+        self.sectors
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::mem;
+
+    use sys::inode::{Flags, Inode as RawInode, InodeExtra, TypePerm};
+
+    use super::{FileType, Metadata};
+
+    #[test]
+    fn metadata() {
+        let mut raw: RawInode = unsafe { mem::zeroed() };
+        raw.type_perm = TypePerm::FILE | TypePerm::U_READ | TypePerm::SET_UID;
+        raw.uid = 1000;
+        raw.gid = 100;
+        raw.size_low = 42;
+        raw.size_high = 1;
+        raw.ctime = 1200;
+        raw.mtime = 1234;
+        raw.hard_links = 2;
+        raw.sectors_count = 8;
+        raw.flags = Flags::APPEND_ONLY;
+
+        let metadata = Metadata::new(12, &raw, None);
+        assert_eq!(metadata.inode(), 12);
+        assert_eq!(metadata.file_type(), TypePerm::FILE);
+        assert_eq!(metadata.kind(), FileType::File);
+        assert_eq!(
+            metadata.permissions(),
+            TypePerm::U_READ | TypePerm::SET_UID
+        );
+        assert_eq!(metadata.uid(), 1000);
+        assert_eq!(metadata.gid(), 100);
+        assert_eq!(metadata.len(), 1 << 32 | 42);
+        assert_eq!(metadata.changed(), 1200);
+        assert_eq!(metadata.modified(), 1234);
+        assert_eq!(metadata.hard_links(), 2);
+        assert_eq!(metadata.sectors(), 8);
+        assert_eq!(metadata.flags(), Flags::APPEND_ONLY);
+
+        raw.type_perm = TypePerm::DIRECTORY;
+        assert_eq!(Metadata::new(12, &raw, None).len(), 42);
+        assert_eq!(metadata.modified_nsec(), 0);
+        assert_eq!(metadata.birth(), None);
+
+        let mut extra: InodeExtra = unsafe { mem::zeroed() };
+        extra.extra_isize = 32;
+        extra.mtime_extra = 999_999_999 << 2 | 1;
+        extra.crtime = 1000;
+        extra.crtime_extra = 5 << 2;
+        let metadata = Metadata::new(12, &raw, Some(&extra));
+        assert_eq!(metadata.modified(), 1234 + (1 << 32));
+        assert_eq!(metadata.modified_nsec(), 999_999_999);
+        assert_eq!(metadata.changed(), 1200);
+        assert_eq!(metadata.birth(), Some((1000, 5)));
+
+        // without an extended part, timestamps are signed
+        raw.atime = 0xffff_fff0;
+        assert_eq!(Metadata::new(12, &raw, None).accessed(), -16);
+
+        extra.extra_isize = 16;
+        assert_eq!(Metadata::new(12, &raw, Some(&extra)).birth(), None);
+    }
+
+    #[test]
+    fn times() {
+        for &seconds in [0, -16, 1 << 31, 1_500_000_000, 3 << 32].iter() {
+            let (base, extra) = InodeExtra::encode_time(seconds, 7);
+            assert_eq!(InodeExtra::decode_time(base, extra), (seconds, 7));
+        }
+        // a u32 timestamp past 2038 needs the epoch bits
+        assert_eq!(InodeExtra::encode_time(0xffff_fff0, 0), (0xffff_fff0, 1));
+    }
+
+    #[test]
+    fn file_type() {
+        let socket = TypePerm::SOCKET | TypePerm::U_READ;
+        assert_eq!(FileType::from_type_perm(socket), FileType::Socket);
+        assert!(!FileType::from_type_perm(socket).is_dir());
+        assert!(FileType::from_type_perm(TypePerm::DIRECTORY).is_dir());
+        assert!(FileType::from_type_perm(TypePerm::SYMLINK).is_symlink());
+        assert_eq!(
+            FileType::from_type_perm(TypePerm::empty()),
+            FileType::Unknown
+        );
+
+        for ty in 0..8 {
+            assert_eq!(FileType::from_entry_type(ty).entry_type(), ty);
+        }
+        // as written by Linux
+        let types = [
+            (1, FileType::File),
+            (2, FileType::Directory),
+            (3, FileType::CharDevice),
+            (4, FileType::BlockDevice),
+            (5, FileType::Fifo),
+            (6, FileType::Socket),
+            (7, FileType::Symlink),
+        ];
+        for &(ty, kind) in types.iter() {
+            assert_eq!(FileType::from_entry_type(ty), kind);
+        }
+        assert!(FileType::from_entry_type(1).is_file());
+        assert!(FileType::from_entry_type(2).is_dir());
+        assert_eq!(FileType::from_entry_type(42), FileType::Unknown);
+    }
+}
@@ -0,0 +1,722 @@
+//! Formatting of volumes as fresh ext2 filesystems, similar to `mke2fs`.
+//!
+//! Every block group starts with a backup of the superblock and of the block
+//! group descriptor table if it has one, followed by its block bitmap, its
+//! inode bitmap and its inode table. The root directory and `lost+found` are
+//! placed right after the inode table of the first group.
+
+use core::mem;
+
+use alloc::{String, Vec};
+
+use error::Error;
+use sector::{Address, SectorSize};
+use volume::{Volume, VolumeCommit};
+use sys::superblock::{FsFlags, Superblock};
+use sys::superblock::{EXT2_MAGIC, ERR_IGNORE, FS_CLEAN, OS_LINUX};
+use sys::superblock::{FeaturesOptional, FeaturesROnly, FeaturesRequired};
+use sys::block_group::BlockGroupDescriptor;
+use sys::inode::{Inode as RawInode, InodeExtra, TypePerm};
+
+use super::dir;
+use super::check::ROOT_INODE;
+use super::features::Features;
+
+/// First non-reserved inode, which is always used for `lost+found`
+const FIRST_INODE: u32 = 11;
+/// `lost+found` is preallocated, so that `repair` rarely has to grow it
+const LOST_AND_FOUND_SIZE: usize = 16384;
+/// Permissions of the root directory: `rwxr-xr-x`
+const ROOT_PERMISSIONS: u16 = 0o755;
+/// Permissions of `lost+found`: `rwx------`
+const LOST_AND_FOUND_PERMISSIONS: u16 = 0o700;
+/// Default number of bytes of the volume per inode
+const BYTES_PER_INODE: u64 = 8192;
+
+/// Options of `format`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FormatOptions {
+    pub(crate) block_size: usize,
+    pub(crate) inodes_per_group: Option<u32>,
+    pub(crate) reserved_percent: u8,
+    pub(crate) revision: u32,
+    pub(crate) inode_size: u16,
+    pub(crate) label: Vec<u8>,
+    pub(crate) uuid: [u8; 16],
+    pub(crate) features: Features,
+    pub(crate) time: u32,
+}
+
+impl FormatOptions {
+    pub fn new() -> FormatOptions {
+        FormatOptions {
+            block_size: 1024,
+            inodes_per_group: None,
+            reserved_percent: 5,
+            revision: 1,
+            inode_size: 128,
+            label: Vec::new(),
+            uuid: [0; 16],
+            features: Features {
+                optional: FeaturesOptional::empty(),
+                required: FeaturesRequired::REQ_DIRECTORY_TYPE,
+                read_only: FeaturesROnly::RONLY_SPARSE
+                    | FeaturesROnly::RONLY_FILE_SIZE_64,
+            },
+            time: 0,
+        }
+    }
+
+    /// Size of a block in bytes, a power of two from 1 KiB to 4 KiB, so that
+    /// the counts of a block group fit in its descriptor. Defaults to 1 KiB.
+    pub fn block_size(&mut self, block_size: usize) -> &mut FormatOptions {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Number of inodes in each block group, rounded up to fill whole blocks
+    /// of the inode table. Defaults to one inode per 8 KiB.
+    pub fn inodes_per_group(
+        &mut self,
+        inodes_per_group: u32,
+    ) -> &mut FormatOptions {
+        self.inodes_per_group = Some(inodes_per_group);
+        self
+    }
+
+    /// Percentage of blocks reserved for the superuser, at most 50. Defaults
+    /// to 5.
+    pub fn reserved_percent(&mut self, percent: u8) -> &mut FormatOptions {
+        self.reserved_percent = percent;
+        self
+    }
+
+    /// Major revision, either 0 or 1. Revision 0 filesystems have 128 byte
+    /// inodes and no features. Defaults to 1.
+    pub fn revision(&mut self, revision: u32) -> &mut FormatOptions {
+        self.revision = revision;
+        self
+    }
+
+    /// Size of an inode in bytes, a power of two from 128 up to the block
+    /// size. Defaults to 128.
+    pub fn inode_size(&mut self, inode_size: u16) -> &mut FormatOptions {
+        self.inode_size = inode_size;
+        self
+    }
+
+    /// Volume name of at most 16 bytes
+    pub fn label(&mut self, label: &[u8]) -> &mut FormatOptions {
+        self.label = label.to_vec();
+        self
+    }
+
+    /// Filesystem ID. There's no source of randomness without `std`, so it
+    /// defaults to all zeros.
+    pub fn uuid(&mut self, uuid: [u8; 16]) -> &mut FormatOptions {
+        self.uuid = uuid;
+        self
+    }
+
+    /// Feature set, which must be writable (see `Features::can_write`).
+    /// Defaults to sparse superblocks, 64-bit file sizes and types in
+    /// directory entries.
+    pub fn features(&mut self, features: Features) -> &mut FormatOptions {
+        self.features = features;
+        self
+    }
+
+    /// Current POSIX time, used as the creation time of the filesystem and
+    /// its directories
+    pub fn time(&mut self, time: u32) -> &mut FormatOptions {
+        self.time = time;
+        self
+    }
+}
+
+impl Default for FormatOptions {
+    fn default() -> FormatOptions {
+        FormatOptions::new()
+    }
+}
+
+/// Locations of the structures of a single block group
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Group {
+    pub first_block: u32,
+    pub block_count: u32,
+    /// Whether the group starts with a backup of the superblock
+    pub backup: bool,
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    pub inode_table: u32,
+    /// First block after the inode table
+    pub data: u32,
+}
+
+impl Group {
+    /// Lays out the group `group` of a filesystem described by `superblock`,
+    /// with descriptor tables of `descr_blocks` blocks
+    pub fn new(
+        superblock: &Superblock,
+        group: u32,
+        descr_blocks: u32,
+    ) -> Group {
+        let first_block =
+            superblock.first_data_block + group * superblock.blocks_per_group;
+        let block_count = superblock
+            .blocks_per_group
+            .min(superblock.blocks_count - first_block);
+        let backup = superblock.has_backup(group);
+        let block_bitmap = if backup {
+            first_block + 1 + descr_blocks
+        } else {
+            first_block
+        };
+        Group {
+            first_block,
+            block_count,
+            backup,
+            block_bitmap,
+            inode_bitmap: block_bitmap + 1,
+            inode_table: block_bitmap + 2,
+            data: block_bitmap + 2 + inode_table_blocks(superblock),
+        }
+    }
+}
+
+/// Number of blocks of a descriptor table of `group_count` groups
+pub(crate) fn descriptor_blocks(group_count: u32, block_size: usize) -> u32 {
+    let descr_size = mem::size_of::<BlockGroupDescriptor>();
+    ((group_count as usize * descr_size + block_size - 1) / block_size) as u32
+}
+
+/// Number of blocks of the inode table of a single group
+pub(crate) fn inode_table_blocks(superblock: &Superblock) -> u32 {
+    let block_size = superblock.block_size();
+    let inode_size = if superblock.rev_major == 0 {
+        mem::size_of::<RawInode>()
+    } else {
+        superblock.inode_size as usize
+    };
+    let table_size = superblock.inodes_per_group as usize * inode_size;
+    ((table_size + block_size - 1) / block_size) as u32
+}
+
+/// Writes the bitmaps and a zeroed inode table of `group`, with its first
+/// `used_blocks` blocks and `used_inodes` inodes marked as used. Returns
+/// its descriptor, without any directories.
+pub(crate) fn init_group<S: SectorSize, V: Volume<u8, S>>(
+    volume: &mut V,
+    superblock: &Superblock,
+    group: &Group,
+    used_blocks: u32,
+    used_inodes: u32,
+) -> Result<BlockGroupDescriptor, Error> {
+    let block_size = superblock.block_size();
+    let inodes_per_group = superblock.inodes_per_group;
+    let bits = block_size as u32 * 8;
+
+    let mut block_bitmap = vec![0; block_size];
+    let mut inode_bitmap = vec![0; block_size];
+    // bits past the end of a group are always marked as used
+    for bit in (0..used_blocks).chain(group.block_count..bits) {
+        block_bitmap[bit as usize >> 3] |= 1 << (bit & 7);
+    }
+    for bit in (0..used_inodes).chain(inodes_per_group..bits) {
+        inode_bitmap[bit as usize >> 3] |= 1 << (bit & 7);
+    }
+    write_block(volume, group.block_bitmap, block_bitmap, block_size)?;
+    write_block(volume, group.inode_bitmap, inode_bitmap, block_size)?;
+    let table_blocks = (group.data - group.inode_table) as usize;
+    write_block(
+        volume,
+        group.inode_table,
+        vec![0; table_blocks * block_size],
+        block_size,
+    )?;
+
+    let mut descr: BlockGroupDescriptor = unsafe { mem::zeroed() };
+    descr.block_usage_addr = group.block_bitmap;
+    descr.inode_usage_addr = group.inode_bitmap;
+    descr.inode_table_block = group.inode_table;
+    descr.free_blocks_count = (group.block_count - used_blocks) as u16;
+    descr.free_inodes_count = (inodes_per_group - used_inodes) as u16;
+    Ok(descr)
+}
+
+/// Formats `volume` as an empty ext2 filesystem holding only the root
+/// directory and `lost+found`. The whole volume is used, except for a
+/// trailing block group too small for its own metadata.
+pub fn format<S: SectorSize, V: Volume<u8, S>>(
+    volume: &mut V,
+    options: &FormatOptions,
+) -> Result<(), Error> {
+    let volume_size = match volume.size().try_len() {
+        Some(size) => size.into_index(),
+        None => return Err(bad_options("the volume has no size")),
+    };
+
+    let mut superblock = layout(options, volume_size)?;
+    let block_size = superblock.block_size();
+
+    let group_count = superblock.inodes_count / superblock.inodes_per_group;
+    let descr_blocks = descriptor_blocks(group_count, block_size);
+    let groups = (0..group_count)
+        .map(|group| Group::new(&superblock, group, descr_blocks))
+        .collect::<Vec<_>>();
+
+    // the root directory and lost+found take the first data blocks
+    let lost_blocks = (LOST_AND_FOUND_SIZE / block_size).max(1).min(12);
+    let root_block = groups[0].data;
+    let lost_first_block = root_block + 1;
+    let used_end = lost_first_block + lost_blocks as u32;
+    if used_end > groups[0].first_block + groups[0].block_count {
+        return Err(bad_options("the first block group is too small"));
+    }
+
+    let mut table = Vec::with_capacity(groups.len());
+    let mut free_blocks = 0;
+    let mut free_inodes = 0;
+    for (index, group) in groups.iter().enumerate() {
+        let (used_blocks, used_inodes) = if index == 0 {
+            (used_end - group.first_block, FIRST_INODE)
+        } else {
+            (group.data - group.first_block, 0)
+        };
+
+        let mut descr =
+            init_group(volume, &superblock, group, used_blocks, used_inodes)?;
+        if index == 0 {
+            descr.dirs_count = 2;
+        }
+        free_blocks += descr.free_blocks_count as u32;
+        free_inodes += descr.free_inodes_count as u32;
+        table.push(descr);
+    }
+
+    superblock.free_blocks_count = free_blocks;
+    superblock.free_inodes_count = free_inodes;
+
+    write_directories(
+        volume,
+        options,
+        &superblock,
+        &groups[0],
+        root_block,
+        lost_first_block,
+        lost_blocks,
+    )?;
+
+    for (index, group) in groups.iter().enumerate() {
+        if !group.backup {
+            continue;
+        }
+
+        let (offset, table_block) = if index == 0 {
+            (1024, superblock.first_data_block + 1)
+        } else {
+            (
+                group.first_block as u64 * block_size as u64,
+                group.first_block + 1,
+            )
+        };
+        let mut copy = superblock;
+        copy.block_group = index as u16;
+        copy.store(volume, Address::from(offset))?;
+        BlockGroupDescriptor::store_descriptor_table(
+            &table,
+            volume,
+            Address::from(table_block as u64 * block_size as u64),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Validates `options` and lays out a superblock for a volume of
+/// `volume_size` bytes, without free counts
+fn layout(
+    options: &FormatOptions,
+    volume_size: u64,
+) -> Result<Superblock, Error> {
+    let block_size = options.block_size;
+    if !block_size.is_power_of_two() || block_size < 1024 || block_size > 4096 {
+        return Err(bad_options(
+            "block size must be a power of two from 1 KiB to 4 KiB",
+        ));
+    }
+
+    let inode_size = options.inode_size as usize;
+    if !inode_size.is_power_of_two()
+        || inode_size < mem::size_of::<RawInode>()
+        || inode_size > block_size
+        || options.revision == 0 && inode_size != mem::size_of::<RawInode>()
+    {
+        return Err(Error::BadInodeSize { size: inode_size });
+    }
+
+    let features = options.features;
+    if options.revision > 1 {
+        return Err(bad_options("revision must be 0 or 1"));
+    }
+    if options.revision == 0 && features != Features::empty() {
+        return Err(bad_options("revision 0 doesn't support any features"));
+    }
+    if !features.can_mount() {
+        return Err(Error::UnsupportedFeatures {
+            required: features.unsupported_required(),
+        });
+    }
+    let unsupported_optional =
+        FeaturesOptional::JOURNAL | FeaturesOptional::SELF_RESIZE;
+    if !features.can_write()
+        || features.optional.intersects(unsupported_optional)
+    {
+        return Err(bad_options("features can't be created"));
+    }
+
+    if options.label.len() > 16 {
+        return Err(bad_options("label is longer than 16 bytes"));
+    }
+    if options.reserved_percent > 50 {
+        return Err(bad_options("at most 50% of blocks can be reserved"));
+    }
+
+    let first_data_block = if block_size == 1024 { 1 } else { 0 };
+    let blocks_per_group = block_size as u32 * 8;
+    let inodes_per_block = (block_size / inode_size) as u32;
+    let inodes_per_group = match options.inodes_per_group {
+        Some(inodes) => inodes,
+        None => {
+            (blocks_per_group as u64 * block_size as u64 / BYTES_PER_INODE)
+                as u32
+        }
+    };
+    // fill whole blocks of the inode table and whole bytes of the bitmap
+    let unit = inodes_per_block.max(8);
+    let inodes_per_group = (inodes_per_group + unit - 1) / unit * unit;
+    if inodes_per_group < FIRST_INODE || inodes_per_group > blocks_per_group {
+        return Err(bad_options("invalid number of inodes per group"));
+    }
+
+    let inode_table_blocks =
+        (inodes_per_group as usize * inode_size + block_size - 1) / block_size;
+
+    let mut blocks_count =
+        (volume_size / block_size as u64).min(u32::max_value() as u64) as u32;
+    if blocks_count <= first_data_block {
+        return Err(bad_options("the volume is too small"));
+    }
+    let mut group_count = (blocks_count - first_data_block + blocks_per_group
+        - 1)
+        / blocks_per_group;
+
+    let mut superblock: Superblock = unsafe { mem::zeroed() };
+    superblock.rev_major = options.revision;
+    superblock.features_opt = features.optional;
+    superblock.features_req = features.required;
+    superblock.features_ronly = features.read_only;
+    if features.optional.contains(FeaturesOptional::HASH_INDEX) {
+        // half-MD4 seeded with the UUID, like mke2fs does with a fresh one
+        superblock.def_hash_version = 1;
+        let mut seed = [0; 4];
+        for (word, bytes) in seed.iter_mut().zip(options.uuid.chunks(4)) {
+            *word = dir::read_u32(bytes, 0);
+        }
+        superblock.hash_seed = seed;
+        superblock.flags = FsFlags::SIGNED_HASH;
+    }
+
+    // drop a trailing group without room for its metadata and some data
+    let last = group_count - 1;
+    let last_blocks = blocks_count - first_data_block - last * blocks_per_group;
+    let mut overhead = 2 + inode_table_blocks as u32;
+    if superblock.has_backup(last) {
+        overhead += 1 + descriptor_blocks(group_count, block_size);
+    }
+    if last_blocks <= overhead {
+        group_count -= 1;
+        blocks_count = first_data_block + group_count * blocks_per_group;
+    }
+    if group_count == 0 {
+        return Err(bad_options("the volume is too small"));
+    }
+
+    let inodes_count = inodes_per_group as u64 * group_count as u64;
+    if inodes_count > u32::max_value() as u64 {
+        return Err(bad_options("too many inodes"));
+    }
+
+    superblock.inodes_count = inodes_count as u32;
+    superblock.blocks_count = blocks_count;
+    superblock.r_blocks_count =
+        (blocks_count as u64 * options.reserved_percent as u64 / 100) as u32;
+    superblock.first_data_block = first_data_block;
+    superblock.log_block_size = block_size.trailing_zeros() - 10;
+    superblock.log_frag_size = superblock.log_block_size as i32;
+    superblock.blocks_per_group = blocks_per_group;
+    superblock.frags_per_group = blocks_per_group;
+    superblock.inodes_per_group = inodes_per_group;
+    superblock.wtime = options.time;
+    superblock.max_mnt_count = -1;
+    superblock.magic = EXT2_MAGIC;
+    superblock.state = FS_CLEAN;
+    superblock.errors = ERR_IGNORE;
+    superblock.lastcheck = options.time;
+    superblock.creator_os = OS_LINUX;
+    if options.revision > 0 {
+        superblock.first_inode = FIRST_INODE;
+        superblock.inode_size = options.inode_size;
+        superblock.fs_id = options.uuid;
+        superblock.volume_name[..options.label.len()]
+            .copy_from_slice(&options.label);
+    }
+
+    Ok(superblock)
+}
+
+/// Writes the root directory and `lost+found`, including their inodes
+fn write_directories<S: SectorSize, V: Volume<u8, S>>(
+    volume: &mut V,
+    options: &FormatOptions,
+    superblock: &Superblock,
+    group: &Group,
+    root_block: u32,
+    lost_first_block: u32,
+    lost_blocks: usize,
+) -> Result<(), Error> {
+    let block_size = superblock.block_size();
+    let ty = if options
+        .features
+        .required
+        .contains(FeaturesRequired::REQ_DIRECTORY_TYPE)
+    {
+        dir::entry_type(TypePerm::DIRECTORY)
+    } else {
+        0
+    };
+
+    let mut block = vec![0; block_size];
+    dir::write_record(&mut block, 0, ROOT_INODE, 12, b".", ty);
+    dir::write_record(&mut block, 12, ROOT_INODE, 12, b"..", ty);
+    dir::write_record(
+        &mut block,
+        24,
+        FIRST_INODE,
+        block_size - 24,
+        b"lost+found",
+        ty,
+    );
+    write_block(volume, root_block, block, block_size)?;
+
+    for index in 0..lost_blocks {
+        let mut block = vec![0; block_size];
+        if index == 0 {
+            dir::write_record(&mut block, 0, FIRST_INODE, 12, b".", ty);
+            dir::write_record(
+                &mut block,
+                12,
+                ROOT_INODE,
+                block_size - 12,
+                b"..",
+                ty,
+            );
+        } else {
+            dir::write_record(&mut block, 0, 0, block_size, b"", 0);
+        }
+        let block_number = lost_first_block + index as u32;
+        write_block(volume, block_number, block, block_size)?;
+    }
+
+    let root_blocks = [root_block];
+    let lost_blocks = (0..lost_blocks as u32)
+        .map(|index| lost_first_block + index)
+        .collect::<Vec<_>>();
+    let directories = [
+        (ROOT_INODE, ROOT_PERMISSIONS, 3, &root_blocks[..]),
+        (FIRST_INODE, LOST_AND_FOUND_PERMISSIONS, 2, &lost_blocks[..]),
+    ];
+
+    let inode_size = options.inode_size as usize;
+    for &(num, permissions, links, blocks) in directories.iter() {
+        let mut inode: RawInode = unsafe { mem::zeroed() };
+        inode.type_perm =
+            TypePerm::DIRECTORY | TypePerm::from_bits_truncate(permissions);
+        inode.atime = options.time;
+        inode.ctime = options.time;
+        inode.mtime = options.time;
+        inode.hard_links = links;
+        inode.size_low = (blocks.len() * block_size) as u32;
+        inode.sectors_count = (blocks.len() * block_size / 512) as u32;
+        let mut pointers = [0; 12];
+        pointers[..blocks.len()].copy_from_slice(blocks);
+        inode.direct_pointer = pointers;
+
+        // both inodes are in the first group
+        let offset = group.inode_table as u64 * block_size as u64
+            + (num - 1) as u64 * inode_size as u64;
+        commit(
+            volume,
+            VolumeCommit::from_cast(&inode, Address::from(offset)),
+        )?;
+
+        let base_size = mem::size_of::<RawInode>();
+        if inode_size > base_size {
+            let mut extra: InodeExtra = unsafe { mem::zeroed() };
+            let extra_isize = mem::size_of::<InodeExtra>();
+            extra.extra_isize = extra_isize.min(inode_size - base_size) as u16;
+            let (_, time_extra) =
+                InodeExtra::encode_time(options.time as i64, 0);
+            extra.ctime_extra = time_extra;
+            extra.mtime_extra = time_extra;
+            extra.atime_extra = time_extra;
+            extra.crtime = options.time;
+            extra.crtime_extra = time_extra;
+
+            let extra_offset = Address::from(offset + base_size as u64);
+            let mut data =
+                VolumeCommit::from_cast(&extra, extra_offset).into_inner();
+            data.truncate(extra.extra_isize as usize);
+            commit(volume, VolumeCommit::new(data, extra_offset))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_block<S: SectorSize, V: Volume<u8, S>>(
+    volume: &mut V,
+    block: u32,
+    data: Vec<u8>,
+    block_size: usize,
+) -> Result<(), Error> {
+    let offset = Address::from(block as u64 * block_size as u64);
+    commit(volume, VolumeCommit::new(data, offset))
+}
+
+fn commit<S: SectorSize, V: Volume<u8, S>>(
+    volume: &mut V,
+    data: VolumeCommit<u8, S>,
+) -> Result<(), Error> {
+    let end = data.address() + Address::from(data.len());
+    if volume.size() < end {
+        return Err(Error::AddressOutOfBounds {
+            sector: end.sector(),
+            offset: end.offset(),
+            size: end.sector_size(),
+        });
+    }
+
+    volume.commit(Some(data)).map_err(|err| err.into())
+}
+
+fn bad_options(reason: &str) -> Error {
+    Error::BadFormatOptions {
+        reason: String::from(reason),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use alloc::Vec;
+
+    use error::Error;
+    use fs::Ext2;
+    use fs::check::check;
+    use fs::features::Features;
+    use fs::mount::MountOptions;
+    use fs::sync::Synced;
+    use sector::Size512;
+    use volume::Volume;
+
+    use super::{format, FormatOptions};
+
+    /// A volume of `size` bytes formatted with `options`
+    pub(crate) fn formatted(size: usize, options: &FormatOptions) -> Vec<u8> {
+        let mut volume = vec![0_u8; size];
+        assert!(format::<Size512, _>(&mut volume, options).is_ok());
+        volume
+    }
+
+    /// Mounts `volume` read-write
+    pub(crate) fn mount_rw<V: Volume<u8, Size512>>(
+        volume: V,
+    ) -> Synced<Ext2<Size512, V>> {
+        Synced::<Ext2<Size512, _>>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap()
+    }
+
+    /// A fresh 1 MiB filesystem with the default options, mounted read-write
+    pub(crate) fn scratch() -> Synced<Ext2<Size512, Vec<u8>>> {
+        mount_rw(formatted(1024 * 1024, &FormatOptions::new()))
+    }
+
+    #[test]
+    fn format_mount() {
+        let mut options = FormatOptions::new();
+        options.label(b"formatted").uuid([7; 16]).inode_size(256);
+        let fs = mount_rw(formatted(4 * 1024 * 1024, &options));
+        {
+            let inner = fs.inner();
+            assert_eq!(inner.block_size(), 1024);
+            assert_eq!(inner.inode_size(), 256);
+            assert_eq!(inner.total_block_count(), 4 * 1024);
+            assert_eq!(
+                &inner.superblock.inner.volume_name[..10],
+                b"formatted\0"
+            );
+            assert_eq!(inner.superblock.inner.fs_id, [7; 16]);
+        }
+
+        let report = check(&fs).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(report.directories, 2);
+        assert!(fs.find_inode(b"/lost+found").unwrap().is_dir());
+    }
+
+    #[test]
+    fn format_revision_0() {
+        let mut options = FormatOptions::new();
+        options
+            .revision(0)
+            .features(Features::empty())
+            .inodes_per_group(100);
+        let volume = formatted(2 * 8 * 1024 * 1024 + 100 * 1024, &options);
+
+        let fs = Synced::<Ext2<Size512, _>>::new(volume).unwrap();
+        {
+            let inner = fs.inner();
+            assert_eq!(inner.version().0, 0);
+            assert_eq!(inner.block_groups.inner.len(), 3);
+            // rounded up to fill whole blocks of the inode table
+            assert_eq!(inner.inodes_count(), 104);
+        }
+        let report = check(&fs).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn format_errors() {
+        let mut volume = vec![0_u8; 4096];
+        match format::<Size512, _>(&mut volume, &FormatOptions::new()) {
+            Err(Error::BadFormatOptions { .. }) => (),
+            result => panic!("formatted a tiny volume: {:?}", result),
+        }
+
+        let mut volume = vec![0_u8; 1024 * 1024];
+        let mut options = FormatOptions::new();
+        options.inode_size(200);
+        match format::<Size512, _>(&mut volume, &options) {
+            Err(Error::BadInodeSize { size: 200 }) => (),
+            result => panic!("accepted a bad inode size: {:?}", result),
+        }
+        options.inode_size(128).revision(0);
+        match format::<Size512, _>(&mut volume, &options) {
+            Err(Error::BadFormatOptions { .. }) => (),
+            result => panic!("accepted features on revision 0: {:?}", result),
+        }
+    }
+}
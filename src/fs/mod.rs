@@ -4,12 +4,26 @@ use alloc::Vec;
 
 use error::Error;
 use sector::{Address, SectorSize};
-use volume::Volume;
-use sys::superblock::Superblock;
+use volume::{Volume, VolumeCommit, WriteClass};
+use sys::superblock::{Superblock, FS_CLEAN};
 use sys::block_group::BlockGroupDescriptor;
-use sys::inode::Inode as RawInode;
+use sys::inode::{Inode as RawInode, InodeExtra};
+
+use self::features::Features;
+use self::mount::MountOptions;
 
 pub mod sync;
+pub mod allocator;
+pub mod metadata;
+pub mod xattr;
+pub mod features;
+pub mod mount;
+pub mod check;
+pub mod repair;
+pub mod mkfs;
+pub mod resize;
+mod dir;
+mod htree;
 
 pub(crate) struct Struct<T, S: SectorSize> {
     pub inner: T,
@@ -29,15 +43,54 @@ pub struct Ext2<S: SectorSize, V: Volume<u8, S>> {
     pub(crate) volume: V,
     pub(crate) superblock: Struct<Superblock, S>,
     pub(crate) block_groups: Struct<Vec<BlockGroupDescriptor>, S>,
+    read_only: bool,
+    mounted: bool,
+    time: u32,
 }
 
 impl<S: SectorSize, V: Volume<u8, S>> Ext2<S, V> {
+    /// Opens the filesystem on `volume` read-only, without recording the
+    /// mount in the superblock
     pub fn new(volume: V) -> Result<Ext2<S, V>, Error> {
-        let superblock = unsafe { Struct::from(Superblock::find(&volume)?) };
+        Ext2::mount(volume, &MountOptions::new())
+    }
+
+    /// Mounts the filesystem on `volume`.
+    ///
+    /// A read-write mount increments the mount count, sets the mount time
+    /// and path and marks the filesystem as not clean until `unmount` is
+    /// called. If the filesystem uses a read-only feature that isn't
+    /// supported, it's mounted read-only regardless of `options`.
+    pub fn mount(
+        volume: V,
+        options: &MountOptions,
+    ) -> Result<Ext2<S, V>, Error> {
+        let superblock = match options.superblock {
+            Some(offset) => unsafe {
+                Superblock::find_at(&volume, Address::from(offset))?
+            },
+            None => unsafe { Superblock::find(&volume)? },
+        };
+        let mut superblock = Struct::from(superblock);
+
+        // unknown required features make the filesystem unreadable, unknown
+        // read-only features only prevent writing to it
+        let features = Features::new(&superblock.inner);
+        if !features.can_mount() {
+            return Err(Error::UnsupportedFeatures {
+                required: features.unsupported_required(),
+            });
+        }
+        let read_only = !options.read_write || !features.can_write();
+        let time = options.time.unwrap_or(superblock.inner.wtime);
+
+        // the descriptor table is in the block following the superblock
+        let log_block_size = superblock.inner.log_block_size + 10;
+        let superblock_block = superblock.offset.into_index() >> log_block_size;
         let block_groups_offset = Address::with_block_size(
-            superblock.inner.first_data_block + 1,
+            superblock_block as u32 + 1,
             0,
-            superblock.inner.log_block_size + 10,
+            log_block_size,
         );
         let block_groups_count = superblock
             .inner
@@ -54,12 +107,92 @@ impl<S: SectorSize, V: Volume<u8, S>> Ext2<S, V> {
                 block_groups_count,
             )?
         };
-        let block_groups = Struct::from(block_groups);
-        Ok(Ext2 {
+        let mut block_groups = Struct::from(block_groups);
+
+        // when mounted from a backup, changes go to the primary copies
+        if options.superblock.is_some() {
+            superblock.inner.block_group = 0;
+            superblock.offset = Address::from(1024_usize);
+            block_groups.offset = Address::with_block_size(
+                superblock.inner.first_data_block + 1,
+                0,
+                log_block_size,
+            );
+        }
+
+        let mut fs = Ext2 {
             volume,
             superblock,
             block_groups,
-        })
+            read_only,
+            mounted: false,
+            time,
+        };
+
+        if !read_only {
+            {
+                let superblock = &mut fs.superblock.inner;
+                superblock.mnt_count = superblock.mnt_count.wrapping_add(1);
+                superblock.mtime = time;
+                superblock.state &= !FS_CLEAN;
+
+                // the path is a C string, keep room for the terminator
+                let len = options.path.len().min(63);
+                superblock.last_mnt_path = [0; 64];
+                superblock.last_mnt_path[..len]
+                    .copy_from_slice(&options.path[..len]);
+            }
+            fs.commit_superblock()?;
+            fs.mounted = true;
+        }
+
+        Ok(fs)
+    }
+
+    /// Unmounts a read-write filesystem, marking it as clean. The filesystem
+    /// is read-only afterwards. Does nothing for read-only filesystems.
+    pub fn unmount(&mut self) -> Result<(), Error> {
+        if !self.mounted {
+            return Ok(());
+        }
+
+        self.superblock.inner.state |= FS_CLEAN;
+        self.superblock.inner.wtime = self.time;
+        self.write_back()?;
+        if let Err(err) = self.volume.commit(None) {
+            return Err(err.into());
+        }
+
+        self.mounted = false;
+        self.read_only = true;
+        Ok(())
+    }
+
+    /// The volume this filesystem lives on
+    pub fn volume(&self) -> &V {
+        &self.volume
+    }
+
+    /// The feature set of this filesystem
+    pub fn features(&self) -> Features {
+        Features::new(&self.superblock.inner)
+    }
+
+    /// Whether this filesystem refuses all writes, either because it was
+    /// mounted read-only or because it uses a read-only feature that isn't
+    /// supported
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fails with `Error::ReadOnly` unless the filesystem is mounted
+    /// read-write
+    pub fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
     }
 
     pub fn version(&self) -> (u32, u16) {
@@ -73,7 +206,6 @@ impl<S: SectorSize, V: Volume<u8, S>> Ext2<S, V> {
         if self.version().0 == 0 {
             mem::size_of::<RawInode>()
         } else {
-            // note: inodes bigger than 128 are not supported
             self.superblock.inner.inode_size as usize
         }
     }
@@ -120,6 +252,162 @@ impl<S: SectorSize, V: Volume<u8, S>> Ext2<S, V> {
     pub fn log_sector_size(&self) -> u32 {
         S::LOG_SIZE
     }
+
+    /// Address of the raw inode `inode` in its block group's inode table
+    pub fn inode_address(&self, inode: u32) -> Address<S> {
+        let group = self.block_group_of_inode(inode);
+        let index = (inode as usize - 1) % self.inodes_count();
+        let inodes_block = self.block_groups.inner[group].inode_table_block;
+        Address::with_block_size(
+            inodes_block,
+            (index * self.inode_size()) as i32,
+            self.log_block_size(),
+        )
+    }
+
+    /// POSIX time used for timestamps of modified inodes. There is no clock
+    /// available in `no_std`, so it's the time given in `MountOptions`, or
+    /// the last write time of the filesystem if none was given.
+    pub fn timestamp(&self) -> u32 {
+        self.time
+    }
+
+    /// Advances the time used for timestamps, see `timestamp`
+    pub fn set_timestamp(&mut self, time: u32) {
+        self.time = time;
+    }
+
+    pub(crate) fn read_block(&self, block: u32) -> Result<Vec<u8>, Error> {
+        let log_block_size = self.log_block_size();
+        let offset = Address::with_block_size(block, 0, log_block_size);
+        let end = Address::with_block_size(block + 1, 0, log_block_size);
+        self.volume
+            .slice(offset..end)
+            .map(|slice| slice.to_vec())
+            .map_err(|err| err.into())
+    }
+
+    pub(crate) fn write_block(
+        &mut self,
+        block: u32,
+        data: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.write_block_as(block, data, WriteClass::Data)
+    }
+
+    /// Writes the block `block`, which holds data of the kind `class`
+    pub(crate) fn write_block_as(
+        &mut self,
+        block: u32,
+        data: Vec<u8>,
+        class: WriteClass,
+    ) -> Result<(), Error> {
+        let offset = Address::with_block_size(block, 0, self.log_block_size());
+        self.commit_as(VolumeCommit::new(data, offset), class)
+    }
+
+    pub(crate) fn commit(
+        &mut self,
+        commit: VolumeCommit<u8, S>,
+    ) -> Result<(), Error> {
+        self.commit_as(commit, WriteClass::Data)
+    }
+
+    /// Commits to the volume, telling it what the commit holds so that
+    /// write-back volumes can order their writes
+    pub(crate) fn commit_as(
+        &mut self,
+        commit: VolumeCommit<u8, S>,
+        class: WriteClass,
+    ) -> Result<(), Error> {
+        self.check_writable()?;
+        self.volume
+            .commit_as(Some(commit), class)
+            .map_err(|err| err.into())
+    }
+
+    pub(crate) fn commit_inode(
+        &mut self,
+        offset: Address<S>,
+        inode: &RawInode,
+    ) -> Result<(), Error> {
+        let commit = VolumeCommit::from_cast(inode, offset);
+        self.commit_as(commit, WriteClass::Inode)
+    }
+
+    /// Writes the extended part of the inode at `offset`, as far as its
+    /// `extra_isize` reaches
+    pub(crate) fn commit_inode_extra(
+        &mut self,
+        offset: Address<S>,
+        extra: &InodeExtra,
+    ) -> Result<(), Error> {
+        let offset = offset + Address::from(mem::size_of::<RawInode>());
+        let mut data = VolumeCommit::from_cast(extra, offset).into_inner();
+        data.truncate(extra.extra_isize as usize);
+        self.commit_as(VolumeCommit::new(data, offset), WriteClass::Inode)
+    }
+
+    /// Writes the primary superblock only, see `write_back`
+    pub(crate) fn commit_superblock(&mut self) -> Result<(), Error> {
+        self.check_writable()?;
+        self.superblock
+            .inner
+            .store(&mut self.volume, self.superblock.offset)
+    }
+
+    /// Writes the superblock and the block group descriptor table to their
+    /// primary location and to every group holding a backup. The backups are
+    /// not kept up to date by every change, only when this is called,
+    /// including on `unmount`.
+    pub fn write_back(&mut self) -> Result<(), Error> {
+        self.check_writable()?;
+        let log_block_size = self.log_block_size();
+
+        for group in 0..self.block_groups.inner.len() {
+            if !self.superblock.inner.has_backup(group as u32) {
+                continue;
+            }
+
+            // the primary copy is at byte 1024 regardless of the block size,
+            // backups are at the start of the first block of their group
+            let mut superblock = self.superblock.inner;
+            let (offset, table_offset) = if group == 0 {
+                (self.superblock.offset, self.block_groups.offset)
+            } else {
+                let first_block = self.group_first_block(group);
+                superblock.block_group = group as u16;
+                (
+                    Address::with_block_size(first_block, 0, log_block_size),
+                    Address::with_block_size(
+                        first_block + 1,
+                        0,
+                        log_block_size,
+                    ),
+                )
+            };
+
+            superblock.store(&mut self.volume, offset)?;
+            BlockGroupDescriptor::store_descriptor_table(
+                &self.block_groups.inner,
+                &mut self.volume,
+                table_offset,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn commit_block_group(
+        &mut self,
+        index: usize,
+    ) -> Result<(), Error> {
+        let offset = self.block_groups.offset
+            + Address::from(index * mem::size_of::<BlockGroupDescriptor>());
+        let commit =
+            VolumeCommit::from_cast(&self.block_groups.inner[index], offset);
+        self.commit_as(commit, WriteClass::Superblock)
+    }
 }
 
 #[cfg(test)]
@@ -131,6 +419,7 @@ mod tests {
     use volume::Volume;
 
     use super::Ext2;
+    use super::mount::MountOptions;
 
     #[test]
     fn file_len() {
@@ -168,4 +457,202 @@ mod tests {
         println!("version: {}.{}", vers.0, vers.1);
         assert_eq!(128, fs.inode_size());
     }
+
+    #[test]
+    fn features() {
+        use std::io::Read;
+
+        use error::Error;
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+
+        let options = MountOptions::new().read_write(true).clone();
+        let fs = Ext2::<Size512, _>::mount(volume.clone(), &options).unwrap();
+        assert!(fs.features().can_write());
+        assert!(!fs.is_read_only());
+
+        // an unknown read-only feature
+        let mut ronly = volume.clone();
+        ronly[1024 + 0x64] |= 0x80;
+        let mut fs = Ext2::<Size512, _>::mount(ronly, &options).unwrap();
+        assert_eq!(fs.features().unsupported_read_only(), 0x80);
+        assert!(fs.is_read_only());
+        match fs.commit_superblock() {
+            Err(Error::ReadOnly) => (),
+            _ => panic!("read-only filesystem accepted a write"),
+        }
+
+        // a journal that needs to be replayed
+        let mut journal = volume.clone();
+        journal[1024 + 0x60] |= 0x04;
+        match Ext2::<Size512, _>::new(journal) {
+            Err(Error::UnsupportedFeatures { required: 0x04 }) => (),
+            _ => panic!("mounted a filesystem with unsupported features"),
+        }
+    }
+
+    #[test]
+    fn mount() {
+        use std::io::Read;
+
+        use error::Error;
+        use sys::superblock::FS_CLEAN;
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+
+        let mut fs = Ext2::<Size512, _>::new(volume).unwrap();
+        let mnt_count = fs.superblock.inner.mnt_count;
+        assert!(fs.is_read_only());
+        match fs.commit_superblock() {
+            Err(Error::ReadOnly) => (),
+            _ => panic!("read-only mount accepted a write"),
+        }
+        assert!(fs.unmount().is_ok());
+
+        let volume = fs.volume;
+        let mut fs = Ext2::<Size512, _>::mount(
+            volume,
+            MountOptions::new()
+                .read_write(true)
+                .time(1_500_000_000)
+                .path(b"/mnt"),
+        ).unwrap();
+        assert!(!fs.is_read_only());
+        assert_eq!(fs.timestamp(), 1_500_000_000);
+        let superblock = fs.superblock.inner;
+        assert_eq!({ superblock.mnt_count }, mnt_count + 1);
+        assert_eq!({ superblock.mtime }, 1_500_000_000);
+        assert_eq!(&superblock.last_mnt_path[..5], b"/mnt\0");
+        assert_eq!(superblock.state & FS_CLEAN, 0);
+
+        // the superblock on the volume must agree with the one in memory
+        let on_disk = Ext2::<Size512, _>::new(fs.volume.clone()).unwrap();
+        let superblock = on_disk.superblock.inner;
+        assert_eq!(superblock.state & FS_CLEAN, 0);
+        assert_eq!({ superblock.mnt_count }, mnt_count + 1);
+
+        assert!(fs.unmount().is_ok());
+        assert!(fs.is_read_only());
+        let on_disk = Ext2::<Size512, _>::new(fs.volume).unwrap();
+        let superblock = on_disk.superblock.inner;
+        assert_eq!(superblock.state & FS_CLEAN, FS_CLEAN);
+        assert_eq!({ superblock.wtime }, 1_500_000_000);
+    }
+
+    #[test]
+    fn backups() {
+        use std::io::Read;
+
+        use sys::superblock::Superblock;
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+
+        let mut fs = Ext2::<Size512, _>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap();
+        fs.superblock.inner.free_blocks_count -= 1;
+        fs.block_groups.inner[0].free_blocks_count -= 1;
+        let free_blocks = fs.superblock.inner.free_blocks_count;
+        assert!(fs.write_back().is_ok());
+
+        let log_block_size = fs.log_block_size();
+        for group in 1..fs.block_groups.inner.len() {
+            if !fs.superblock.inner.has_backup(group as u32) {
+                continue;
+            }
+
+            let first_block = fs.group_first_block(group);
+            let block = |block| {
+                Address::<Size512>::with_block_size(block, 0, log_block_size)
+            };
+
+            let (backup, _) = unsafe {
+                fs.volume
+                    .slice(block(first_block)..block(first_block + 1))
+                    .unwrap()
+                    .dynamic_cast::<Superblock>()
+            };
+            assert_eq!({ backup.free_blocks_count }, free_blocks);
+            assert_eq!({ backup.block_group }, group as u16);
+
+            let table = fs.volume
+                .slice(block(first_block + 1)..block(first_block + 2))
+                .unwrap();
+            let free_blocks_count = table[12] as u16 | (table[13] as u16) << 8;
+            let descr = fs.block_groups.inner[0];
+            assert_eq!(free_blocks_count, { descr.free_blocks_count });
+        }
+    }
+
+    #[test]
+    fn backup_superblock() {
+        use std::io::Read;
+
+        use error::Error;
+        use sys::superblock::Superblock;
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+
+        // put a backup into the first block of group 1, even if the image
+        // is too small to have one
+        let (offset, table, block_size) = {
+            let fs = Ext2::<Size512, _>::new(volume.clone()).unwrap();
+            let block_size = fs.block_size();
+            let offset = fs.group_first_block(1) as usize * block_size;
+            let table = fs.block_groups.offset.into_index() as usize;
+            (offset, table, block_size)
+        };
+        if volume.len() < offset + 2 * block_size {
+            volume.resize(offset + 2 * block_size, 0);
+        }
+        let superblock = volume[1024..2048].to_vec();
+        volume[offset..offset + 1024].copy_from_slice(&superblock);
+        let table = volume[table..table + block_size].to_vec();
+        volume[offset + block_size..offset + 2 * block_size]
+            .copy_from_slice(&table);
+
+        // corrupt the magic of the primary superblock
+        volume[1024 + 56] = 0;
+        match Ext2::<Size512, _>::new(volume.clone()) {
+            Err(Error::BadMagic { .. }) => (),
+            _ => panic!("mounted a filesystem with a corrupt superblock"),
+        }
+
+        let backups =
+            unsafe { Superblock::find_backups::<Size512, _>(&volume) };
+        assert!(
+            backups
+                .iter()
+                .any(|&(_, address)| address.into_index() == offset as u64)
+        );
+
+        let mut fs = Ext2::<Size512, _>::mount(
+            volume,
+            MountOptions::new()
+                .read_write(true)
+                .superblock(offset as u64),
+        ).unwrap();
+        assert_eq!(fs.inode_size(), 128);
+        assert!(fs.unmount().is_ok());
+
+        // the read-write mount restored the primary superblock
+        assert!(Ext2::<Size512, _>::new(fs.volume).is_ok());
+    }
 }
@@ -0,0 +1,59 @@
+use alloc::Vec;
+
+/// Options of `Ext2::mount`. Filesystems are mounted read-only unless
+/// `read_write` is set.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MountOptions {
+    pub(crate) read_write: bool,
+    pub(crate) time: Option<u32>,
+    pub(crate) path: Vec<u8>,
+    pub(crate) superblock: Option<u64>,
+}
+
+impl MountOptions {
+    pub fn new() -> MountOptions {
+        MountOptions {
+            read_write: false,
+            time: None,
+            path: Vec::new(),
+            superblock: None,
+        }
+    }
+
+    /// Allow modifications of the filesystem. A filesystem using an
+    /// unsupported read-only feature is still mounted read-only, check
+    /// `Ext2::is_read_only` after mounting.
+    pub fn read_write(&mut self, read_write: bool) -> &mut MountOptions {
+        self.read_write = read_write;
+        self
+    }
+
+    /// Current POSIX time, used as the mount time and for timestamps of
+    /// modified inodes. Defaults to the last write time of the filesystem.
+    pub fn time(&mut self, time: u32) -> &mut MountOptions {
+        self.time = Some(time);
+        self
+    }
+
+    /// Path the filesystem is mounted at, recorded in the superblock when
+    /// mounted read-write
+    pub fn path(&mut self, path: &[u8]) -> &mut MountOptions {
+        self.path = path.to_vec();
+        self
+    }
+
+    /// Byte offset of a backup superblock to use instead of the primary one,
+    /// like `mount -o sb=` (see `Superblock::find_backups`). A read-write
+    /// mount writes the backup over the primary superblock, and the backup
+    /// descriptor table over the primary one on `write_back`.
+    pub fn superblock(&mut self, offset: u64) -> &mut MountOptions {
+        self.superblock = Some(offset);
+        self
+    }
+}
+
+impl Default for MountOptions {
+    fn default() -> MountOptions {
+        MountOptions::new()
+    }
+}
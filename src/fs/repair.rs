@@ -0,0 +1,310 @@
+//! Automatic repairs of the problems found by `check`, similar to
+//! `e2fsck -p`.
+//!
+//! Bitmaps and free counts are rebuilt from the blocks and inodes actually in
+//! use, dangling directory entries are cleared, except for `.` and `..` which
+//! are pointed at the directory and its parent, unattached inodes are linked
+//! into `/lost+found` and link counts are set to the number of entries
+//! pointing to each inode. Duplicate and invalid block pointers are reported,
+//! but left alone.
+
+use alloc::{String, Vec};
+
+use genfs::{DirOptions, Fs};
+
+use error::Error;
+use sector::SectorSize;
+use volume::Volume;
+
+use super::Ext2;
+use super::check::{self, Checker, Problem, Report};
+use super::sync::{Inode, Synced};
+
+/// Path of the directory unattached inodes are reconnected to
+pub const LOST_AND_FOUND: &[u8] = b"/lost+found";
+
+/// The outcome of `repair`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Repair {
+    /// Problems found before repairing
+    pub found: Report,
+    /// Problems left after repairing
+    pub remaining: Report,
+}
+
+/// Checks the filesystem and repairs what can be repaired automatically.
+/// The filesystem must be mounted read-write.
+pub fn repair<S: SectorSize, V: Volume<u8, S>>(
+    fs: &Synced<Ext2<S, V>>,
+) -> Result<Repair, Error> {
+    fs.inner().check_writable()?;
+
+    let found = {
+        let mut checker = Checker::new(fs);
+        checker.run()?;
+        // the repairs below allocate blocks and inodes, which must not hand
+        // out any that are in use
+        rebuild_bitmaps(&checker)?;
+        checker.report()
+    };
+
+    let mut orphans = Vec::new();
+    for problem in found.problems.iter() {
+        match *problem {
+            Problem::DanglingEntry { dir, ref name, .. } => match &name[..] {
+                b"." => {
+                    inode(fs, dir)?.replace_entry(b".", dir)?;
+                }
+                // pointed at the parent through the `BadParentEntry`
+                // reported along with it, or by `reconnect`
+                b".." => (),
+                _ => {
+                    inode(fs, dir)?.remove_entry(name)?;
+                }
+            },
+            Problem::BadParentEntry { dir, expected, .. } => {
+                inode(fs, dir)?.replace_entry(b"..", expected)?;
+            }
+            Problem::Unreachable { inode } => orphans.push(inode),
+            _ => (),
+        }
+    }
+    if !orphans.is_empty() {
+        reconnect(fs, &orphans)?;
+    }
+
+    // links can only be counted once the tree is whole again
+    {
+        let mut checker = Checker::new(fs);
+        checker.run()?;
+        for problem in checker.problems.iter() {
+            if let Problem::LinkCount {
+                inode: num,
+                counted,
+                ..
+            } = *problem
+            {
+                let mut inode = inode(fs, num)?;
+                inode.raw_mut().hard_links = counted as u16;
+                inode.commit()?;
+            }
+        }
+        rebuild_bitmaps(&checker)?;
+    }
+
+    let remaining = check::check(fs)?;
+    Ok(Repair { found, remaining })
+}
+
+fn inode<S: SectorSize, V: Volume<u8, S>>(
+    fs: &Synced<Ext2<S, V>>,
+    num: u32,
+) -> Result<Inode<S, V>, Error> {
+    fs.try_inode_nth(num as usize)
+}
+
+/// Writes the bitmaps, free counts and directory counts of every block group
+/// and the superblock as found by `checker`
+pub(crate) fn rebuild_bitmaps<S: SectorSize, V: Volume<u8, S>>(
+    checker: &Checker<S, V>,
+) -> Result<(), Error> {
+    let mut fs = checker.fs.inner();
+    let inodes_per_group = fs.inodes_count();
+    let mut free_blocks = 0;
+    let mut free_inodes = 0;
+
+    for group in 0..fs.block_groups.inner.len() {
+        let first_block = fs.group_first_block(group);
+        let mut blocks = fs.block_bitmap(group)?;
+        for bit in 0..blocks.len() {
+            if checker.is_used(first_block + bit as u32) {
+                blocks.set(bit)?;
+            } else {
+                blocks.clear(bit)?;
+            }
+        }
+
+        let mut inodes = fs.inode_bitmap(group)?;
+        let mut directories = 0;
+        for bit in 0..inodes.len() {
+            let num = group * inodes_per_group + bit + 1;
+            let info = match checker.inodes.get(num) {
+                Some(info) => *info,
+                None => break,
+            };
+            if info.in_use || (num as u32) < checker.first_inode {
+                inodes.set(bit)?;
+            } else {
+                inodes.clear(bit)?;
+            }
+            if info.is_dir() {
+                directories += 1;
+            }
+        }
+
+        fs.commit_bitmap(&blocks)?;
+        fs.commit_bitmap(&inodes)?;
+
+        let group_free_blocks = blocks.count_free();
+        let group_free_inodes = inodes.count_free();
+        fs.block_groups.inner[group].free_blocks_count =
+            group_free_blocks as u16;
+        fs.block_groups.inner[group].free_inodes_count =
+            group_free_inodes as u16;
+        fs.block_groups.inner[group].dirs_count = directories;
+        fs.commit_block_group(group)?;
+
+        free_blocks += group_free_blocks as u32;
+        free_inodes += group_free_inodes as u32;
+    }
+
+    fs.superblock.inner.free_blocks_count = free_blocks;
+    fs.superblock.inner.free_inodes_count = free_inodes;
+    fs.commit_superblock()
+}
+
+/// Links every inode of `orphans` into `/lost+found` as `#<inode>`, creating
+/// the directory if it doesn't exist. The `..` entries of directories are
+/// pointed at `/lost+found` too.
+fn reconnect<S: SectorSize, V: Volume<u8, S>>(
+    fs: &Synced<Ext2<S, V>>,
+    orphans: &[u32],
+) -> Result<(), Error> {
+    let mut lost = match fs.find_inode(LOST_AND_FOUND) {
+        Err(Error::NotFound { .. }) => {
+            fs.clone().create_dir(LOST_AND_FOUND, &DirOptions::new())?;
+            fs.find_inode(LOST_AND_FOUND)?
+        }
+        result => result?,
+    };
+    if !lost.is_dir() {
+        return Err(Error::NotADirectory {
+            inode: lost.num(),
+            name: String::from_utf8_lossy(LOST_AND_FOUND).into_owned(),
+        });
+    }
+
+    for &num in orphans {
+        let mut orphan = inode(fs, num)?;
+        let name = format!("#{}", num).into_bytes();
+        if lost.find_entry(&name)?.is_some() {
+            continue;
+        }
+
+        let ty = fs.entry_type(orphan.raw().type_perm);
+        lost.add_entry(&name, num, ty)?;
+
+        if orphan.is_dir() {
+            match orphan.replace_entry(b"..", lost.num()) {
+                Ok(_) | Err(Error::NotFound { .. }) => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+
+    use genfs::{DirOptions, Fs};
+
+    use error::Error;
+    use fs::Ext2;
+    use fs::mount::MountOptions;
+    use fs::sync::Synced;
+    use sector::Size512;
+
+    use super::repair;
+
+    #[test]
+    fn repair_image() {
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+
+        let mut fs = Synced::<Ext2<Size512, _>>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap();
+        let result = repair(&fs).unwrap();
+        assert!(result.found.is_clean(), "{:?}", result.found.problems);
+
+        assert!(fs
+            .create_dir(b"/orphan/sub", DirOptions::new().recursive(true))
+            .is_ok());
+        let orphan = fs.find_inode(b"/orphan").unwrap().num();
+        let first_inode = fs.inner().first_inode();
+        let unused = fs.inodes()
+            .map(|inode| inode.unwrap())
+            .find(|inode| !inode.in_use() && inode.num() >= first_inode)
+            .unwrap()
+            .num();
+
+        // detach a directory, add a dangling entry and mess up the counts
+        let mut root = fs.root_inode().unwrap();
+        assert_eq!(root.remove_entry(b"orphan").unwrap(), orphan);
+        assert!(root.add_entry(b"ghost", unused, 0).is_ok());
+        {
+            let mut inner = fs.inner();
+            inner.superblock.inner.free_blocks_count += 3;
+            inner.block_groups.inner[0].free_inodes_count -= 1;
+        }
+
+        let result = repair(&fs).unwrap();
+        assert!(!result.found.is_clean());
+        assert!(
+            result.remaining.is_clean(),
+            "{:?}",
+            result.remaining.problems
+        );
+
+        match fs.find_inode(b"/ghost") {
+            Err(Error::NotFound { .. }) => (),
+            result => panic!("dangling entry not cleared: {:?}", result),
+        }
+        let path = format!("/lost+found/#{}/sub", orphan);
+        assert!(fs.find_inode(path.as_bytes()).unwrap().is_dir());
+    }
+
+    #[test]
+    fn dangling_dot_entries() {
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+        let mut fs = Synced::<Ext2<Size512, _>>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap();
+        assert!(fs.create_dir(b"/dir", &DirOptions::new()).is_ok());
+        let mut dir = fs.find_inode(b"/dir").unwrap();
+        let num = dir.num();
+        let first_inode = fs.inner().first_inode();
+        let unused = fs.inodes()
+            .map(|inode| inode.unwrap())
+            .find(|inode| !inode.in_use() && inode.num() >= first_inode)
+            .unwrap()
+            .num();
+        assert_eq!(dir.replace_entry(b".", unused).unwrap(), num);
+        assert_eq!(dir.replace_entry(b"..", unused).unwrap(), 2);
+
+        let result = repair(&fs).unwrap();
+        assert!(!result.found.is_clean());
+        assert!(
+            result.remaining.is_clean(),
+            "{:?}",
+            result.remaining.problems
+        );
+
+        let dir = fs.find_inode(b"/dir").unwrap();
+        assert_eq!(dir.find_entry(b".").unwrap().unwrap().inode, num as usize);
+        assert_eq!(dir.find_entry(b"..").unwrap().unwrap().inode, 2);
+    }
+}
@@ -0,0 +1,675 @@
+//! Offline resizing of filesystems, similar to `resize2fs`.
+//!
+//! Growing adds block groups after the last one, once the volume itself was
+//! grown. If the descriptor table needs more blocks, whatever follows it in
+//! groups holding a backup is moved out of its way. Shrinking moves inodes
+//! and blocks out of the trailing groups before dropping them, after which the
+//! volume can be truncated.
+//!
+//! The reserved descriptor blocks of `FeaturesOptional::SELF_RESIZE` are not
+//! maintained. Such filesystems are only resized if
+//! `ResizeOptions::drop_resize_inode` is set, which releases those blocks
+//! along with the resize inode and clears the feature first.
+
+use core::mem;
+
+use alloc::{BTreeMap, BTreeSet, String};
+
+use error::Error;
+use sector::{Address, SectorSize};
+use volume::{Volume, VolumeCommit, WriteClass};
+use sys::superblock::{FeaturesOptional, Superblock};
+
+use super::Ext2;
+use super::allocator::Bitmap;
+use super::check::{self, Checker, RESIZE_INODE};
+use super::dir;
+use super::mkfs::{self, Group};
+use super::repair;
+use super::sync::Synced;
+
+/// Options of `resize`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ResizeOptions {
+    pub(crate) drop_resize_inode: bool,
+}
+
+impl ResizeOptions {
+    pub fn new() -> ResizeOptions {
+        ResizeOptions {
+            drop_resize_inode: false,
+        }
+    }
+
+    /// Allow resizing a filesystem with `FeaturesOptional::SELF_RESIZE` by
+    /// clearing the feature and its resize inode, which frees the blocks
+    /// reserved for growing the descriptor table. The filesystem can't grow
+    /// online afterwards. Defaults to `false`.
+    pub fn drop_resize_inode(
+        &mut self,
+        drop_resize_inode: bool,
+    ) -> &mut ResizeOptions {
+        self.drop_resize_inode = drop_resize_inode;
+        self
+    }
+}
+
+impl Default for ResizeOptions {
+    fn default() -> ResizeOptions {
+        ResizeOptions::new()
+    }
+}
+
+/// Resizes the filesystem to `blocks_count` blocks. The filesystem must be
+/// mounted read-write, pass `check` and not be used by anything else
+/// meanwhile. To grow it, the volume has to be grown first; after shrinking
+/// it, the volume can be truncated to `blocks_count` blocks. Resizing to the
+/// current size changes nothing.
+///
+/// Filesystems with `FeaturesOptional::SELF_RESIZE` are rejected with
+/// `Error::BadResize`, unless `options` allow dropping the feature (see
+/// `ResizeOptions::drop_resize_inode`).
+pub fn resize<S: SectorSize, V: Volume<u8, S>>(
+    fs: &Synced<Ext2<S, V>>,
+    blocks_count: u32,
+    options: &ResizeOptions,
+) -> Result<(), Error> {
+    fs.inner().check_writable()?;
+    let old = fs.inner().superblock.inner;
+    if blocks_count == old.blocks_count {
+        return Ok(());
+    }
+
+    if !check::check(fs)?.is_clean() {
+        return Err(bad_resize("the filesystem has errors, repair it first"));
+    }
+
+    let features_opt = old.features_opt;
+    if features_opt.contains(FeaturesOptional::SELF_RESIZE) {
+        if !options.drop_resize_inode {
+            return Err(bad_resize(
+                "the reserved descriptor blocks of the resize inode aren't \
+                 maintained",
+            ));
+        }
+        drop_resize_inode(fs)?;
+    }
+
+    if blocks_count > old.blocks_count {
+        grow(fs, blocks_count)?;
+    } else {
+        shrink(fs, blocks_count)?;
+    }
+
+    {
+        let mut inner = fs.inner();
+        let superblock = &mut inner.superblock.inner;
+        superblock.r_blocks_count =
+            (old.r_blocks_count as u64 * blocks_count as u64
+                / old.blocks_count as u64) as u32;
+    }
+
+    // free counts are recounted once instead of tracking every move
+    let mut checker = Checker::new(fs);
+    checker.run()?;
+    repair::rebuild_bitmaps(&checker)?;
+
+    let mut inner = fs.inner();
+    inner.write_back()
+}
+
+/// Number of block groups of a filesystem of `blocks_count` blocks
+fn group_count(superblock: &Superblock, blocks_count: u32) -> u32 {
+    let blocks_per_group = superblock.blocks_per_group;
+    (blocks_count - superblock.first_data_block + blocks_per_group - 1)
+        / blocks_per_group
+}
+
+/// Clears the resize inode and the `SELF_RESIZE` feature, which releases
+/// the reserved descriptor blocks
+fn drop_resize_inode<S: SectorSize, V: Volume<u8, S>>(
+    fs: &Synced<Ext2<S, V>>,
+) -> Result<(), Error> {
+    {
+        let mut inner = fs.inner();
+        let superblock = &mut inner.superblock.inner;
+        superblock
+            .features_opt
+            .remove(FeaturesOptional::SELF_RESIZE);
+        superblock.reserved_gdt_blocks = 0;
+    }
+    fs.inner().commit_superblock()?;
+
+    let mut inode = fs.try_inode_nth(RESIZE_INODE as usize)?;
+    *inode.raw_mut() = unsafe { mem::zeroed() };
+    inode.commit()?;
+
+    let mut checker = Checker::new(fs);
+    checker.run()?;
+    repair::rebuild_bitmaps(&checker)
+}
+
+fn grow<S: SectorSize, V: Volume<u8, S>>(
+    fs: &Synced<Ext2<S, V>>,
+    blocks_count: u32,
+) -> Result<(), Error> {
+    let (old, volume_size) = {
+        let inner = fs.inner();
+        let size = inner.volume.size().try_len().map(|len| len.into_index());
+        (inner.superblock.inner, size)
+    };
+    let block_size = old.block_size();
+    match volume_size {
+        Some(size) if size / block_size as u64 >= blocks_count as u64 => (),
+        _ => return Err(bad_resize("the volume is smaller than the new size")),
+    }
+
+    let old_groups = group_count(&old, old.blocks_count);
+    let new_groups = group_count(&old, blocks_count);
+    let inodes_count = old.inodes_per_group as u64 * new_groups as u64;
+    if inodes_count > u32::max_value() as u64 {
+        return Err(bad_resize("too many inodes"));
+    }
+
+    let mut superblock = old;
+    superblock.blocks_count = blocks_count;
+    superblock.inodes_count = inodes_count as u32;
+    let old_descr = mkfs::descriptor_blocks(old_groups, block_size);
+    let new_descr = mkfs::descriptor_blocks(new_groups, block_size);
+
+    let last = Group::new(&superblock, new_groups - 1, new_descr);
+    if last.data >= last.first_block + last.block_count {
+        return Err(bad_resize("the last block group would be too small"));
+    }
+
+    if new_descr > old_descr {
+        make_room(fs, old_groups, old_descr, new_descr)?;
+    }
+
+    let mut inner = fs.inner();
+
+    // the last group grows into the new space, whose bits were padding
+    let last_old = old_groups as usize - 1;
+    let bitmap = inner.block_bitmap(last_old)?;
+    let old_len = bitmap.len();
+    inner.superblock.inner.blocks_count = blocks_count;
+    inner.superblock.inner.inodes_count = inodes_count as u32;
+    let new_len = inner.group_block_count(last_old);
+    let mut bitmap =
+        Bitmap::new(last_old, bitmap.block(), new_len, bitmap.into_inner())?;
+    for bit in old_len..new_len {
+        bitmap.clear(bit)?;
+    }
+    inner.commit_bitmap(&bitmap)?;
+
+    for group in old_groups..new_groups {
+        let layout = Group::new(&superblock, group, new_descr);
+        let used_blocks = layout.data - layout.first_block;
+        let descr = mkfs::init_group(
+            &mut inner.volume,
+            &superblock,
+            &layout,
+            used_blocks,
+            0,
+        )?;
+        inner.block_groups.inner.push(descr);
+    }
+
+    Ok(())
+}
+
+/// Frees the blocks a descriptor table of `new_descr` blocks takes beyond
+/// one of `old_descr` blocks in every group holding a backup, by moving
+/// bitmaps, inode tables and data blocks elsewhere
+fn make_room<S: SectorSize, V: Volume<u8, S>>(
+    fs: &Synced<Ext2<S, V>>,
+    group_count: u32,
+    old_descr: u32,
+    new_descr: u32,
+) -> Result<(), Error> {
+    let mut room = BTreeSet::new();
+    let mut inner = fs.inner();
+
+    // claim the blocks first, so that nothing is moved into them
+    for group in 0..group_count as usize {
+        if !inner.superblock.inner.has_backup(group as u32) {
+            continue;
+        }
+
+        let first_block = inner.group_first_block(group);
+        let mut bitmap = inner.block_bitmap(group)?;
+        let start = 1 + old_descr as usize;
+        let end = 1 + new_descr as usize;
+        if end > bitmap.len() {
+            return Err(bad_resize("a block group is too small"));
+        }
+        for bit in start..end {
+            room.insert(first_block + bit as u32);
+            bitmap.set(bit)?;
+        }
+        inner.commit_bitmap(&bitmap)?;
+    }
+
+    let table_blocks = mkfs::inode_table_blocks(&inner.superblock.inner);
+    for group in 0..group_count as usize {
+        let first_block = inner.group_first_block(group);
+        let descr = inner.block_groups.inner[group];
+
+        if room.contains(&descr.block_usage_addr) {
+            let block = inner.allocate_block(first_block)?;
+            let data = inner.read_block(descr.block_usage_addr)?;
+            inner.write_block(block, data)?;
+            inner.block_groups.inner[group].block_usage_addr = block;
+        }
+        if room.contains(&descr.inode_usage_addr) {
+            let block = inner.allocate_block(first_block)?;
+            let data = inner.read_block(descr.inode_usage_addr)?;
+            inner.write_block(block, data)?;
+            inner.block_groups.inner[group].inode_usage_addr = block;
+        }
+
+        let table = descr.inode_table_block;
+        if (table..table + table_blocks).any(|block| room.contains(&block)) {
+            // the inode table has to stay contiguous
+            let mut bitmap = inner.block_bitmap(group)?;
+            let start = match bitmap.find_free_run(table_blocks as usize) {
+                Some(start) => start,
+                None => {
+                    return Err(Error::NoSpaceLeft {
+                        group_count: group_count as usize,
+                    })
+                }
+            };
+            for bit in start..start + table_blocks as usize {
+                bitmap.set(bit)?;
+            }
+            inner.commit_bitmap(&bitmap)?;
+
+            let new_table = first_block + start as u32;
+            for index in 0..table_blocks {
+                let data = inner.read_block(table + index)?;
+                let block = new_table + index;
+                inner.write_block_as(block, data, WriteClass::Inode)?;
+            }
+            inner.block_groups.inner[group].inode_table_block = new_table;
+        }
+
+        inner.commit_block_group(group)?;
+    }
+
+    drop(inner);
+    relocate_blocks(fs, |block| room.contains(&block))
+}
+
+fn shrink<S: SectorSize, V: Volume<u8, S>>(
+    fs: &Synced<Ext2<S, V>>,
+    blocks_count: u32,
+) -> Result<(), Error> {
+    let (old, first_inode) = {
+        let inner = fs.inner();
+        (inner.superblock.inner, inner.first_inode())
+    };
+    if blocks_count <= old.first_data_block {
+        return Err(bad_resize("the new size is too small"));
+    }
+
+    let old_groups = group_count(&old, old.blocks_count);
+    let new_groups = group_count(&old, blocks_count);
+    let inodes_count = old.inodes_per_group * new_groups;
+    let table_blocks = mkfs::inode_table_blocks(&old);
+
+    {
+        let inner = fs.inner();
+        for group in 0..new_groups as usize {
+            let descr = inner.block_groups.inner[group];
+            if descr.block_usage_addr >= blocks_count
+                || descr.inode_usage_addr >= blocks_count
+                || descr.inode_table_block + table_blocks > blocks_count
+            {
+                return Err(bad_resize(
+                    "the metadata of a remaining group is past the new end",
+                ));
+            }
+        }
+    }
+
+    {
+        let mut checker = Checker::new(fs);
+        checker.run()?;
+
+        let needed_blocks = (blocks_count..old.blocks_count)
+            .filter(|&block| {
+                checker.is_used(block) && !checker.is_metadata(block)
+            })
+            .count();
+        let free_blocks = (old.first_data_block..blocks_count)
+            .filter(|&block| !checker.is_used(block))
+            .count();
+        if needed_blocks > free_blocks {
+            return Err(bad_resize("not enough free blocks"));
+        }
+
+        let inodes = &checker.inodes;
+        let needed_inodes = inodes[inodes_count as usize + 1..]
+            .iter()
+            .filter(|info| info.in_use)
+            .count();
+        let free_inodes = inodes
+            [first_inode as usize..inodes_count as usize + 1]
+            .iter()
+            .filter(|info| !info.in_use)
+            .count();
+        if needed_inodes > free_inodes {
+            return Err(bad_resize("not enough free inodes"));
+        }
+    }
+
+    // keep allocations out of the dropped groups and the dropped part of the
+    // last remaining group
+    {
+        let mut inner = fs.inner();
+        for group in new_groups as usize..old_groups as usize {
+            inner.block_groups.inner[group].free_blocks_count = 0;
+            inner.block_groups.inner[group].free_inodes_count = 0;
+        }
+
+        let last = new_groups as usize - 1;
+        let first_block = inner.group_first_block(last);
+        let mut bitmap = inner.block_bitmap(last)?;
+        for bit in (blocks_count - first_block) as usize..bitmap.len() {
+            bitmap.set(bit)?;
+        }
+        inner.commit_bitmap(&bitmap)?;
+    }
+
+    relocate_inodes(fs, inodes_count)?;
+    relocate_blocks(fs, |block| block >= blocks_count)?;
+
+    let mut inner = fs.inner();
+    inner.block_groups.inner.truncate(new_groups as usize);
+    inner.superblock.inner.blocks_count = blocks_count;
+    inner.superblock.inner.inodes_count = inodes_count;
+    Ok(())
+}
+
+/// Moves every inode in use numbered above `inodes_count` to a free inode
+/// below, and points the directory entries linking it at the new inode
+fn relocate_inodes<S: SectorSize, V: Volume<u8, S>>(
+    fs: &Synced<Ext2<S, V>>,
+    inodes_count: u32,
+) -> Result<(), Error> {
+    let mut moved = BTreeMap::new();
+
+    for inode in fs.inodes_nth(inodes_count as usize + 1) {
+        let inode = inode?;
+        if !inode.in_use() {
+            continue;
+        }
+
+        let mut inner = fs.inner();
+        let num = inner.allocate_inode(0, inode.is_dir())?;
+        let start = inner.inode_address(inode.num());
+        let end = start + Address::from(inner.inode_size());
+        let data = match inner.volume.slice(start..end) {
+            Ok(slice) => slice.to_vec(),
+            Err(err) => return Err(err.into()),
+        };
+        let addr = inner.inode_address(num);
+        inner.commit_as(VolumeCommit::new(data, addr), WriteClass::Inode)?;
+        // the old copy must not own the blocks anymore
+        let zeroed = vec![0; inner.inode_size()];
+        inner.commit_as(VolumeCommit::new(zeroed, start), WriteClass::Inode)?;
+        moved.insert(inode.num(), num);
+    }
+
+    if moved.is_empty() {
+        return Ok(());
+    }
+
+    // this includes the `.` and `..` entries of moved directories
+    for dir in fs.inodes() {
+        let dir = dir?;
+        if dir.num() > inodes_count {
+            break;
+        }
+        if !dir.in_use() || !dir.is_dir() {
+            continue;
+        }
+
+        for block in dir.blocks() {
+            let (mut data, addr) = match block? {
+                Some(block) => block,
+                None => continue,
+            };
+            let mut changed = false;
+            let mut offset = 0;
+            while let Some(record) = dir::read_record(&data, offset) {
+                if let Some(&num) = moved.get(&record.inode) {
+                    dir::write_u32(&mut data, offset, num);
+                    changed = true;
+                }
+                offset += record.rec_len;
+            }
+
+            if changed {
+                let commit = VolumeCommit::new(data, addr);
+                fs.inner().commit_as(commit, WriteClass::Directory)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves every block of an inode for which `evacuate` returns `true` to a
+/// newly allocated one, including indirect and extended attribute blocks
+fn relocate_blocks<S, V, F>(
+    fs: &Synced<Ext2<S, V>>,
+    evacuate: F,
+) -> Result<(), Error>
+where
+    S: SectorSize,
+    V: Volume<u8, S>,
+    F: Fn(u32) -> bool,
+{
+    let (block_size, first_inode) = {
+        let inner = fs.inner();
+        (inner.block_size(), inner.first_inode())
+    };
+    // extended attribute blocks are shared between inodes
+    let mut moved = BTreeMap::new();
+
+    for inode in fs.inodes() {
+        let mut inode = inode?;
+        let num = inode.num();
+        let mut raw = *inode.raw();
+        let reserved = num < first_inode && num != check::ROOT_INODE;
+        if raw.hard_links == 0 && !reserved
+            || !check::has_blocks(&raw, block_size)
+        {
+            continue;
+        }
+
+        let changed = {
+            let mut inner = fs.inner();
+            let inner = &mut *inner;
+            let mut changed = false;
+
+            let mut pointers = raw.direct_pointer;
+            for pointer in pointers.iter_mut() {
+                let block = *pointer;
+                *pointer = relocate(inner, block, 0, &evacuate, &mut moved)?;
+                changed |= *pointer != block;
+            }
+            raw.direct_pointer = pointers;
+
+            let mut pointers = [
+                raw.indirect_pointer,
+                raw.doubly_indirect,
+                raw.triply_indirect,
+                raw.ext_attribute_block,
+            ];
+            for (depth, pointer) in [1, 2, 3, 0].iter().zip(pointers.iter_mut())
+            {
+                let block = *pointer;
+                *pointer =
+                    relocate(inner, block, *depth, &evacuate, &mut moved)?;
+                changed |= *pointer != block;
+            }
+            raw.indirect_pointer = pointers[0];
+            raw.doubly_indirect = pointers[1];
+            raw.triply_indirect = pointers[2];
+            raw.ext_attribute_block = pointers[3];
+            changed
+        };
+
+        if changed {
+            *inode.raw_mut() = raw;
+            inode.commit()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves `block` if `evacuate` returns `true` for it and, if it's an
+/// indirect block with `depth` levels below it, the blocks it points to.
+/// Returns the new number of `block`.
+fn relocate<S, V, F>(
+    fs: &mut Ext2<S, V>,
+    block: u32,
+    depth: u32,
+    evacuate: &F,
+    moved: &mut BTreeMap<u32, u32>,
+) -> Result<u32, Error>
+where
+    S: SectorSize,
+    V: Volume<u8, S>,
+    F: Fn(u32) -> bool,
+{
+    if block == 0 {
+        return Ok(0);
+    }
+    if let Some(&new) = moved.get(&block) {
+        return Ok(new);
+    }
+
+    let new = if evacuate(block) {
+        let new = fs.allocate_block(block)?;
+        let data = fs.read_block(block)?;
+        fs.write_block(new, data)?;
+        moved.insert(block, new);
+        new
+    } else {
+        block
+    };
+
+    if depth > 0 {
+        let mut table = fs.read_block(new)?;
+        let mut changed = false;
+        for index in 0..table.len() / 4 {
+            let pointer = dir::read_u32(&table, index * 4);
+            let relocated = relocate(fs, pointer, depth - 1, evacuate, moved)?;
+            if relocated != pointer {
+                dir::write_u32(&mut table, index * 4, relocated);
+                changed = true;
+            }
+        }
+        if changed {
+            fs.write_block(new, table)?;
+        }
+    }
+
+    Ok(new)
+}
+
+fn bad_resize(reason: &str) -> Error {
+    Error::BadResize {
+        reason: String::from(reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use genfs::{DirOptions, Fs};
+
+    use fs::check::check;
+    use fs::mkfs::FormatOptions;
+    use fs::mkfs::tests::{formatted, mount_rw};
+
+    use super::{resize, ResizeOptions};
+
+    #[test]
+    fn grow_shrink() {
+        let mut volume = formatted(2 * 1024 * 1024, &FormatOptions::new());
+        volume.resize(20 * 1024 * 1024, 0);
+
+        let mut fs = mount_rw(volume);
+        assert!(resize(&fs, 20 * 1024, &ResizeOptions::new()).is_ok());
+        assert_eq!(fs.inner().block_groups.inner.len(), 3);
+        let report = check(&fs).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        // directories are spread out over the new groups
+        let target = vec![b'x'; 200];
+        for name in [&b"/a"[..], b"/b", b"/c"].iter() {
+            assert!(fs.create_dir(name, &DirOptions::new()).is_ok());
+            let link = [*name, b"/link"].concat();
+            assert!(fs.symlink(&target, &link).is_ok());
+        }
+        let num = fs.find_inode(b"/c").unwrap().num();
+        assert!(num > fs.inner().inodes_count() as u32);
+
+        assert!(resize(&fs, 3 * 1024, &ResizeOptions::new()).is_ok());
+        assert_eq!(fs.inner().block_groups.inner.len(), 1);
+        assert_eq!(fs.inner().total_block_count(), 3 * 1024);
+        let report = check(&fs).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        for name in [&b"/a"[..], b"/b", b"/c"].iter() {
+            let link = [*name, b"/link"].concat();
+            let inode = fs.find_inode_nofollow(&link).unwrap();
+            assert_eq!(inode.read_link().unwrap(), target);
+        }
+
+        // the inode table of the first group reaches past the new end
+        assert!(resize(&fs, 30, &ResizeOptions::new()).is_err());
+        assert!(fs.unmount().is_ok());
+    }
+
+    #[test]
+    fn self_resize() {
+        use sys::superblock::FeaturesOptional;
+
+        let mut volume = formatted(2 * 1024 * 1024, &FormatOptions::new());
+        volume.resize(20 * 1024 * 1024, 0);
+        let fs = mount_rw(volume);
+        {
+            let mut inner = fs.inner();
+            let superblock = &mut inner.superblock.inner;
+            let mut features = superblock.features_opt;
+            features.insert(FeaturesOptional::SELF_RESIZE);
+            superblock.features_opt = features;
+        }
+        let has_feature = || {
+            let features = fs.inner().superblock.inner.features_opt;
+            features.contains(FeaturesOptional::SELF_RESIZE)
+        };
+
+        // nothing to do, so nothing is dropped
+        assert!(resize(&fs, 2 * 1024, &ResizeOptions::new()).is_ok());
+        assert!(has_feature());
+
+        assert!(resize(&fs, 20 * 1024, &ResizeOptions::new()).is_err());
+        assert!(has_feature());
+        assert_eq!(fs.inner().total_block_count(), 2 * 1024);
+
+        let mut options = ResizeOptions::new();
+        options.drop_resize_inode(true);
+        assert!(resize(&fs, 20 * 1024, &options).is_ok());
+        assert!(!has_feature());
+        assert_eq!(fs.inner().total_block_count(), 20 * 1024);
+        let report = check(&fs).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+}
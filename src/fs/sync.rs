@@ -1,4 +1,6 @@
+use core::mem;
 use core::fmt::{self, Debug};
+use core::cell::Cell;
 use core::nonzero::NonZero;
 use core::iter::Iterator;
 
@@ -10,10 +12,25 @@ use genfs::*;
 
 use error::Error;
 use sector::{Address, SectorSize};
-use volume::Volume;
-use sys::inode::Inode as RawInode;
+use volume::{Volume, VolumeCommit, WriteClass};
+use sys::superblock::{FeaturesOptional, FeaturesRequired, FsFlags};
+use sys::superblock::FeaturesROnly;
+use sys::inode::{Flags, Inode as RawInode, InodeExtra, TypePerm};
+use sys::inode::INLINE_DATA_SIZE;
 
 use super::Ext2;
+use super::mount::MountOptions;
+use super::dir;
+use super::htree::{self, HashVersion};
+use super::metadata::{FileType, Metadata};
+use super::xattr::{self, Xattr};
+
+/// Permissions of newly created directories: `rwxr-xr-x`
+const DIR_PERMISSIONS: u16 = 0o755;
+/// Permissions of newly created symbolic links: `rwxrwxrwx`
+const LINK_PERMISSIONS: u16 = 0o777;
+/// Maximum number of symbolic links followed while resolving a single path
+const MAX_SYMLINKS: usize = 40;
 
 pub struct Synced<T> {
     inner: Arc<Mutex<T>>,
@@ -44,12 +61,49 @@ impl<S: SectorSize, V: Volume<u8, S>> Synced<Ext2<S, V>> {
         Ext2::new(volume).map(|inner| Synced::with_inner(inner))
     }
 
-    pub fn root_inode(&self) -> Inode<S, V> {
-        self.inode_nth(2).unwrap()
+    pub fn mount(
+        volume: V,
+        options: &MountOptions,
+    ) -> Result<Synced<Ext2<S, V>>, Error> {
+        Ext2::mount(volume, options).map(|inner| Synced::with_inner(inner))
+    }
+
+    pub fn unmount(&self) -> Result<(), Error> {
+        self.inner().unmount()
+    }
+
+    pub fn root_inode(&self) -> Result<Inode<S, V>, Error> {
+        self.try_inode_nth(2)
     }
 
     pub fn inode_nth(&self, index: usize) -> Option<Inode<S, V>> {
-        self.inodes_nth(index).next()
+        self.try_inode_nth(index).ok()
+    }
+
+    /// Like `inode_nth`, but tells apart an inode that doesn't exist
+    /// (`Error::InodeNotFound`) from one that couldn't be read from the
+    /// volume
+    pub fn try_inode_nth(&self, index: usize) -> Result<Inode<S, V>, Error> {
+        let fs = self.inner();
+        if index == 0 || index > fs.total_inodes_count() {
+            return Err(Error::InodeNotFound {
+                inode: index as u32,
+            });
+        }
+
+        let inode_size = fs.inode_size();
+        let offset = fs.inode_address(index as u32);
+        let (raw, offset) =
+            unsafe { RawInode::find_inode(&fs.volume, offset, inode_size)? };
+        let extra =
+            unsafe { InodeExtra::find_extra(&fs.volume, offset, inode_size)? };
+        Ok(Inode::new(
+            self.clone(),
+            raw,
+            extra.map(|(extra, _)| extra),
+            offset,
+            index as u32,
+        ))
     }
 
     pub fn inodes(&self) -> Inodes<S, V> {
@@ -58,13 +112,9 @@ impl<S: SectorSize, V: Volume<u8, S>> Synced<Ext2<S, V>> {
 
     pub fn inodes_nth(&self, index: usize) -> Inodes<S, V> {
         assert!(index > 0, "inodes are 1-indexed");
-        let inner = self.inner();
         Inodes {
             fs: self.clone(),
-            log_block_size: inner.log_block_size(),
-            inode_size: inner.inode_size(),
-            inodes_per_group: inner.inodes_count(),
-            inodes_count: inner.total_inodes_count(),
+            inodes_count: self.inner().total_inodes_count(),
             index,
         }
     }
@@ -76,98 +126,411 @@ impl<S: SectorSize, V: Volume<u8, S>> Synced<Ext2<S, V>> {
     pub fn log_sector_size(&self) -> u32 {
         S::LOG_SIZE
     }
-}
 
-impl<S: SectorSize, V: Volume<u8, S>> Fs for Synced<Ext2<S, V>> {
-    type Path = [u8];
-    type PathOwned = Vec<u8>;
-    type File = Inode<S, V>;
-    type Dir = Directory<S, V>;
-    type DirEntry = DirectoryEntry;
-    type Metadata = (); // TODO
-    type Permissions = (); // TODO
-    type Error = Error;
+    /// Finds the inode at `abs_path`, following every symbolic link on the
+    /// way
+    pub fn find_inode(&self, abs_path: &[u8]) -> Result<Inode<S, V>, Error> {
+        self.resolve(abs_path, true)
+    }
 
-    fn open(
+    /// Finds the inode at `abs_path` without following the final component
+    /// if it's a symbolic link
+    pub fn find_inode_nofollow(
         &self,
-        abs_path: &Self::Path,
-        _options: &OpenOptions<Self::Permissions>,
-    ) -> Result<Self::File, Self::Error> {
-        fn inner<'a, S, V, I>(
-            fs: &Synced<Ext2<S, V>>,
-            inode: Inode<S, V>,
-            mut path: I,
-            abs_path: &[u8],
-        ) -> Result<Inode<S, V>, Error>
-        where
-            S: SectorSize,
-            V: Volume<u8, S>,
-            I: Iterator<Item = &'a [u8]>,
-        {
-            let name = match path.next() {
-                Some(name) => name,
-                None => return Ok(inode),
-            };
+        abs_path: &[u8],
+    ) -> Result<Inode<S, V>, Error> {
+        self.resolve(abs_path, false)
+    }
+
+    fn resolve(
+        &self,
+        abs_path: &[u8],
+        follow: bool,
+    ) -> Result<Inode<S, V>, Error> {
+        fn push_components(stack: &mut Vec<Vec<u8>>, path: &[u8]) {
+            stack.extend(
+                path.split(|byte| *byte == b'/')
+                    .filter(|name| name.len() > 0)
+                    .rev()
+                    .map(|name| name.to_vec()),
+            );
+        }
 
-            let mut dir =
-                inode.directory().ok_or_else(|| Error::NotADirectory {
+        if abs_path.len() == 0 || abs_path[0] != b'/' {
+            return Err(Error::NotAbsolute {
+                name: String::from_utf8_lossy(abs_path).into_owned(),
+            });
+        }
+
+        // components left to resolve, in reverse order
+        let mut path = Vec::new();
+        push_components(&mut path, abs_path);
+
+        let mut inode = self.root_inode()?;
+        let mut links = 0;
+
+        while let Some(name) = path.pop() {
+            if !inode.is_dir() {
+                return Err(Error::NotADirectory {
                     inode: inode.num,
                     name: String::from_utf8_lossy(abs_path).into_owned(),
-                })?;
+                });
+            }
 
-            let entry = dir.find(|entry| {
-                entry.is_err() || entry.as_ref().unwrap().name == name
-            }).ok_or_else(|| Error::NotFound {
-                name: String::from_utf8_lossy(abs_path).into_owned(),
-            })??;
+            let entry =
+                inode.find_entry(&name)?.ok_or_else(|| Error::NotFound {
+                    name: String::from_utf8_lossy(abs_path).into_owned(),
+                })?;
+            let next = self.try_inode_nth(entry.inode)?;
+
+            if next.is_symlink() && (follow || path.len() > 0) {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(Error::SymlinkLoop {
+                        name: String::from_utf8_lossy(abs_path).into_owned(),
+                    });
+                }
 
-            let inode = fs.inode_nth(entry.inode)
-                .ok_or(Error::InodeNotFound { inode: inode.num })?;
+                // relative targets are resolved from the directory
+                // containing the link
+                let target = next.read_link()?;
+                if target.first() == Some(&b'/') {
+                    inode = self.root_inode()?;
+                }
+                push_components(&mut path, &target);
+                continue;
+            }
 
-            inner(fs, inode, path, abs_path)
+            inode = next;
         }
 
-        if abs_path.len() == 0 || abs_path[0] != b'/' {
-            return Err(Error::NotAbsolute {
+        Ok(inode)
+    }
+
+    fn find_directory(&self, abs_path: &[u8]) -> Result<Inode<S, V>, Error> {
+        self.find_directory_as(abs_path, true)
+    }
+
+    /// Like `find_directory`, but a final symbolic link isn't followed, so
+    /// that removing a link to a directory fails instead of removing the
+    /// directory
+    fn find_directory_nofollow(
+        &self,
+        abs_path: &[u8],
+    ) -> Result<Inode<S, V>, Error> {
+        self.find_directory_as(abs_path, false)
+    }
+
+    fn find_directory_as(
+        &self,
+        abs_path: &[u8],
+        follow: bool,
+    ) -> Result<Inode<S, V>, Error> {
+        let inode = self.resolve(abs_path, follow)?;
+        if inode.is_dir() {
+            Ok(inode)
+        } else {
+            Err(Error::NotADirectory {
+                inode: inode.num,
                 name: String::from_utf8_lossy(abs_path).into_owned(),
-            });
+            })
         }
+    }
 
-        if abs_path == b"/" {
-            return Ok(self.root_inode());
+    /// Whether the directory `num` is `dir` itself or one of its ancestors,
+    /// found by following `..` entries up to the root
+    fn is_ancestor(&self, num: u32, dir: &Inode<S, V>) -> Result<bool, Error> {
+        let inodes_count = self.inner().total_inodes_count();
+        let mut dir = dir.clone();
+
+        // no path is longer than the number of inodes, unless the `..`
+        // entries of a corrupted filesystem form a loop
+        for _ in 0..inodes_count {
+            if dir.num == num {
+                return Ok(true);
+            }
+            let parent = match dir.find_entry(b"..")? {
+                Some(entry) => entry.inode,
+                None => return Ok(false),
+            };
+            // the root is its own parent
+            if parent == dir.num as usize {
+                return Ok(false);
+            }
+            dir = self.try_inode_nth(parent)?;
         }
 
-        let mut path = abs_path.split(|byte| *byte == b'/');
-        path.next();
-        let root = self.root_inode();
+        Err(Error::Other(format!(
+            "directory inode no. {} has no path to the root",
+            dir.num
+        )))
+    }
+
+    /// Allocates a new inode of type `type_perm` near its parent directory
+    /// and writes it out with no links and no data
+    fn create_inode(
+        &self,
+        parent: &Inode<S, V>,
+        type_perm: TypePerm,
+    ) -> Result<Inode<S, V>, Error> {
+        let mut fs = self.inner();
+        let group = fs.block_group_of_inode(parent.num);
+        let directory = FileType::from_type_perm(type_perm).is_dir();
+        let num = fs.allocate_inode(group, directory)?;
+        let addr = fs.inode_address(num);
+        let time = fs.timestamp();
+
+        let mut inner: RawInode = unsafe { mem::zeroed() };
+        inner.type_perm = type_perm;
+        inner.atime = time;
+        inner.ctime = time;
+        inner.mtime = time;
+
+        // clear the whole inode, including a stale extended part
+        let inode_size = fs.inode_size();
+        let zeroed = VolumeCommit::new(vec![0; inode_size], addr);
+        fs.commit_as(zeroed, WriteClass::Inode)?;
+        fs.commit_inode(addr, &inner)?;
+
+        let base_size = mem::size_of::<RawInode>();
+        let extra = if inode_size > base_size {
+            let mut extra: InodeExtra = unsafe { mem::zeroed() };
+            let extra_isize = mem::size_of::<InodeExtra>();
+            extra.extra_isize = extra_isize.min(inode_size - base_size) as u16;
+            // times past 2038 need the epoch bits of the extra fields
+            let (_, time_extra) = InodeExtra::encode_time(time as i64, 0);
+            extra.ctime_extra = time_extra;
+            extra.mtime_extra = time_extra;
+            extra.atime_extra = time_extra;
+            extra.crtime = time;
+            extra.crtime_extra = time_extra;
+            fs.commit_inode_extra(addr, &extra)?;
+            Some(extra)
+        } else {
+            None
+        };
 
-        inner(self, root, path, abs_path)
+        Ok(Inode::new(self.clone(), inner, extra, addr, num))
     }
 
-    fn remove_file(&mut self, _path: &Self::Path) -> Result<(), Self::Error> {
-        unimplemented!()
+    /// Writes the initial data of an inode fresh from `create_inode`. If
+    /// that fails, the inode is released again, as nothing links to it yet.
+    fn fill_inode(
+        &self,
+        mut inode: Inode<S, V>,
+        data: &[u8],
+    ) -> Result<Inode<S, V>, Error> {
+        let err = match inode.write_at(0, data) {
+            Ok(len) if len == data.len() => return Ok(inode),
+            Ok(_) => Error::NoSpaceLeft {
+                group_count: self.inner().block_groups.inner.len(),
+            },
+            Err(err) => err,
+        };
+
+        inode.inner.hard_links = 1;
+        self.unlink(inode)?;
+        Err(err)
+    }
+
+    /// Type indicator for directory entries pointing to inodes of type
+    /// `type_perm`. It's always 0 unless the filesystem stores types in
+    /// directory entries.
+    pub(crate) fn entry_type(&self, type_perm: TypePerm) -> u8 {
+        let fs = self.inner();
+        let features = fs.superblock.inner.features_req;
+        if features.contains(FeaturesRequired::REQ_DIRECTORY_TYPE) {
+            dir::entry_type(type_perm)
+        } else {
+            0
+        }
+    }
+
+    /// Drops one hard link to `inode`, releasing its blocks and the inode
+    /// itself once no links are left
+    fn unlink(&self, mut inode: Inode<S, V>) -> Result<(), Error> {
+        inode.inner.hard_links = inode.inner.hard_links.saturating_sub(1);
+        if inode.inner.hard_links > 0 {
+            return inode.commit();
+        }
+
+        let directory = inode.is_dir();
+        inode.free_blocks()?;
+        inode.inner.dtime = {
+            let fs = self.inner();
+            fs.timestamp()
+        };
+        inode.commit()?;
+
+        let mut fs = self.inner();
+        fs.free_inode(inode.num, directory)
+    }
+}
+
+/// Splits an absolute path into its parent directory and its last
+/// component, ignoring trailing slashes
+fn split_path(abs_path: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    if abs_path.len() == 0 || abs_path[0] != b'/' {
+        return Err(Error::NotAbsolute {
+            name: String::from_utf8_lossy(abs_path).into_owned(),
+        });
+    }
+
+    let end = abs_path
+        .iter()
+        .rposition(|&byte| byte != b'/')
+        .map(|index| index + 1)
+        .unwrap_or(0);
+    let path = &abs_path[..end];
+    let start = path.iter().rposition(|&byte| byte == b'/').unwrap_or(0);
+    let name = &path[start + 1..];
+
+    if name.len() == 0 || name == b"." || name == b".." {
+        return Err(Error::NotFound {
+            name: String::from_utf8_lossy(abs_path).into_owned(),
+        });
+    }
+
+    if name.len() > 255 {
+        return Err(Error::NameTooLong {
+            name: String::from_utf8_lossy(name).into_owned(),
+        });
+    }
+
+    let parent = if start == 0 { &path[..1] } else { &path[..start] };
+    Ok((parent, name))
+}
+
+/// `DirOptions` doesn't expose its fields, but setting `recursive` on a copy
+/// is a no-op if it was already set
+fn is_recursive(options: &DirOptions<()>) -> bool {
+    let mut probe = options.clone();
+    probe.recursive(true);
+    probe == *options
+}
+
+impl<S: SectorSize, V: Volume<u8, S>> Fs for Synced<Ext2<S, V>> {
+    type Path = [u8];
+    type PathOwned = Vec<u8>;
+    type File = FileHandle<S, V>;
+    type Dir = Directory<S, V>;
+    type DirEntry = DirectoryEntry<S, V>;
+    type Metadata = Metadata;
+    type Permissions = (); // TODO
+    type Error = Error;
+
+    fn open(
+        &self,
+        abs_path: &Self::Path,
+        _options: &OpenOptions<Self::Permissions>,
+    ) -> Result<Self::File, Self::Error> {
+        self.find_inode(abs_path).map(FileHandle::new)
+    }
+
+    fn remove_file(&mut self, path: &Self::Path) -> Result<(), Self::Error> {
+        self.inner().check_writable()?;
+        let (parent_path, name) = split_path(path)?;
+        let mut parent = self.find_directory(parent_path)?;
+        let entry = parent.find_entry(name)?.ok_or_else(|| Error::NotFound {
+            name: String::from_utf8_lossy(path).into_owned(),
+        })?;
+        let inode = self.try_inode_nth(entry.inode)?;
+
+        if inode.is_dir() {
+            return Err(Error::IsADirectory {
+                inode: inode.num,
+                name: String::from_utf8_lossy(path).into_owned(),
+            });
+        }
+
+        parent.remove_entry(name)?;
+        self.unlink(inode)
     }
 
     fn metadata(
         &self,
-        _path: &Self::Path,
+        path: &Self::Path,
     ) -> Result<Self::Metadata, Self::Error> {
-        unimplemented!()
+        self.find_inode(path).map(|inode| inode.metadata())
     }
 
     fn symlink_metadata(
         &self,
-        _path: &Self::Path,
+        path: &Self::Path,
     ) -> Result<Self::Metadata, Self::Error> {
-        unimplemented!()
+        self.find_inode_nofollow(path).map(|inode| inode.metadata())
     }
 
     fn rename(
         &mut self,
-        _from: &Self::Path,
-        _to: &Self::Path,
+        from: &Self::Path,
+        to: &Self::Path,
     ) -> Result<(), Self::Error> {
-        unimplemented!()
+        self.inner().check_writable()?;
+        let (from_parent_path, from_name) = split_path(from)?;
+        let (to_parent_path, to_name) = split_path(to)?;
+
+        let from_parent = self.find_directory(from_parent_path)?;
+        let entry =
+            from_parent.find_entry(from_name)?.ok_or_else(|| Error::NotFound {
+                name: String::from_utf8_lossy(from).into_owned(),
+            })?;
+        let mut inode = self.try_inode_nth(entry.inode)?;
+        let directory = inode.is_dir();
+
+        // a directory can't become its own descendant
+        let to_parent = self.find_directory(to_parent_path)?;
+        if directory && self.is_ancestor(inode.num, &to_parent)? {
+            return Err(Error::Other(format!(
+                "can't move {} into itself",
+                String::from_utf8_lossy(from)
+            )));
+        }
+
+        if let Some(existing) = to_parent.find_entry(to_name)? {
+            if existing.inode == entry.inode {
+                return Ok(());
+            }
+
+            let existing = self.try_inode_nth(existing.inode)?;
+            match (directory, existing.is_dir()) {
+                (true, true) => self.remove_dir(to)?,
+                (false, false) => self.remove_file(to)?,
+                (true, false) => {
+                    return Err(Error::NotADirectory {
+                        inode: existing.num,
+                        name: String::from_utf8_lossy(to).into_owned(),
+                    })
+                }
+                (false, true) => {
+                    return Err(Error::IsADirectory {
+                        inode: existing.num,
+                        name: String::from_utf8_lossy(to).into_owned(),
+                    })
+                }
+            }
+        }
+
+        // link the new name before unlinking the old one, so that an
+        // interrupted rename never leaves the inode unreachable. The parents
+        // are reloaded after every step, as they might be the same directory.
+        let mut to_parent = self.find_directory(to_parent_path)?;
+        to_parent.add_entry(to_name, inode.num, entry.ty)?;
+        let mut from_parent = self.find_directory(from_parent_path)?;
+        from_parent.remove_entry(from_name)?;
+
+        if directory && from_parent.num != to_parent.num {
+            inode.replace_entry(b"..", to_parent.num)?;
+
+            from_parent.inner.hard_links =
+                from_parent.inner.hard_links.saturating_sub(1);
+            from_parent.commit()?;
+            let mut to_parent = self.find_directory(to_parent_path)?;
+            to_parent.inner.hard_links += 1;
+            to_parent.commit()?;
+        }
+
+        Ok(())
     }
 
     fn copy(
@@ -188,17 +551,49 @@ impl<S: SectorSize, V: Volume<u8, S>> Fs for Synced<Ext2<S, V>> {
 
     fn symlink(
         &mut self,
-        _src: &Self::Path,
-        _dst: &Self::Path,
+        src: &Self::Path,
+        dst: &Self::Path,
     ) -> Result<(), Self::Error> {
-        unimplemented!()
+        self.inner().check_writable()?;
+        let (parent_path, name) = split_path(dst)?;
+        let parent = self.find_directory(parent_path)?;
+        if parent.find_entry(name)?.is_some() {
+            return Err(Error::AlreadyExists {
+                name: String::from_utf8_lossy(dst).into_owned(),
+            });
+        }
+
+        let type_perm =
+            TypePerm::SYMLINK | TypePerm::from_bits_truncate(LINK_PERMISSIONS);
+        let ty = self.entry_type(type_perm);
+        let mut link = self.create_inode(&parent, type_perm)?;
+        link.inner.hard_links = 1;
+
+        if src.len() < INLINE_DATA_SIZE {
+            // fast symlink, the target is stored in place of block pointers
+            link.inner.inline_data_mut()[..src.len()].copy_from_slice(src);
+            link.inner.size_low = src.len() as u32;
+            link.commit()?;
+        } else {
+            link = self.fill_inode(link, src)?;
+        }
+
+        let mut parent = self.find_directory(parent_path)?;
+        parent.add_entry(name, link.num, ty)
     }
 
     fn read_link(
         &self,
-        _path: &Self::Path,
+        path: &Self::Path,
     ) -> Result<Self::PathOwned, Self::Error> {
-        unimplemented!()
+        let inode = self.find_inode_nofollow(path)?;
+        if !inode.is_symlink() {
+            return Err(Error::NotASymlink {
+                inode: inode.num,
+                name: String::from_utf8_lossy(path).into_owned(),
+            });
+        }
+        inode.read_link()
     }
 
     fn canonicalize(
@@ -210,25 +605,132 @@ impl<S: SectorSize, V: Volume<u8, S>> Fs for Synced<Ext2<S, V>> {
 
     fn create_dir(
         &mut self,
-        _path: &Self::Path,
-        _options: &DirOptions<Self::Permissions>,
+        path: &Self::Path,
+        options: &DirOptions<Self::Permissions>,
     ) -> Result<(), Self::Error> {
-        unimplemented!()
+        self.inner().check_writable()?;
+        let (parent_path, name) = split_path(path)?;
+        let recursive = is_recursive(options);
+
+        let parent = match self.find_directory(parent_path) {
+            Err(Error::NotFound { .. }) if recursive => {
+                self.create_dir(parent_path, options)?;
+                self.find_directory(parent_path)?
+            }
+            result => result?,
+        };
+
+        if let Some(entry) = parent.find_entry(name)? {
+            let inode = self.try_inode_nth(entry.inode)?;
+            return if recursive && inode.is_dir() {
+                Ok(())
+            } else {
+                Err(Error::AlreadyExists {
+                    name: String::from_utf8_lossy(path).into_owned(),
+                })
+            };
+        }
+
+        let type_perm = TypePerm::DIRECTORY
+            | TypePerm::from_bits_truncate(DIR_PERMISSIONS);
+        let ty = self.entry_type(type_perm);
+        let mut dir = self.create_inode(&parent, type_perm)?;
+        dir.inner.hard_links = 2;
+
+        let block_size = {
+            let fs = self.inner();
+            fs.block_size()
+        };
+        let mut block = vec![0; block_size];
+        dir::write_record(&mut block, 0, dir.num, 12, b".", ty);
+        dir::write_record(
+            &mut block,
+            12,
+            parent.num,
+            block_size - 12,
+            b"..",
+            ty,
+        );
+        let dir = self.fill_inode(dir, &block)?;
+
+        // the parent might have been modified by the allocation
+        let mut parent = self.find_directory(parent_path)?;
+        parent.add_entry(name, dir.num, ty)?;
+        parent.inner.hard_links += 1;
+        parent.commit()
     }
 
-    fn remove_dir(&mut self, _path: &Self::Path) -> Result<(), Self::Error> {
-        unimplemented!()
+    fn remove_dir(&mut self, path: &Self::Path) -> Result<(), Self::Error> {
+        self.inner().check_writable()?;
+        let (parent_path, name) = split_path(path)?;
+        let mut parent = self.find_directory(parent_path)?;
+        let dir = self.find_directory_nofollow(path)?;
+
+        let empty = dir.directory()
+            .map(|entries| {
+                entries
+                    .filter(|entry| match *entry {
+                        Ok(ref entry) => {
+                            entry.name != b"." && entry.name != b".."
+                        }
+                        Err(_) => true,
+                    })
+                    .next()
+                    .is_none()
+            })
+            .unwrap_or(false);
+        if !empty {
+            return Err(Error::DirectoryNotEmpty {
+                inode: dir.num,
+                name: String::from_utf8_lossy(path).into_owned(),
+            });
+        }
+
+        parent.remove_entry(name)?;
+        parent.inner.hard_links = parent.inner.hard_links.saturating_sub(1);
+        parent.commit()?;
+
+        // drop the link from `.` first, the one from the parent's entry is
+        // dropped by `unlink`
+        let mut dir = dir;
+        dir.inner.hard_links = 1;
+        self.unlink(dir)
     }
 
     fn remove_dir_all(
         &mut self,
-        _path: &Self::Path,
+        path: &Self::Path,
     ) -> Result<(), Self::Error> {
-        unimplemented!()
+        self.inner().check_writable()?;
+        let dir = self.find_directory_nofollow(path)?;
+        let entries = dir.directory()
+            .map(|entries| entries.collect::<Result<Vec<_>, _>>())
+            .unwrap_or(Ok(Vec::new()))?;
+
+        for entry in entries {
+            if entry.name == b"." || entry.name == b".." {
+                continue;
+            }
+
+            let mut child = path.to_vec();
+            if child.last() != Some(&b'/') {
+                child.push(b'/');
+            }
+            child.extend_from_slice(&entry.name);
+
+            let inode = self.try_inode_nth(entry.inode)?;
+            if inode.is_dir() {
+                self.remove_dir_all(&child)?;
+            } else {
+                self.remove_file(&child)?;
+            }
+        }
+
+        self.remove_dir(path)
     }
 
     fn read_dir(&self, path: &Self::Path) -> Result<Self::Dir, Self::Error> {
-        let inode = self.open(path, OpenOptions::new().read(true))?;
+        let inode = self.find_inode(path)?;
         inode.directory().ok_or(Error::NotADirectory {
             inode: inode.num,
             name: String::from_utf8_lossy(path).into_owned(),
@@ -250,49 +752,27 @@ impl<S: SectorSize, V: Volume<u8, S>> Debug for Synced<Ext2<S, V>> {
     }
 }
 
+/// Iterates over the inodes of the filesystem, in use or not. Inodes that
+/// couldn't be read from the volume are yielded as errors.
 #[derive(Debug, Clone)]
 pub struct Inodes<S: SectorSize, V: Volume<u8, S>> {
     fs: Synced<Ext2<S, V>>,
-    log_block_size: u32,
-    inode_size: usize,
-    inodes_per_group: usize,
     inodes_count: usize,
     index: usize,
 }
 
 impl<S: SectorSize, V: Volume<u8, S>> Iterator for Inodes<S, V> {
-    type Item = Inode<S, V>;
+    type Item = Result<Inode<S, V>, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index < self.inodes_count {
-            let block_group = (self.index - 1) / self.inodes_per_group;
-            let index = (self.index - 1) % self.inodes_per_group;
+        while self.index <= self.inodes_count {
             self.index += 1;
-
-            let fs = self.fs.inner();
-
-            let inodes_block =
-                fs.block_groups.inner[block_group].inode_table_block;
-
-            let offset = Address::with_block_size(
-                inodes_block,
-                (index * self.inode_size) as i32,
-                self.log_block_size,
-            );
-            let raw = unsafe {
-                RawInode::find_inode(&fs.volume, offset, self.inode_size).ok()
-            };
-            raw.map(|(raw, offset)| {
-                Inode::new(
-                    self.fs.clone(),
-                    raw,
-                    offset,
-                    (self.index - 1) as u32,
-                )
-            })
-        } else {
-            None
+            match self.fs.try_inode_nth(self.index - 1) {
+                Err(Error::InodeNotFound { .. }) => continue,
+                result => return Some(result),
+            }
         }
+        None
     }
 }
 
@@ -300,6 +780,7 @@ impl<S: SectorSize, V: Volume<u8, S>> Iterator for Inodes<S, V> {
 pub struct Inode<S: SectorSize, V: Volume<u8, S>> {
     fs: Synced<Ext2<S, V>>,
     inner: RawInode,
+    extra: Option<InodeExtra>,
     addr: Address<S>,
     num: u32,
 }
@@ -309,6 +790,7 @@ impl<S: SectorSize, V: Volume<u8, S>> Clone for Inode<S, V> {
         Inode {
             fs: self.fs.clone(),
             inner: self.inner,
+            extra: self.extra,
             addr: self.addr,
             num: self.num,
         }
@@ -319,12 +801,14 @@ impl<S: SectorSize, V: Volume<u8, S>> Inode<S, V> {
     pub fn new(
         fs: Synced<Ext2<S, V>>,
         inner: RawInode,
+        extra: Option<InodeExtra>,
         addr: Address<S>,
         num: u32,
     ) -> Inode<S, V> {
         Inode {
             fs,
             inner,
+            extra,
             addr,
             num,
         }
@@ -353,10 +837,24 @@ impl<S: SectorSize, V: Volume<u8, S>> Inode<S, V> {
         })
     }
 
+    /// Iterates over the blocks covering the size of this inode, yielding
+    /// `None` for every hole in a sparse file
     pub fn blocks(&self) -> InodeBlocks<S, V> {
+        let block_size = {
+            let fs = self.fs.inner();
+            fs.block_size() as u64
+        };
+        // the block pointers of a fast symlink hold its target instead
+        let count = if self.is_fast_symlink() {
+            0
+        } else {
+            (self.size64() + block_size - 1) / block_size
+        };
+
         InodeBlocks {
             inode: self.clone(),
             index: 0,
+            count: count as usize,
         }
     }
 
@@ -370,15 +868,630 @@ impl<S: SectorSize, V: Volume<u8, S>> Inode<S, V> {
                     let fs = self.fs.inner();
                     fs.block_size()
                 },
+                index: 0,
+                corrupt: false,
             })
         } else {
             None
         }
     }
 
+    pub fn file_type(&self) -> FileType {
+        FileType::from_type_perm(self.inner.type_perm)
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type().is_file()
+    }
+
     pub fn is_dir(&self) -> bool {
-        use sys::inode::TypePerm;
-        unsafe { self.inner.type_perm.contains(TypePerm::DIRECTORY) }
+        self.file_type().is_dir()
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type().is_symlink()
+    }
+
+    /// A fast symbolic link keeps its target in the block pointer area of
+    /// the inode and has no data blocks, apart from an extended attribute
+    /// block
+    pub fn is_fast_symlink(&self) -> bool {
+        let ext_attribute_sectors = if self.inner.ext_attribute_block != 0 {
+            let fs = self.fs.inner();
+            (fs.block_size() >> 9) as u32
+        } else {
+            0
+        };
+        self.is_symlink() && self.inner.sectors_count == ext_attribute_sectors
+    }
+
+    /// Reads the target of a symbolic link
+    pub fn read_link(&self) -> Result<Vec<u8>, Error> {
+        if self.is_fast_symlink() {
+            let len = self.size().min(INLINE_DATA_SIZE);
+            Ok(self.inner.inline_data()[..len].to_vec())
+        } else {
+            let mut target = Vec::new();
+            self.read_to_end(&mut target).map(|_| target)
+        }
+    }
+
+    pub fn num(&self) -> u32 {
+        self.num
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        Metadata::new(self.num, &self.inner, self.extra.as_ref())
+    }
+
+    /// The extended part of the inode, if the filesystem has inodes larger
+    /// than 128 bytes
+    pub fn extra(&self) -> Option<&InodeExtra> {
+        self.extra.as_ref()
+    }
+
+    /// Extended attributes stored in the body of the inode
+    pub fn xattrs(&self) -> Result<Vec<Xattr>, Error> {
+        let extra_isize = match self.extra {
+            Some(extra) => extra.extra_isize as usize,
+            None => return Ok(Vec::new()),
+        };
+
+        let fs = self.fs.inner();
+        let base_size = mem::size_of::<RawInode>();
+        let start = self.addr + Address::from(base_size + extra_isize);
+        let end = self.addr + Address::from(fs.inode_size());
+        match fs.volume.slice(start..end) {
+            Ok(area) => xattr::parse_ibody(&area),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn hard_links(&self) -> u16 {
+        self.inner.hard_links
+    }
+
+    /// Writes the raw inode back to the volume
+    pub(crate) fn commit(&self) -> Result<(), Error> {
+        let mut fs = self.fs.inner();
+        fs.commit_inode(self.addr, &self.inner)
+    }
+
+    pub fn find_entry(
+        &self,
+        name: &[u8],
+    ) -> Result<Option<DirectoryEntry<S, V>>, Error> {
+        let dir = self.directory().ok_or_else(|| Error::NotADirectory {
+            inode: self.num,
+            name: String::from_utf8_lossy(name).into_owned(),
+        })?;
+
+        if let Some(blocks) = self.indexed_blocks(name)? {
+            for block in blocks {
+                let mut offset = 0;
+                while let Some(record) = dir::read_record(&block, offset) {
+                    if record.inode != 0 && record.name(&block) == name {
+                        return Ok(Some(DirectoryEntry {
+                            name: name.to_vec(),
+                            inode: record.inode as usize,
+                            ty: record.ty,
+                            fs: self.fs.clone(),
+                        }));
+                    }
+                    offset += record.rec_len;
+                }
+            }
+            return Ok(None);
+        }
+
+        for entry in dir {
+            let entry = entry?;
+            if entry.name == name {
+                return Ok(Some(entry));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Reads the blocks of this directory that may hold `name` according to
+    /// its hash index. Returns `None` if the directory isn't indexed or its
+    /// index is corrupted, in which case it has to be searched linearly.
+    fn indexed_blocks(
+        &self,
+        name: &[u8],
+    ) -> Result<Option<Vec<Vec<u8>>>, Error> {
+        let (seed, unsigned) = match self.hash_settings() {
+            Some(settings) if self.is_indexed() => settings,
+            _ => return Ok(None),
+        };
+
+        let root = match self.read_dir_block(0)? {
+            Some(root) => root,
+            None => return Ok(None),
+        };
+        let leaves = htree::lookup(&root, name, &seed, unsigned, |index| {
+            self.read_dir_block(index as usize)
+        })?;
+        let leaves = match leaves {
+            Some(leaves) => leaves,
+            None => return Ok(None),
+        };
+
+        let mut blocks = Vec::with_capacity(leaves.len());
+        for index in leaves {
+            match self.read_dir_block(index as usize)? {
+                Some(block) => blocks.push(block),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(blocks))
+    }
+
+    /// Whether this directory is flagged as hash-indexed
+    fn is_indexed(&self) -> bool {
+        let flags = self.inner.flags;
+        flags.contains(Flags::HASH_DIR)
+    }
+
+    /// The seed of directory hashes and whether they treat names as unsigned,
+    /// or `None` if the filesystem doesn't index directories
+    fn hash_settings(&self) -> Option<([u32; 4], bool)> {
+        let fs = self.fs.inner();
+        let features = fs.features();
+        if !features.optional.contains(FeaturesOptional::HASH_INDEX) {
+            return None;
+        }
+        let superblock = &fs.superblock.inner;
+        let flags = superblock.flags;
+        Some((superblock.hash_seed, flags.contains(FsFlags::UNSIGNED_HASH)))
+    }
+
+    /// Reads the block `index` of this directory, returning `None` if it's
+    /// past the end or not allocated
+    fn read_dir_block(&self, index: usize) -> Result<Option<Vec<u8>>, Error> {
+        let block_size = {
+            let fs = self.fs.inner();
+            fs.block_size()
+        };
+        if index >= self.size() / block_size {
+            return Ok(None);
+        }
+
+        match self.try_block(index)? {
+            Some(block) => {
+                let fs = self.fs.inner();
+                fs.read_block(block.get()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Applies `f` to every block of this directory until it returns `Some`,
+    /// writing the block back if it did
+    fn modify_entries<T, F>(&mut self, mut f: F) -> Result<Option<T>, Error>
+    where
+        F: FnMut(&mut [u8]) -> Option<T>,
+    {
+        let block_size = {
+            let fs = self.fs.inner();
+            fs.block_size()
+        };
+
+        for index in 0..self.size() / block_size {
+            let block = match self.try_block(index)? {
+                Some(block) => block.get(),
+                None => continue,
+            };
+
+            let mut fs = self.fs.inner();
+            let mut data = fs.read_block(block)?;
+            if let Some(result) = f(&mut data[..]) {
+                fs.write_block_as(block, data, WriteClass::Directory)?;
+                Inode::touch(&fs, &mut self.inner, self.extra.as_mut());
+                fs.commit_inode(self.addr, &self.inner)?;
+                if let Some(ref extra) = self.extra {
+                    fs.commit_inode_extra(self.addr, extra)?;
+                }
+                return Ok(Some(result));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Links `inode` into this directory as `name`, reusing slack in existing
+    /// records or appending a new block if there's none.
+    ///
+    /// On filesystems with `FeaturesOptional::HASH_INDEX`, a directory
+    /// outgrowing its first block is converted into an indexed one, whose
+    /// index is kept up to date. Like Linux does, a corrupted index is
+    /// dropped and the directory is treated as a linear one instead.
+    pub(crate) fn add_entry(
+        &mut self,
+        name: &[u8],
+        inode: u32,
+        ty: u8,
+    ) -> Result<(), Error> {
+        let settings = self.hash_settings();
+        if let Some(settings) = settings {
+            if self.is_indexed() {
+                if self.add_indexed_entry(name, inode, ty, settings)? {
+                    return Ok(());
+                }
+                let mut flags = self.inner.flags;
+                flags.remove(Flags::HASH_DIR);
+                self.inner.flags = flags;
+                self.commit()?;
+            }
+        }
+
+        let inserted = self.modify_entries(|block| {
+            if dir::insert(block, inode, name, ty) {
+                Some(())
+            } else {
+                None
+            }
+        })?;
+        if inserted.is_some() {
+            return Ok(());
+        }
+
+        let block_size = {
+            let fs = self.fs.inner();
+            fs.block_size()
+        };
+        if let Some(settings) = settings {
+            if self.size() == block_size && self.make_indexed(settings)? {
+                return self.add_entry(name, inode, ty);
+            }
+        }
+
+        let mut block = vec![0; block_size];
+        dir::write_record(&mut block, 0, inode, block_size, name, ty);
+        self.append_dir_block(&block).map(|_| ())
+    }
+
+    /// Inserts an entry into the leaf its hash belongs to, splitting the leaf
+    /// if it's full. Returns `false` if the index is corrupted.
+    fn add_indexed_entry(
+        &mut self,
+        name: &[u8],
+        inode: u32,
+        ty: u8,
+        (seed, unsigned): ([u32; 4], bool),
+    ) -> Result<bool, Error> {
+        let (block_size, group_count) = {
+            let fs = self.fs.inner();
+            (fs.block_size(), fs.block_groups.inner.len())
+        };
+
+        let mut root_block = match self.read_dir_block(0)? {
+            Some(block) => block,
+            None => return Ok(false),
+        };
+        let mut root = match htree::read_root(&root_block) {
+            Some(root) => root,
+            None => return Ok(false),
+        };
+        let version = match HashVersion::from_raw(root.hash_version, unsigned) {
+            Some(version) => version,
+            None => return Ok(false),
+        };
+        let (hash, _) = htree::hash(name, version, &seed);
+        let mut path = match htree::probe(&root, hash, |index| {
+            self.read_dir_block(index as usize)
+        })? {
+            Some(path) => path,
+            None => return Ok(false),
+        };
+
+        let leaf_index = path.last().unwrap().target();
+        let mut leaf = match self.read_dir_block(leaf_index as usize)? {
+            Some(block) => block,
+            None => return Ok(false),
+        };
+        if dir::insert(&mut leaf, inode, name, ty) {
+            self.write_dir_block(leaf_index, &leaf)?;
+            return Ok(true);
+        }
+
+        // make room for the entry of the new leaf first
+        let full = |frames: &[htree::Frame]| {
+            let capacity = if frames.len() == 1 {
+                htree::root_capacity(block_size)
+            } else {
+                htree::node_capacity(block_size)
+            };
+            frames.last().unwrap().entries.len() >= capacity
+        };
+        if full(&path[..]) {
+            if path.len() == 1 {
+                if root.indirect_levels >= htree::MAX_INDIRECT_LEVELS {
+                    return Err(Error::NoSpaceLeft { group_count });
+                }
+
+                // move the entries of the root into a new node below it
+                let mut frame = path.pop().unwrap();
+                let node = htree::node_block(block_size, &frame.entries);
+                let node_index = self.append_dir_block(&node)?;
+                root.indirect_levels += 1;
+                path.push(htree::Frame {
+                    block: 0,
+                    entries: vec![htree::Entry {
+                        hash: 0,
+                        block: node_index,
+                    }],
+                    index: 0,
+                });
+                frame.block = node_index;
+                path.push(frame);
+            } else {
+                if full(&path[..1]) {
+                    return Err(Error::NoSpaceLeft { group_count });
+                }
+
+                // split the node in two halves
+                let mut frame = path.pop().unwrap();
+                let half = frame.entries.len() / 2;
+                let upper = frame.entries.split_off(half);
+                let node = htree::node_block(block_size, &upper);
+                let node_index = self.append_dir_block(&node)?;
+                let node = htree::node_block(block_size, &frame.entries);
+                self.write_dir_block(frame.block, &node)?;
+
+                {
+                    let parent = &mut path[0];
+                    parent.entries.insert(
+                        parent.index + 1,
+                        htree::Entry {
+                            hash: upper[0].hash,
+                            block: node_index,
+                        },
+                    );
+                    if frame.index >= half {
+                        parent.index += 1;
+                    }
+                }
+                if frame.index >= half {
+                    frame = htree::Frame {
+                        block: node_index,
+                        entries: upper,
+                        index: frame.index - half,
+                    };
+                }
+                path.push(frame);
+            }
+            htree::write_root(
+                &mut root_block,
+                root.hash_version,
+                root.indirect_levels,
+                &path[0].entries,
+            );
+            self.write_dir_block(0, &root_block)?;
+        }
+
+        let entries = htree::leaf_entries(&leaf, 0, version, &seed);
+        let (lower, upper, split_hash) = htree::split_leaf(entries, block_size);
+        let upper_index = self.append_dir_block(&upper)?;
+        self.write_dir_block(leaf_index, &lower)?;
+
+        {
+            let frame = path.last_mut().unwrap();
+            let index = frame.index + 1;
+            frame.entries.insert(
+                index,
+                htree::Entry {
+                    hash: split_hash,
+                    block: upper_index,
+                },
+            );
+        }
+        if path.len() == 1 {
+            htree::write_root(
+                &mut root_block,
+                root.hash_version,
+                root.indirect_levels,
+                &path[0].entries,
+            );
+            self.write_dir_block(0, &root_block)?;
+        } else {
+            let frame = path.last().unwrap();
+            let node = htree::node_block(block_size, &frame.entries);
+            self.write_dir_block(frame.block, &node)?;
+        }
+
+        let (index, mut block) = if hash >= split_hash & !1 {
+            (upper_index, upper)
+        } else {
+            (leaf_index, lower)
+        };
+        if !dir::insert(&mut block, inode, name, ty) {
+            return Err(Error::NoSpaceLeft { group_count });
+        }
+        self.write_dir_block(index, &block)?;
+        Ok(true)
+    }
+
+    /// Converts this single block directory into an indexed one, moving its
+    /// records into two leaves. Returns `false` if its first block doesn't
+    /// start with the `.` and `..` records.
+    fn make_indexed(
+        &mut self,
+        (seed, unsigned): ([u32; 4], bool),
+    ) -> Result<bool, Error> {
+        let (block_size, hash_version) = {
+            let fs = self.fs.inner();
+            (fs.block_size(), fs.superblock.inner.def_hash_version)
+        };
+        // Linux falls back to half-MD4 for unknown hash versions as well
+        let hash_version = if hash_version <= 2 { hash_version } else { 1 };
+        let version = HashVersion::from_raw(hash_version, unsigned).unwrap();
+
+        let block = match self.read_dir_block(0)? {
+            Some(block) => block,
+            None => return Ok(false),
+        };
+        let (dot, dotdot) = match dir::read_record(&block, 0) {
+            Some(dot) if dot.name(&block) == b"." => {
+                match dir::read_record(&block, dot.rec_len) {
+                    Some(dotdot) if dotdot.name(&block) == b".." => {
+                        (dot, dotdot)
+                    }
+                    _ => return Ok(false),
+                }
+            }
+            _ => return Ok(false),
+        };
+
+        let start = dotdot.offset + dotdot.rec_len;
+        let entries = htree::leaf_entries(&block, start, version, &seed);
+        let (lower, upper, split_hash) = htree::split_leaf(entries, block_size);
+        let lower_index = self.append_dir_block(&lower)?;
+        let upper_index = self.append_dir_block(&upper)?;
+
+        let mut root = vec![0; block_size];
+        dir::write_record(&mut root, 0, dot.inode, 12, b".", dot.ty);
+        dir::write_record(
+            &mut root,
+            12,
+            dotdot.inode,
+            block_size - 12,
+            b"..",
+            dotdot.ty,
+        );
+        let entries = [
+            htree::Entry {
+                hash: 0,
+                block: lower_index,
+            },
+            htree::Entry {
+                hash: split_hash,
+                block: upper_index,
+            },
+        ];
+        htree::write_root(&mut root, hash_version, 0, &entries);
+        self.write_dir_block(0, &root)?;
+
+        let mut flags = self.inner.flags;
+        flags.insert(Flags::HASH_DIR);
+        self.inner.flags = flags;
+        self.commit()?;
+        Ok(true)
+    }
+
+    /// Appends `block` to this directory, returning its index
+    fn append_dir_block(&mut self, block: &[u8]) -> Result<u32, Error> {
+        let size = self.size64();
+        let index = size / block.len() as u64;
+        self.write_dir_block(index as u32, block)?;
+        Ok(index as u32)
+    }
+
+    /// Overwrites the block `index` of this directory, allocating it if
+    /// needed
+    fn write_dir_block(
+        &mut self,
+        index: u32,
+        block: &[u8],
+    ) -> Result<(), Error> {
+        let offset = index as u64 * block.len() as u64;
+        let class = WriteClass::Directory;
+        if self.write_as(offset, block, class)? < block.len() {
+            let fs = self.fs.inner();
+            return Err(Error::NoSpaceLeft {
+                group_count: fs.block_groups.inner.len(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Unlinks the entry `name` from this directory, returning the inode it
+    /// pointed to
+    pub(crate) fn remove_entry(&mut self, name: &[u8]) -> Result<u32, Error> {
+        let num = self.num;
+        self.modify_entries(|block| dir::remove(block, name))?
+            .ok_or_else(|| Error::NotFound {
+                name: format!(
+                    "{} in directory inode no. {}",
+                    String::from_utf8_lossy(name),
+                    num
+                ),
+            })
+    }
+
+    /// Points the entry `name` of this directory at `inode`
+    pub(crate) fn replace_entry(
+        &mut self,
+        name: &[u8],
+        inode: u32,
+    ) -> Result<u32, Error> {
+        let num = self.num;
+        self.modify_entries(|block| dir::replace(block, name, inode))?
+            .ok_or_else(|| Error::NotFound {
+                name: format!(
+                    "{} in directory inode no. {}",
+                    String::from_utf8_lossy(name),
+                    num
+                ),
+            })
+    }
+
+    /// Releases every data and indirect block of this inode and truncates it
+    /// to 0 bytes
+    fn free_blocks(&mut self) -> Result<(), Error> {
+        if self.is_fast_symlink() {
+            self.inner.inline_data_mut().iter_mut().for_each(|x| *x = 0);
+            self.inner.size_low = 0;
+            return self.commit();
+        }
+
+        let mut fs = self.fs.inner();
+
+        for index in 0..12 {
+            let block = self.inner.direct_pointer[index];
+            if block != 0 {
+                fs.free_block(block)?;
+            }
+        }
+
+        let roots = [
+            (self.inner.indirect_pointer, 1),
+            (self.inner.doubly_indirect, 2),
+            (self.inner.triply_indirect, 3),
+        ];
+        for &(block, depth) in roots.iter() {
+            if block != 0 {
+                Inode::free_tree(&mut fs, block, depth)?;
+            }
+        }
+
+        self.inner.direct_pointer = [0; 12];
+        self.inner.indirect_pointer = 0;
+        self.inner.doubly_indirect = 0;
+        self.inner.triply_indirect = 0;
+        self.inner.sectors_count = 0;
+        self.inner.size_low = 0;
+        self.inner.size_high = 0;
+        fs.commit_inode(self.addr, &self.inner)
+    }
+
+    fn free_tree(
+        fs: &mut Ext2<S, V>,
+        block: u32,
+        depth: u32,
+    ) -> Result<(), Error> {
+        if depth > 0 {
+            let table = fs.read_block(block)?;
+            for entry in table.chunks(4) {
+                let pointer = dir::read_u32(entry, 0);
+                if pointer != 0 {
+                    Inode::free_tree(fs, pointer, depth - 1)?;
+                }
+            }
+        }
+
+        fs.free_block(block)
     }
 
     pub fn block(&self, index: usize) -> Option<NonZero<u32>> {
@@ -387,7 +1500,7 @@ impl<S: SectorSize, V: Volume<u8, S>> Inode<S, V> {
 
     pub fn try_block(
         &self,
-        mut index: usize,
+        index: usize,
     ) -> Result<Option<NonZero<u32>>, Error> {
         // number of blocks in direct table: 12
         // number of blocks in indirect table: block_size/4
@@ -409,6 +1522,10 @@ impl<S: SectorSize, V: Volume<u8, S>> Inode<S, V> {
             index: usize,
             log_block_size: u32,
         ) -> Result<Option<NonZero<u32>>, Error> {
+            if block == 0 {
+                return Ok(None);
+            }
+
             let offset = (index * 4) as i32;
             let end = offset + 4;
             let addr = Address::with_block_size(block, offset, log_block_size);
@@ -423,77 +1540,372 @@ impl<S: SectorSize, V: Volume<u8, S>> Inode<S, V> {
         }
 
         let fs = self.fs.inner();
-
-        let bs4 = fs.block_size() / 4;
         let log_block_size = fs.log_block_size();
 
-        if index < 12 {
-            return Ok(NonZero::new(self.inner.direct_pointer[index]));
+        let path = match BlockPath::new(index, log_block_size) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        match path {
+            BlockPath::Direct(index) => {
+                Ok(NonZero::new(self.inner.direct_pointer[index]))
+            }
+            BlockPath::Indirect(index) => block_index(
+                &fs.volume,
+                self.inner.indirect_pointer,
+                index,
+                log_block_size,
+            ),
+            BlockPath::Doubly(indirect_index, index) => {
+                let block = match block_index(
+                    &fs.volume,
+                    self.inner.doubly_indirect,
+                    indirect_index,
+                    log_block_size,
+                ) {
+                    Ok(Some(block)) => block.get(),
+                    Ok(None) => return Ok(None),
+                    Err(err) => return Err(err),
+                };
+                block_index(&fs.volume, block, index, log_block_size)
+            }
+            BlockPath::Triply(doubly_index, indirect_index, index) => {
+                let indirect = match block_index(
+                    &fs.volume,
+                    self.inner.triply_indirect,
+                    doubly_index,
+                    log_block_size,
+                ) {
+                    Ok(Some(block)) => block.get(),
+                    Ok(None) => return Ok(None),
+                    Err(err) => return Err(err),
+                };
+                let block = match block_index(
+                    &fs.volume,
+                    indirect,
+                    indirect_index,
+                    log_block_size,
+                ) {
+                    Ok(Some(block)) => block.get(),
+                    Ok(None) => return Ok(None),
+                    Err(err) => return Err(err),
+                };
+                block_index(&fs.volume, block, index, log_block_size)
+            }
         }
+    }
 
-        index -= 12;
+    /// Reads from the file starting at byte `offset`. Only the blocks
+    /// covering the requested range are read from the volume.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let size = self.size64();
+        if offset >= size {
+            return Ok(0);
+        }
 
-        if index < bs4 {
-            let block = self.inner.indirect_pointer;
-            return block_index(&fs.volume, block, index, log_block_size);
+        let len = (size - offset).min(buf.len() as u64) as usize;
+        if self.is_fast_symlink() {
+            // the target is stored in place of the block pointers
+            let end = (offset + len as u64).min(INLINE_DATA_SIZE as u64);
+            if offset >= end {
+                return Ok(0);
+            }
+            let data = &self.inner.inline_data()[offset as usize..end as usize];
+            buf[..data.len()].copy_from_slice(data);
+            return Ok(data.len());
         }
 
-        index -= bs4;
+        let block_size = {
+            let fs = self.fs.inner();
+            fs.block_size() as u64
+        };
 
-        if index < bs4 * bs4 {
-            let indirect_index = index >> (log_block_size + 2);
-            let block = match block_index(
-                &fs.volume,
-                self.inner.doubly_indirect,
-                indirect_index,
-                log_block_size,
+        let mut read = 0;
+        while read < len {
+            let position = offset + read as u64;
+            let index = (position / block_size) as usize;
+            let block_offset = (position % block_size) as usize;
+            let chunk = (block_size as usize - block_offset).min(len - read);
+            let dst = &mut buf[read..read + chunk];
+
+            match self.try_block(index)? {
+                Some(block) => {
+                    let fs = self.fs.inner();
+                    let data = fs.read_block(block.get())?;
+                    dst.copy_from_slice(
+                        &data[block_offset..block_offset + chunk],
+                    );
+                }
+                None => dst.iter_mut().for_each(|x| *x = 0),
+            }
+
+            read += chunk;
+        }
+
+        Ok(read)
+    }
+
+    /// Writes `buf` into the file starting at byte `offset`, allocating any
+    /// missing data and indirect blocks on the way. The raw inode is committed
+    /// to the volume afterwards, even if the write could only be completed
+    /// partially.
+    pub fn write_at(
+        &mut self,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<usize, Error> {
+        self.write_as(offset, buf, WriteClass::Data)
+    }
+
+    /// Like `write_at`, writing data blocks of the kind `class`
+    fn write_as(
+        &mut self,
+        offset: u64,
+        buf: &[u8],
+        class: WriteClass,
+    ) -> Result<usize, Error> {
+        // the block pointers of a fast symlink hold its target, a symlink
+        // without one yet is written like any other file
+        if self.is_fast_symlink() && self.size64() > 0 {
+            return Err(Error::Other(format!(
+                "can't write to the fast symlink inode no. {}",
+                self.num
+            )));
+        }
+
+        let mut fs = self.fs.inner();
+        fs.check_writable()?;
+        let block_size = fs.block_size();
+        let group = fs.block_group_of_inode(self.num);
+        let mut goal = fs.group_first_block(group);
+
+        let mut written = 0;
+        let mut error = None;
+        while written < buf.len() {
+            let position = offset + written as u64;
+            let index = (position / block_size as u64) as usize;
+            let block_offset = (position % block_size as u64) as usize;
+            let len = (block_size - block_offset).min(buf.len() - written);
+
+            let (block, fresh) = match Inode::block_or_allocate(
+                &mut fs,
+                &mut self.inner,
+                goal,
+                index,
             ) {
-                Ok(Some(block)) => block.get(),
-                Ok(None) => return Ok(None),
-                Err(err) => return Err(err),
+                Ok(block) => block,
+                Err(err) => {
+                    error = Some(err);
+                    break;
+                }
             };
-            return block_index(
-                &fs.volume,
-                block,
-                index & (bs4 - 1),
-                log_block_size,
-            );
+
+            // blocks allocated so far are only recorded in `self.inner`, so
+            // errors must not skip committing it
+            let data = if fresh || len == block_size {
+                Ok(vec![0; block_size])
+            } else {
+                fs.read_block(block)
+            };
+            let result = data.and_then(|mut data| {
+                data[block_offset..block_offset + len]
+                    .copy_from_slice(&buf[written..written + len]);
+                fs.write_block_as(block, data, class)
+            });
+            if let Err(err) = result {
+                error = Some(err);
+                break;
+            }
+
+            goal = block + 1;
+            written += len;
+        }
+
+        let size = offset + written as u64;
+        let grown = size > self.size64();
+        if grown {
+            self.inner.size_low = size as u32;
+            self.inner.size_high = (size >> 32) as u32;
+        }
+        if written > 0 {
+            Inode::touch(&fs, &mut self.inner, self.extra.as_mut());
+        }
+        fs.commit_inode(self.addr, &self.inner)?;
+        match self.extra {
+            Some(ref extra) if written > 0 => {
+                fs.commit_inode_extra(self.addr, extra)?
+            }
+            _ => (),
+        }
+
+        // regular files over 2 GiB need `size_high`, which older
+        // implementations ignore
+        let features = fs.superblock.inner.features_ronly;
+        if grown && size > i32::max_value() as u64 && self.is_file()
+            && !features.contains(FeaturesROnly::RONLY_FILE_SIZE_64)
+        {
+            fs.superblock.inner.features_ronly =
+                features | FeaturesROnly::RONLY_FILE_SIZE_64;
+            fs.commit_superblock()?;
+        }
+
+        match error {
+            Some(err) if written == 0 => Err(err),
+            _ => Ok(written),
+        }
+    }
+
+    /// Sets the modification and change times of an inode to the current
+    /// time of `fs`, without committing it
+    fn touch(
+        fs: &Ext2<S, V>,
+        inner: &mut RawInode,
+        extra: Option<&mut InodeExtra>,
+    ) {
+        let time = fs.timestamp();
+        inner.mtime = time;
+        inner.ctime = time;
+        if let Some(extra) = extra {
+            // the epoch bits have to match the new times
+            let (_, time_extra) = InodeExtra::encode_time(time as i64, 0);
+            extra.mtime_extra = time_extra;
+            extra.ctime_extra = time_extra;
+        }
+    }
+
+    /// Returns the block at `index`, allocating it (and every indirect block
+    /// leading to it) if it isn't there yet. The second value is `true` if
+    /// the data block was freshly allocated.
+    fn block_or_allocate(
+        fs: &mut Ext2<S, V>,
+        inner: &mut RawInode,
+        goal: u32,
+        index: usize,
+    ) -> Result<(u32, bool), Error> {
+        let path = BlockPath::new(index, fs.log_block_size())
+            .ok_or(Error::OutOfBounds { index })?;
+
+        match path {
+            BlockPath::Direct(index) => {
+                let block = inner.direct_pointer[index];
+                if block != 0 {
+                    return Ok((block, false));
+                }
+                let block = Inode::allocate(fs, inner, goal, false)?;
+                inner.direct_pointer[index] = block;
+                Ok((block, true))
+            }
+            BlockPath::Indirect(index) => {
+                let mut table = inner.indirect_pointer;
+                if table == 0 {
+                    table = Inode::allocate(fs, inner, goal, true)?;
+                    inner.indirect_pointer = table;
+                }
+                Inode::entry_or_allocate(fs, inner, goal, table, index, false)
+            }
+            BlockPath::Doubly(indirect_index, index) => {
+                let mut table = inner.doubly_indirect;
+                if table == 0 {
+                    table = Inode::allocate(fs, inner, goal, true)?;
+                    inner.doubly_indirect = table;
+                }
+                let (table, _) = Inode::entry_or_allocate(
+                    fs,
+                    inner,
+                    goal,
+                    table,
+                    indirect_index,
+                    true,
+                )?;
+                Inode::entry_or_allocate(fs, inner, goal, table, index, false)
+            }
+            BlockPath::Triply(doubly_index, indirect_index, index) => {
+                let mut table = inner.triply_indirect;
+                if table == 0 {
+                    table = Inode::allocate(fs, inner, goal, true)?;
+                    inner.triply_indirect = table;
+                }
+                let (table, _) = Inode::entry_or_allocate(
+                    fs,
+                    inner,
+                    goal,
+                    table,
+                    doubly_index,
+                    true,
+                )?;
+                let (table, _) = Inode::entry_or_allocate(
+                    fs,
+                    inner,
+                    goal,
+                    table,
+                    indirect_index,
+                    true,
+                )?;
+                Inode::entry_or_allocate(fs, inner, goal, table, index, false)
+            }
         }
+    }
 
-        index -= bs4 * bs4;
+    /// Reads the block pointer at `index` in the indirect block `table`,
+    /// allocating a new block and storing its address there if it's empty
+    fn entry_or_allocate(
+        fs: &mut Ext2<S, V>,
+        inner: &mut RawInode,
+        goal: u32,
+        table: u32,
+        index: usize,
+        zeroed: bool,
+    ) -> Result<(u32, bool), Error> {
+        let log_block_size = fs.log_block_size();
+        let offset = (index * 4) as i32;
+        let addr = Address::with_block_size(table, offset, log_block_size);
+        let end = Address::with_block_size(table, offset + 4, log_block_size);
 
-        if index < bs4 * bs4 * bs4 {
-            let doubly_index = index >> (2 * log_block_size + 4);
-            let indirect = match block_index(
-                &fs.volume,
-                self.inner.triply_indirect,
-                doubly_index,
-                log_block_size,
-            ) {
-                Ok(Some(block)) => block.get(),
-                Ok(None) => return Ok(None),
-                Err(err) => return Err(err),
-            };
-            let indirect_index = (index >> (log_block_size + 2)) & (bs4 - 1);
-            let block = match block_index(
-                &fs.volume,
-                indirect as u32,
-                indirect_index,
-                log_block_size,
-            ) {
-                Ok(Some(block)) => block.get(),
-                Ok(None) => return Ok(None),
-                Err(err) => return Err(err),
-            };
-            return block_index(
-                &fs.volume,
-                block,
-                index & (bs4 - 1),
-                log_block_size,
-            );
+        let block = match fs.volume.slice(addr..end) {
+            Ok(slice) => unsafe { slice.dynamic_cast::<u32>().0 },
+            Err(err) => return Err(err.into()),
+        };
+        if block != 0 {
+            return Ok((block, false));
         }
 
-        Ok(None)
+        let block = Inode::allocate(fs, inner, goal, zeroed)?;
+        let bytes = vec![
+            block as u8,
+            (block >> 8) as u8,
+            (block >> 16) as u8,
+            (block >> 24) as u8,
+        ];
+        fs.commit(VolumeCommit::new(bytes, addr))?;
+        Ok((block, true))
+    }
+
+    /// Allocates a block for this inode and accounts for it in
+    /// `sectors_count`
+    fn allocate(
+        fs: &mut Ext2<S, V>,
+        inner: &mut RawInode,
+        goal: u32,
+        zeroed: bool,
+    ) -> Result<u32, Error> {
+        let block = fs.allocate_block(goal)?;
+        let block_size = fs.block_size();
+        if zeroed {
+            fs.write_block(block, vec![0; block_size])?;
+        }
+        inner.sectors_count += (block_size >> 9) as u32;
+        Ok(block)
+    }
+
+    /// The raw on-disk inode
+    pub(crate) fn raw(&self) -> &RawInode {
+        &self.inner
+    }
+
+    /// The raw inode, only written back by `commit`
+    pub(crate) fn raw_mut(&mut self) -> &mut RawInode {
+        &mut self.inner
     }
 
     pub fn in_use(&self) -> bool {
@@ -533,40 +1945,155 @@ impl<S: SectorSize, V: Volume<u8, S>> File for Inode<S, V> {
     type Error = Error;
 
     fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
-        let total_size = self.size();
-        let block_size = {
-            let fs = self.fs.inner();
-            fs.block_size()
-        };
-        let mut offset = 0;
+        self.read_at(0, buf)
+    }
 
-        for block in self.blocks() {
-            match block {
-                Ok((data, _)) => {
-                    let data_size = block_size
-                        .min(total_size - offset)
-                        .min(buf.len() - offset);
-                    let end = offset + data_size;
-                    buf[offset..end].copy_from_slice(&data[..data_size]);
-                    offset += data_size;
-                }
-                Err(err) => return Err(err.into()),
-            }
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.write_at(0, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        // every write is committed to the volume immediately
+        Ok(())
+    }
+
+    fn seek(&mut self, _pos: SeekFrom) -> Result<u64, Self::Error> {
+        Err(Error::Other(String::from(
+            "an inode has no cursor, use a `FileHandle` to seek",
+        )))
+    }
+}
+
+/// An open file with a cursor, as returned by `Fs::open`
+#[derive(Debug, Clone)]
+pub struct FileHandle<S: SectorSize, V: Volume<u8, S>> {
+    inode: Inode<S, V>,
+    position: Cell<u64>,
+}
+
+impl<S: SectorSize, V: Volume<u8, S>> FileHandle<S, V> {
+    pub fn new(inode: Inode<S, V>) -> FileHandle<S, V> {
+        FileHandle {
+            inode,
+            position: Cell::new(0),
         }
+    }
 
-        Ok(offset)
+    pub fn inode(&self) -> &Inode<S, V> {
+        &self.inode
     }
 
-    fn write(&mut self, _buf: &[u8]) -> Result<usize, Self::Error> {
-        unimplemented!()
+    pub fn into_inode(self) -> Inode<S, V> {
+        self.inode
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position.get()
+    }
+
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        self.inode.read_at(offset, buf)
+    }
+
+    pub fn write_at(
+        &mut self,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<usize, Error> {
+        self.inode.write_at(offset, buf)
+    }
+}
+
+impl<S: SectorSize, V: Volume<u8, S>> File for FileHandle<S, V> {
+    type Error = Error;
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let position = self.position.get();
+        let read = self.inode.read_at(position, buf)?;
+        self.position.set(position + read as u64);
+        Ok(read)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let position = self.position.get();
+        let written = self.inode.write_at(position, buf)?;
+        self.position.set(position + written as u64);
+        Ok(written)
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
-        unimplemented!()
+        self.inode.flush()
     }
 
-    fn seek(&mut self, _pos: SeekFrom) -> Result<u64, Self::Error> {
-        unimplemented!()
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position.set(offset);
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.inode.size64(), offset),
+            SeekFrom::Current(offset) => (self.position.get(), offset),
+        };
+
+        let position = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+
+        match position {
+            Some(position) => {
+                self.position.set(position);
+                Ok(position)
+            }
+            None => Err(Error::BadSeek { base, offset }),
+        }
+    }
+}
+
+/// Location of a logical block in the block pointer tree of an inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockPath {
+    Direct(usize),
+    Indirect(usize),
+    Doubly(usize, usize),
+    Triply(usize, usize, usize),
+}
+
+impl BlockPath {
+    fn new(mut index: usize, log_block_size: u32) -> Option<BlockPath> {
+        // a block holds 2^(log_block_size - 2) 32-bit pointers
+        let log_bs4 = log_block_size - 2;
+        let bs4 = 1 << log_bs4;
+        let mask = bs4 - 1;
+
+        if index < 12 {
+            return Some(BlockPath::Direct(index));
+        }
+
+        index -= 12;
+
+        if index < bs4 {
+            return Some(BlockPath::Indirect(index));
+        }
+
+        index -= bs4;
+
+        if index < bs4 * bs4 {
+            return Some(BlockPath::Doubly(index >> log_bs4, index & mask));
+        }
+
+        index -= bs4 * bs4;
+
+        if index < bs4 * bs4 * bs4 {
+            return Some(BlockPath::Triply(
+                index >> (2 * log_bs4),
+                (index >> log_bs4) & mask,
+                index & mask,
+            ));
+        }
+
+        None
     }
 }
 
@@ -574,20 +2101,26 @@ impl<S: SectorSize, V: Volume<u8, S>> File for Inode<S, V> {
 pub struct InodeBlocks<S: SectorSize, V: Volume<u8, S>> {
     inode: Inode<S, V>,
     index: usize,
+    /// Number of blocks covering the size of the inode
+    count: usize,
 }
 
 impl<S: SectorSize, V: Volume<u8, S>> Iterator for InodeBlocks<S, V> {
-    type Item = Result<(Vec<u8>, Address<S>), Error>;
+    type Item = Result<Option<(Vec<u8>, Address<S>)>, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.count {
+            return None;
+        }
+
         let block = self.inode.try_block(self.index);
+        self.index += 1;
         let block = match block {
             Ok(Some(ok)) => ok,
-            Ok(None) => return None,
+            Ok(None) => return Some(Ok(None)),
             Err(err) => return Some(Err(err)),
         };
 
-        self.index += 1;
         let fs = self.inode.fs.inner();
 
         let block = block.get();
@@ -597,10 +2130,15 @@ impl<S: SectorSize, V: Volume<u8, S>> Iterator for InodeBlocks<S, V> {
 
         let slice = fs.volume
             .slice(offset..end)
-            .map(|slice| (slice.to_vec(), offset))
+            .map(|slice| Some((slice.to_vec(), offset)))
             .map_err(|err| err.into());
         Some(slice)
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.count.saturating_sub(self.index);
+        (remaining, Some(remaining))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -609,64 +2147,105 @@ pub struct Directory<S: SectorSize, V: Volume<u8, S>> {
     offset: usize,
     buffer: Option<Vec<u8>>,
     block_size: usize,
+    /// Number of blocks read so far
+    index: usize,
+    corrupt: bool,
 }
 
-impl<S: SectorSize, V: Volume<u8, S>> Dir<DirectoryEntry, Error>
+impl<S: SectorSize, V: Volume<u8, S>> Dir<DirectoryEntry<S, V>, Error>
     for Directory<S, V>
 {
 }
 
 impl<S: SectorSize, V: Volume<u8, S>> Iterator for Directory<S, V> {
-    type Item = Result<DirectoryEntry, Error>;
+    type Item = Result<DirectoryEntry<S, V>, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.buffer.is_none() || self.offset >= self.block_size {
-            self.buffer = match self.blocks.next() {
-                None => return None,
-                Some(Ok((block, _))) => Some(block),
-                Some(Err(err)) => return Some(Err(err)),
-            };
-
-            self.offset = 0;
-        }
-
-        let buffer = &self.buffer.as_ref().unwrap()[self.offset..];
+        loop {
+            if self.corrupt {
+                return None;
+            }
 
-        let inode = buffer[0] as u32 | (buffer[1] as u32) << 8
-            | (buffer[2] as u32) << 16
-            | (buffer[3] as u32) << 24;
-        if inode == 0 {
-            return None;
-        }
+            if self.buffer.is_none() || self.offset >= self.block_size {
+                self.buffer = match self.blocks.next() {
+                    None => return None,
+                    // a hole holds no entries
+                    Some(Ok(None)) => {
+                        self.index += 1;
+                        continue;
+                    }
+                    Some(Ok(Some((block, _)))) => Some(block),
+                    Some(Err(err)) => return Some(Err(err)),
+                };
 
-        let size = buffer[4] as u16 | (buffer[5] as u16) << 8;
-        let len = buffer[6];
-        let ty = buffer[7];
+                self.offset = 0;
+                self.index += 1;
+            }
 
-        let name = buffer[8..8 + len as usize].to_vec();
+            let buffer = self.buffer.as_ref().unwrap();
+            let record = match dir::read_record(buffer, self.offset) {
+                Some(record) => record,
+                None => {
+                    // nothing after a corrupt record can be trusted
+                    self.corrupt = true;
+                    return Some(Err(Error::CorruptDirectory {
+                        inode: self.blocks.inode.num,
+                        block: self.index - 1,
+                        offset: self.offset,
+                    }));
+                }
+            };
+            self.offset += record.rec_len;
 
-        self.offset += size as usize;
+            // deleted records are cleared if they can't be merged into the
+            // previous one
+            if record.inode == 0 {
+                continue;
+            }
 
-        Some(Ok(DirectoryEntry {
-            name: name,
-            inode: inode as usize,
-            ty: ty,
-        }))
+            return Some(Ok(DirectoryEntry {
+                name: record.name(buffer).to_vec(),
+                inode: record.inode as usize,
+                ty: record.ty,
+                fs: self.blocks.inode.fs.clone(),
+            }));
+        }
     }
 }
 
-#[derive(Clone)]
-pub struct DirectoryEntry {
+pub struct DirectoryEntry<S: SectorSize, V: Volume<u8, S>> {
     pub name: Vec<u8>,
     pub inode: usize,
     pub ty: u8,
+    fs: Synced<Ext2<S, V>>,
+}
+
+impl<S: SectorSize, V: Volume<u8, S>> Clone for DirectoryEntry<S, V> {
+    fn clone(&self) -> Self {
+        DirectoryEntry {
+            name: self.name.clone(),
+            inode: self.inode,
+            ty: self.ty,
+            fs: self.fs.clone(),
+        }
+    }
+}
+
+impl<S: SectorSize, V: Volume<u8, S>> Debug for DirectoryEntry<S, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DirectoryEntry")
+            .field("name", &String::from_utf8_lossy(&self.name))
+            .field("inode", &self.inode)
+            .field("ty", &self.ty)
+            .finish()
+    }
 }
 
-impl DirEntry for DirectoryEntry {
+impl<S: SectorSize, V: Volume<u8, S>> DirEntry for DirectoryEntry<S, V> {
     type Path = [u8];
     type PathOwned = Vec<u8>;
-    type Metadata = (); // TODO
-    type FileType = u8; // TODO: enum FileType
+    type Metadata = Metadata;
+    type FileType = FileType;
     type Error = Error;
 
     fn path(&self) -> Self::PathOwned {
@@ -674,11 +2253,28 @@ impl DirEntry for DirectoryEntry {
     }
 
     fn metadata(&self) -> Result<Self::Metadata, Self::Error> {
-        unimplemented!()
+        self.fs
+            .try_inode_nth(self.inode)
+            .map(|inode| inode.metadata())
     }
 
+    /// The type stored in the entry itself, or the one of the inode it
+    /// points to if the filesystem doesn't keep types in directory entries
     fn file_type(&self) -> Result<Self::FileType, Self::Error> {
-        Ok(self.ty)
+        let ty = FileType::from_entry_type(self.ty);
+        let features = {
+            let fs = self.fs.inner();
+            fs.superblock.inner.features_req
+        };
+        if features.contains(FeaturesRequired::REQ_DIRECTORY_TYPE)
+            && ty != FileType::Unknown
+        {
+            return Ok(ty);
+        }
+
+        self.fs
+            .try_inode_nth(self.inode)
+            .map(|inode| inode.file_type())
     }
 
     fn file_name(&self) -> &Self::Path {
@@ -693,6 +2289,8 @@ mod tests {
 
     use genfs::{File as GenFile, Fs, OpenOptions};
 
+    use error::Error;
+    use fs::mount::MountOptions;
     use sector::{SectorSize, Size512};
     use volume::Volume;
 
@@ -730,7 +2328,10 @@ mod tests {
 
         let fs = fs.unwrap();
 
-        let inodes = fs.inodes().filter(|inode| inode.in_use());
+        let inodes = fs
+            .inodes()
+            .map(|inode| inode.unwrap())
+            .filter(|inode| inode.in_use());
         for inode in inodes {
             println!("{:?}", inode);
         }
@@ -742,14 +2343,14 @@ mod tests {
         let file = RefCell::new(File::open("ext2.img").unwrap());
         let fs = Synced::<Ext2<Size512, _>>::new(file).unwrap();
 
-        let inodes = fs.inodes().filter(|inode| {
+        let inodes = fs.inodes().map(|inode| inode.unwrap()).filter(|inode| {
             inode.in_use() && inode.uid() == 1000 && inode.size() < 1024
         });
         for inode in inodes {
             println!("{:?}", inode);
             let size = inode.size();
             for block in inode.blocks() {
-                let (data, _) = block.unwrap();
+                let (data, _) = block.unwrap().unwrap();
                 assert_eq!(data.len(), {
                     let fs = fs.inner();
                     fs.block_size()
@@ -766,7 +2367,7 @@ mod tests {
         let file = RefCell::new(File::open("ext2.img").unwrap());
         let fs = Synced::<Ext2<Size512, _>>::new(file).unwrap();
 
-        let inodes = fs.inodes().filter(|inode| {
+        let inodes = fs.inodes().map(|inode| inode.unwrap()).filter(|inode| {
             inode.in_use() && inode.uid() == 1000 && inode.size() < 1024
         });
         for inode in inodes {
@@ -789,7 +2390,7 @@ mod tests {
         let file = RefCell::new(File::open("ext2.img").unwrap());
         let fs = Synced::<Ext2<Size512, _>>::new(file).unwrap();
 
-        let inodes = fs.inodes().filter(|inode| {
+        let inodes = fs.inodes().map(|inode| inode.unwrap()).filter(|inode| {
             inode.in_use() && inode.uid() == 1000 && inode.size() == 537600
         });
         for inode in inodes {
@@ -844,7 +2445,7 @@ mod tests {
         let file = RefCell::new(File::open("ext2.img").unwrap());
         let fs = Synced::<Ext2<Size512, _>>::new(file).unwrap();
 
-        let root = fs.root_inode();
+        let root = fs.root_inode().unwrap();
         walk(&fs, root, String::new());
     }
 
@@ -857,9 +2458,666 @@ mod tests {
         let found = fs.open(b"/home/funky/README.md", &OpenOptions::new());
 
         assert!(found.is_ok());
-        let inode = found.unwrap();
+        let file = found.unwrap();
         let mut vec = Vec::new();
-        assert!(inode.read_to_end(&mut vec).is_ok());
+        assert!(file.inode().read_to_end(&mut vec).is_ok());
         println!("{}", str::from_utf8(&vec).unwrap());
     }
+
+    #[test]
+    fn block_path() {
+        use super::BlockPath;
+
+        assert_eq!(BlockPath::new(11, 10), Some(BlockPath::Direct(11)));
+        assert_eq!(BlockPath::new(12, 10), Some(BlockPath::Indirect(0)));
+        assert_eq!(BlockPath::new(12 + 256, 10), Some(BlockPath::Doubly(0, 0)));
+        assert_eq!(
+            BlockPath::new(12 + 256 + 257, 10),
+            Some(BlockPath::Doubly(1, 1))
+        );
+        assert_eq!(
+            BlockPath::new(12 + 256 + 256 * 256, 10),
+            Some(BlockPath::Triply(0, 0, 0))
+        );
+        assert!(BlockPath::new(12 + 1024 + 1024 * 1024, 12).is_some());
+        assert_eq!(
+            BlockPath::new(12 + 256 + 256 * 256 + 256 * 256 * 256, 10),
+            None
+        );
+    }
+
+    #[test]
+    fn write_errors() {
+        use core::ops::Range;
+        use std::io::{self, Read};
+
+        use error::Error;
+        use sector::Address;
+        use volume::{VolumeCommit, VolumeSlice};
+        use volume::size::Size;
+
+        /// Fails every write of data starting with `FAIL`
+        struct Failing(Vec<u8>);
+
+        impl Volume<u8, Size512> for Failing {
+            type Error = Error;
+
+            fn size(&self) -> Size<Size512> {
+                self.0.size()
+            }
+
+            fn commit(
+                &mut self,
+                slice: Option<VolumeCommit<u8, Size512>>,
+            ) -> Result<(), Error> {
+                match slice {
+                    Some(ref slice) if slice.starts_with(b"FAIL") => {
+                        return Err(Error::Io {
+                            inner: io::Error::new(io::ErrorKind::Other, "fail"),
+                        })
+                    }
+                    _ => (),
+                }
+                self.0.commit(slice)
+            }
+
+            unsafe fn slice_unchecked<'a>(
+                &'a self,
+                range: Range<Address<Size512>>,
+            ) -> VolumeSlice<'a, u8, Size512> {
+                self.0.slice_unchecked(range)
+            }
+
+            fn slice<'a>(
+                &'a self,
+                range: Range<Address<Size512>>,
+            ) -> Result<VolumeSlice<'a, u8, Size512>, Error> {
+                self.0.slice(range)
+            }
+        }
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+        let fs = Synced::<Ext2<Size512, _>>::mount(
+            Failing(volume),
+            MountOptions::new().read_write(true),
+        ).unwrap();
+
+        let mut file = fs.open(b"/home/funky/README.md", &OpenOptions::new())
+            .unwrap();
+        assert!(file.inode().try_block(5).unwrap().is_none());
+        assert!(file.write_at(5 * 1024, b"FAIL").is_err());
+
+        // the block allocated before the failure is owned by the inode on
+        // the volume, not leaked
+        let file = fs.open(b"/home/funky/README.md", &OpenOptions::new())
+            .unwrap();
+        assert!(file.inode().try_block(5).unwrap().is_some());
+    }
+
+
+    #[test]
+    fn large_files() {
+        use std::io::Read;
+
+        use sys::superblock::FeaturesROnly;
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+        let fs = Synced::<Ext2<Size512, _>>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap();
+        {
+            let mut fs = fs.inner();
+            let features = fs.superblock.inner.features_ronly;
+            fs.superblock.inner.features_ronly =
+                features - FeaturesROnly::RONLY_FILE_SIZE_64;
+        }
+
+        let mut file = fs.open(b"/home/funky/README.md", &OpenOptions::new())
+            .unwrap();
+        assert_eq!(file.write_at(1024, b"data").unwrap(), 4);
+        let features = fs.inner().superblock.inner.features_ronly;
+        assert!(!features.contains(FeaturesROnly::RONLY_FILE_SIZE_64));
+
+        assert_eq!(file.write_at(1 << 31, b"data").unwrap(), 4);
+        assert_eq!(file.inode().size64(), (1 << 31) + 4);
+        let features = fs.inner().superblock.inner.features_ronly;
+        assert!(features.contains(FeaturesROnly::RONLY_FILE_SIZE_64));
+
+        let volume = fs.inner().volume.clone();
+        let on_disk = Ext2::<Size512, _>::new(volume).unwrap();
+        let features = on_disk.superblock.inner.features_ronly;
+        assert!(features.contains(FeaturesROnly::RONLY_FILE_SIZE_64));
+    }
+
+    #[test]
+    fn modification_times() {
+        use std::io::Read;
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+        let fs = Synced::<Ext2<Size512, _>>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap();
+        let time = fs.inner().timestamp();
+
+        let mut inode = fs.find_inode(b"/home/funky/README.md").unwrap();
+        inode.inner.mtime = 0;
+        inode.inner.ctime = 0;
+        assert_eq!(inode.write_at(1024, b"data").unwrap(), 4);
+        let inode = fs.find_inode(b"/home/funky/README.md").unwrap();
+        assert_eq!({ inode.inner.mtime }, time);
+        assert_eq!({ inode.inner.ctime }, time);
+
+        // directories are modified when entries are added or removed
+        let mut root = fs.root_inode().unwrap();
+        root.inner.mtime = 0;
+        root.add_entry(b"file", inode.num(), 0).unwrap();
+        assert_eq!({ fs.root_inode().unwrap().inner.mtime }, time);
+        let mut root = fs.root_inode().unwrap();
+        root.inner.ctime = 0;
+        root.remove_entry(b"file").unwrap();
+        assert_eq!({ fs.root_inode().unwrap().inner.ctime }, time);
+    }
+
+    #[test]
+    fn write() {
+        use std::io::Read;
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+        let fs = Synced::<Ext2<Size512, _>>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap();
+
+        let mut inode = fs.find_inode(b"/home/funky/README.md").unwrap();
+        let free_blocks = fs.inner().free_block_count();
+
+        // spans direct, indirect and doubly indirect blocks
+        let data = (0..300 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        assert_eq!(inode.write(&data).unwrap(), data.len());
+        assert_eq!(inode.size(), data.len());
+        assert!(fs.inner().free_block_count() < free_blocks);
+
+        let inode = fs.find_inode(b"/home/funky/README.md").unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(inode.read_to_end(&mut buf).unwrap(), data.len());
+        assert!(buf == data);
+    }
+
+    #[test]
+    fn seek() {
+        use std::io::Read;
+
+        use genfs::SeekFrom;
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+        let fs = Synced::<Ext2<Size512, _>>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap();
+
+        let mut file = fs.open(b"/home/funky/README.md", &OpenOptions::new())
+            .unwrap();
+        let data = (0..64 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        assert_eq!(file.write(&data).unwrap(), data.len());
+        assert_eq!(file.position(), data.len() as u64);
+
+        assert_eq!(file.seek(SeekFrom::Start(1000)).unwrap(), 1000);
+        let mut buf = [0; 100];
+        assert_eq!(file.read(&mut buf).unwrap(), 100);
+        assert!(&buf[..] == &data[1000..1100]);
+        assert_eq!(file.position(), 1100);
+
+        assert_eq!(file.seek(SeekFrom::Current(-100)).unwrap(), 1000);
+        let size = file.inode().size64();
+        assert_eq!(file.seek(SeekFrom::End(-10)).unwrap(), size - 10);
+        assert_eq!(file.read(&mut buf).unwrap(), 10);
+        assert!(file.seek(SeekFrom::Current(-(size as i64) - 1)).is_err());
+
+        assert_eq!(file.write_at(50 * 1024, &[0xff; 3]).unwrap(), 3);
+        assert_eq!(file.read_at(50 * 1024 - 1, &mut buf[..5]).unwrap(), 5);
+        assert!(&buf[..5] == &[(50 * 1024 - 1) as u8, 0xff, 0xff, 0xff, 3]);
+    }
+
+    #[test]
+    fn directories() {
+        use std::io::Read;
+
+        use genfs::DirOptions;
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+        let mut fs = Synced::<Ext2<Size512, _>>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap();
+        let free_inodes = fs.inner().superblock.inner.free_inodes_count;
+        let root_links = fs.root_inode().unwrap().hard_links();
+
+        assert!(fs.create_dir(b"/foo", &DirOptions::new()).is_ok());
+        assert!(fs.create_dir(b"/foo", &DirOptions::new()).is_err());
+        assert!(
+            fs.create_dir(b"/foo/bar/baz", DirOptions::new().recursive(true))
+                .is_ok()
+        );
+        assert_eq!(fs.root_inode().unwrap().hard_links(), root_links + 1);
+        assert_eq!(fs.find_inode(b"/foo").unwrap().hard_links(), 3);
+
+        let baz = fs.find_inode(b"/foo/bar/baz").unwrap();
+        let parent = baz.find_entry(b"..").unwrap().unwrap();
+        assert_eq!(
+            parent.inode as u32,
+            fs.find_inode(b"/foo/bar").unwrap().num()
+        );
+
+        assert!(fs.rename(b"/foo/bar/baz", b"/baz").is_ok());
+        assert!(fs.find_inode(b"/foo/bar/baz").is_err());
+        let baz = fs.find_inode(b"/baz").unwrap();
+        assert_eq!(baz.find_entry(b"..").unwrap().unwrap().inode, 2);
+        assert_eq!(fs.root_inode().unwrap().hard_links(), root_links + 2);
+        assert!(fs.rename(b"/foo", b"/foo/bar/foo").is_err());
+        assert!(fs.rename(b"/foo/", b"/foo/bar/foo").is_err());
+        assert!(fs.rename(b"//foo", b"/foo/bar/foo").is_err());
+        assert!(fs.rename(b"/./foo", b"/foo/bar/foo").is_err());
+        assert!(fs.find_inode(b"/foo/bar").is_ok());
+
+        assert!(fs.rename(b"/home/funky/README.md", b"/foo/README").is_ok());
+        assert!(fs.find_inode(b"/foo/README").is_ok());
+        assert!(fs.remove_dir(b"/foo").is_err());
+        assert!(fs.remove_file(b"/foo/README").is_ok());
+        assert!(fs.remove_file(b"/foo/README").is_err());
+
+        assert!(fs.remove_dir_all(b"/foo").is_ok());
+        assert!(fs.remove_dir(b"/baz").is_ok());
+        assert_eq!(fs.root_inode().unwrap().hard_links(), root_links);
+        assert!(fs.read_dir(b"/foo").is_err());
+        assert_eq!(
+            fs.inner().superblock.inner.free_inodes_count,
+            free_inodes + 1
+        );
+
+        // a directory whose first block can't be allocated is released
+        {
+            let mut fs = fs.inner();
+            for descr in fs.block_groups.inner.iter_mut() {
+                descr.free_blocks_count = 0;
+            }
+        }
+        assert!(fs.create_dir(b"/full", &DirOptions::new()).is_err());
+        assert!(fs.find_inode(b"/full").is_err());
+        assert_eq!(
+            fs.inner().superblock.inner.free_inodes_count,
+            free_inodes + 1
+        );
+    }
+
+    #[test]
+    fn metadata() {
+        use genfs::DirEntry;
+        use sys::inode::TypePerm;
+
+        let file = RefCell::new(File::open("ext2.img").unwrap());
+        let fs = Synced::<Ext2<Size512, _>>::new(file).unwrap();
+
+        let root = fs.metadata(b"/").unwrap();
+        assert_eq!(root.inode(), 2);
+        assert_eq!(root.file_type(), TypePerm::DIRECTORY);
+        assert!(root.hard_links() >= 2);
+        // the image has 128-byte inodes, without an extended part
+        assert!(fs.root_inode().unwrap().extra().is_none());
+        assert_eq!(fs.root_inode().unwrap().xattrs().unwrap(), vec![]);
+        assert_eq!(root.birth(), None);
+
+        let readme = fs.metadata(b"/home/funky/README.md").unwrap();
+        assert_eq!(readme.file_type(), TypePerm::FILE);
+        assert_eq!(readme.uid(), 1000);
+        assert_eq!(
+            readme.len(),
+            fs.find_inode(b"/home/funky/README.md").unwrap().size64()
+        );
+
+        for entry in fs.read_dir(b"/home/funky").unwrap() {
+            let entry = entry.unwrap();
+            assert_eq!(entry.metadata().unwrap().inode(), entry.inode as u32);
+        }
+    }
+
+    #[test]
+    fn symlinks() {
+        use std::io::Read;
+
+        use sys::inode::TypePerm;
+
+        let mut volume = Vec::new();
+        File::open("ext2.img")
+            .unwrap()
+            .read_to_end(&mut volume)
+            .unwrap();
+        let mut fs = Synced::<Ext2<Size512, _>>::mount(
+            volume,
+            MountOptions::new().read_write(true),
+        ).unwrap();
+
+        let readme = b"/home/funky/README.md";
+        assert!(fs.symlink(readme, b"/fast").is_ok());
+        assert!(fs.symlink(readme, b"/fast").is_err());
+        let mut fast = fs.find_inode_nofollow(b"/fast").unwrap();
+        assert!(fast.is_fast_symlink());
+        assert_eq!(fs.read_link(b"/fast").unwrap(), &readme[..]);
+        let mut buf = [0; 64];
+        assert_eq!(fast.read_at(0, &mut buf).unwrap(), readme.len());
+        assert_eq!(&buf[..readme.len()], &readme[..]);
+        assert_eq!(fast.read_at(6, &mut buf[..5]).unwrap(), 5);
+        assert_eq!(&buf[..5], b"funky");
+        assert!(fast.write_at(0, b"/etc").is_err());
+
+        let mut long = b"/home/funky".to_vec();
+        for _ in 0..40 {
+            long.extend_from_slice(b"/.");
+        }
+        long.extend_from_slice(b"/README.md");
+        assert!(fs.symlink(&long, b"/slow").is_ok());
+        let slow = fs.find_inode_nofollow(b"/slow").unwrap();
+        assert!(slow.is_symlink() && !slow.is_fast_symlink());
+        assert_eq!(fs.read_link(b"/slow").unwrap(), long);
+
+        let target = fs.find_inode(readme).unwrap().num();
+        assert_eq!(fs.find_inode(b"/fast").unwrap().num(), target);
+        assert_eq!(fs.find_inode(b"/slow").unwrap().num(), target);
+        assert_eq!(fs.metadata(b"/fast").unwrap().inode(), target);
+        assert_eq!(
+            fs.symlink_metadata(b"/fast").unwrap().file_type(),
+            TypePerm::SYMLINK
+        );
+        assert!(fs.read_link(readme).is_err());
+
+        assert!(fs.symlink(b"funky", b"/home/relative").is_ok());
+        assert_eq!(
+            fs.find_inode(b"/home/relative/README.md").unwrap().num(),
+            target
+        );
+
+        assert!(fs.symlink(b"/loop_b", b"/loop_a").is_ok());
+        assert!(fs.symlink(b"/loop_a", b"/loop_b").is_ok());
+        match fs.find_inode(b"/loop_a") {
+            Err(Error::SymlinkLoop { .. }) => (),
+            _ => panic!("symlink loop not detected"),
+        }
+        assert!(fs.symlink_metadata(b"/loop_a").is_ok());
+
+        assert!(fs.rename(b"/home/funky", b"/home/relative/funky").is_err());
+
+        // removing a link to a directory leaves the directory alone
+        match fs.remove_dir(b"/home/relative") {
+            Err(Error::NotADirectory { .. }) => (),
+            _ => panic!("link to a directory removed as a directory"),
+        }
+        assert!(fs.remove_dir_all(b"/home/relative").is_err());
+        assert!(fs.find_inode(b"/home/relative/README.md").is_ok());
+
+        assert!(fs.remove_file(b"/slow").is_ok());
+        assert!(fs.remove_file(b"/fast").is_ok());
+        assert!(fs.remove_file(b"/home/relative").is_ok());
+        assert!(fs.find_inode(readme).is_ok());
+
+        // a link whose target can't be stored is released
+        let free_inodes = fs.inner().superblock.inner.free_inodes_count;
+        {
+            let mut fs = fs.inner();
+            for descr in fs.block_groups.inner.iter_mut() {
+                descr.free_blocks_count = 0;
+            }
+        }
+        assert!(fs.symlink(&[b'/'; 100], b"/link").is_err());
+        assert!(fs.find_inode_nofollow(b"/link").is_err());
+        assert_eq!(
+            fs.inner().superblock.inner.free_inodes_count,
+            free_inodes
+        );
+    }
+
+    #[test]
+    fn foreign_index() {
+        use genfs::DirOptions;
+
+        use fs::dir;
+        use fs::mkfs::FormatOptions;
+        use fs::mkfs::tests::{formatted, mount_rw};
+        use sys::inode::{Flags, TypePerm};
+        use sys::superblock::FeaturesOptional;
+
+        // a zero UUID seeds the hashes with zeros: with half-MD4, "a" hashes
+        // to 0xd5fa7d7a and "lost+found" to 0x591de422
+        let mut options = FormatOptions::new();
+        let mut features = options.features;
+        features.optional.insert(FeaturesOptional::HASH_INDEX);
+        options.features(features).uuid([0; 16]);
+        let mut fs = mount_rw(formatted(1024 * 1024, &options));
+        assert!(fs.create_dir(b"/indexed", &DirOptions::new()).is_ok());
+        let mut indexed = fs.find_inode(b"/indexed").unwrap();
+        let file = fs.create_inode(&indexed, TypePerm::FILE).unwrap();
+        let decoy = fs.create_inode(&indexed, TypePerm::FILE).unwrap();
+        let lost = fs.create_inode(&indexed, TypePerm::FILE).unwrap();
+
+        // the root as Linux lays it out, indexing the hashes below
+        // 0x80000000 into block 1 and the rest into block 2
+        let mut blocks = vec![0; 3 * 1024];
+        {
+            let root = &mut blocks[..1024];
+            dir::write_u32(root, 0, indexed.num);
+            dir::write_u16(root, 4, 12);
+            root[6] = 1;
+            root[7] = 2;
+            root[8] = b'.';
+            dir::write_u32(root, 12, 2);
+            dir::write_u16(root, 16, 1024 - 12);
+            root[18] = 2;
+            root[19] = 2;
+            root[20..22].copy_from_slice(b"..");
+            // reserved, hash version, info length, indirect levels, flags
+            root[28] = 1;
+            root[29] = 8;
+            // limit, count and block of the first entry
+            dir::write_u16(root, 32, (1024 - 32) / 8);
+            dir::write_u16(root, 34, 2);
+            dir::write_u32(root, 36, 1);
+            dir::write_u32(root, 40, 0x8000_0000);
+            dir::write_u32(root, 44, 2);
+        }
+        {
+            // a linear search would find the decoy in block 1 first
+            let lower = &mut blocks[1024..2 * 1024];
+            dir::write_record(lower, 0, decoy.num, 12, b"a", 1);
+            dir::write_record(lower, 12, lost.num, 1012, b"lost+found", 1);
+        }
+        dir::write_record(&mut blocks[2 * 1024..], 0, file.num, 1024, b"a", 1);
+
+        assert_eq!(indexed.write_at(0, &blocks).unwrap(), blocks.len());
+        // EXT2_INDEX_FL
+        indexed.raw_mut().flags = Flags::from_bits_truncate(0x1000);
+        indexed.commit().unwrap();
+
+        let indexed = fs.find_inode(b"/indexed").unwrap();
+        let entry = indexed.find_entry(b"a").unwrap().unwrap();
+        assert_eq!(entry.inode, file.num as usize);
+        let entry = indexed.find_entry(b"lost+found").unwrap().unwrap();
+        assert_eq!(entry.inode, lost.num as usize);
+        assert!(indexed.find_entry(b"missing").unwrap().is_none());
+        assert_eq!(fs.find_inode(b"/indexed/a").unwrap().num, file.num);
+    }
+
+    #[test]
+    fn indexed_directories() {
+        use genfs::DirOptions;
+
+        use fs::check::check;
+        use fs::mkfs::FormatOptions;
+        use fs::mkfs::tests::{formatted, mount_rw};
+        use sys::inode::Flags;
+        use sys::superblock::FeaturesOptional;
+
+        let mut options = FormatOptions::new();
+        let mut features = options.features;
+        features.optional.insert(FeaturesOptional::HASH_INDEX);
+        options.features(features).uuid([0x5a; 16]);
+        let mut fs = mount_rw(formatted(4 * 1024 * 1024, &options));
+        assert!(fs.create_dir(b"/big", &DirOptions::new()).is_ok());
+        let names = (0..300)
+            .map(|index| format!("/big/directory-{}", index))
+            .collect::<Vec<_>>();
+        for name in names.iter() {
+            assert!(fs.create_dir(name.as_bytes(), &DirOptions::new()).is_ok());
+        }
+
+        // converted once it outgrew its first block, then split further
+        let big = fs.find_inode(b"/big").unwrap();
+        let flags = big.raw().flags;
+        assert!(flags.contains(Flags::HASH_DIR));
+        assert!(big.size() > 4 * 1024);
+        assert_eq!(big.directory().unwrap().count(), 302);
+        for name in names.iter() {
+            assert!(fs.find_inode(name.as_bytes()).unwrap().is_dir());
+        }
+        assert!(fs.find_inode(b"/big/directory-300").is_err());
+
+        assert!(fs.remove_dir(b"/big/directory-7").is_ok());
+        assert!(fs.find_inode(b"/big/directory-7").is_err());
+        assert!(fs.find_inode(b"/big/directory-8").is_ok());
+        let options = DirOptions::new();
+        assert!(fs.create_dir(b"/big/directory-7", &options).is_ok());
+        assert!(fs.find_inode(b"/big/directory-7").is_ok());
+
+        let report = check(&fs).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn sparse() {
+        use fs::mkfs::tests::scratch;
+        use sys::inode::TypePerm;
+
+        let fs = scratch();
+
+        let root = fs.root_inode().unwrap();
+        let mut inode = fs.create_inode(&root, TypePerm::FILE).unwrap();
+        // leaves holes before and after the last direct block, the second
+        // one spanning into the indirect blocks
+        assert_eq!(inode.write_at(2 * 1024 + 10, b"data").unwrap(), 4);
+        assert_eq!(inode.write_at(20 * 1024, b"end").unwrap(), 3);
+
+        let data = inode
+            .blocks()
+            .enumerate()
+            .filter(|&(_, ref block)| block.as_ref().unwrap().is_some())
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        assert_eq!(inode.blocks().count(), 21);
+        assert_eq!(data, [2, 20]);
+
+        let mut buf = vec![0xff_u8; inode.size()];
+        assert_eq!(inode.read_at(0, &mut buf).unwrap(), buf.len());
+        assert!(buf[..2 * 1024 + 10].iter().all(|&x| x == 0));
+        assert_eq!(&buf[2 * 1024 + 10..2 * 1024 + 14], b"data");
+        assert!(buf[2 * 1024 + 14..20 * 1024].iter().all(|&x| x == 0));
+        assert_eq!(&buf[20 * 1024..], b"end");
+    }
+
+    #[test]
+    fn file_types() {
+        use genfs::{DirEntry, DirOptions};
+
+        use fs::metadata::FileType;
+        use fs::mkfs::FormatOptions;
+        use fs::mkfs::tests::{formatted, mount_rw};
+        use sys::superblock::FeaturesRequired;
+
+        for &filetype in [true, false].iter() {
+            let mut options = FormatOptions::new();
+            let mut features = options.features;
+            features
+                .required
+                .set(FeaturesRequired::REQ_DIRECTORY_TYPE, filetype);
+            options.features(features);
+            let mut fs = mount_rw(formatted(1024 * 1024, &options));
+            assert!(fs.create_dir(b"/dir", &DirOptions::new()).is_ok());
+            assert!(fs.symlink(b"/dir", b"/link").is_ok());
+
+            for entry in fs.read_dir(b"/").unwrap() {
+                let entry = entry.unwrap();
+                let expected = match &entry.name[..] {
+                    b"link" => FileType::Symlink,
+                    _ => FileType::Directory,
+                };
+                assert_eq!(entry.ty != 0, filetype);
+                assert_eq!(entry.file_type().unwrap(), expected);
+            }
+            let link = fs.find_inode_nofollow(b"/link").unwrap();
+            assert!(link.is_symlink() && !link.is_dir());
+        }
+    }
+
+    #[test]
+    fn corrupt_directory() {
+        use genfs::DirOptions;
+
+        use fs::dir;
+        use fs::mkfs::tests::scratch;
+
+        let mut fs = scratch();
+        for name in [&b"/foo"[..], b"/foo/a", b"/foo/b", b"/foo/c"].iter() {
+            assert!(fs.create_dir(name, &DirOptions::new()).is_ok());
+        }
+
+        let foo = fs.find_inode(b"/foo").unwrap();
+        let block = foo.try_block(0).unwrap().unwrap().get();
+        let mut data = fs.inner().read_block(block).unwrap();
+
+        // clear `b` in the middle of the block instead of merging it into `a`
+        dir::write_u32(&mut data, 36, 0);
+        fs.inner().write_block(block, data.clone()).unwrap();
+        let names = foo.directory()
+            .unwrap()
+            .map(|entry| entry.unwrap().name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, [&b"."[..], b"..", b"a", b"c"]);
+
+        // `a` reaches past the end of the block
+        dir::write_u16(&mut data, 28, 2000);
+        fs.inner().write_block(block, data).unwrap();
+        let mut entries = foo.directory().unwrap();
+        assert!(entries.next().unwrap().is_ok());
+        assert!(entries.next().unwrap().is_ok());
+        match entries.next() {
+            Some(Err(Error::CorruptDirectory { block, offset, .. })) => {
+                assert_eq!((block, offset), (0, 24))
+            }
+            _ => panic!("expected a corrupt directory"),
+        }
+        assert!(entries.next().is_none());
+        assert!(fs.find_inode(b"/foo/c").is_err());
+    }
 }
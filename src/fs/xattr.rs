@@ -0,0 +1,142 @@
+//! Extended attributes stored in the body of inodes larger than 128 bytes,
+//! right after the `InodeExtra` structure
+
+use alloc::Vec;
+
+use error::Error;
+
+use super::dir::{read_u16, read_u32};
+
+/// Magic number at the start of the in-inode extended attribute area
+pub const MAGIC: u32 = 0xea02_0000;
+/// Size of the fixed part of an extended attribute entry
+const ENTRY_SIZE: usize = 16;
+
+/// A single extended attribute
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Xattr {
+    name_index: u8,
+    name: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Xattr {
+    pub fn new(name_index: u8, name: Vec<u8>, value: Vec<u8>) -> Xattr {
+        Xattr {
+            name_index,
+            name,
+            value,
+        }
+    }
+
+    /// Index of the name prefix, e.g. 1 for `user.`
+    pub fn name_index(&self) -> u8 {
+        self.name_index
+    }
+
+    /// The name without its prefix
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// The name with its prefix, e.g. `user.mime_type`
+    pub fn full_name(&self) -> Vec<u8> {
+        let prefix: &[u8] = match self.name_index {
+            1 => b"user.",
+            2 => b"system.posix_acl_access",
+            3 => b"system.posix_acl_default",
+            4 => b"trusted.",
+            6 => b"security.",
+            7 => b"system.",
+            8 => b"system.richacl",
+            _ => b"",
+        };
+        [prefix, &self.name].concat()
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+}
+
+/// Parses the extended attribute area of an inode body. Returns no
+/// attributes if the area doesn't start with `MAGIC`.
+pub fn parse_ibody(area: &[u8]) -> Result<Vec<Xattr>, Error> {
+    if area.len() < 4 || read_u32(area, 0) != MAGIC {
+        return Ok(Vec::new());
+    }
+
+    // value offsets are relative to the first entry
+    let entries = &area[4..];
+    let mut xattrs = Vec::new();
+    let mut offset = 0;
+
+    while offset + 4 <= entries.len() && read_u32(entries, offset) != 0 {
+        if offset + ENTRY_SIZE > entries.len() {
+            return Err(corrupt(offset));
+        }
+
+        let name_len = entries[offset] as usize;
+        let name_index = entries[offset + 1];
+        let value_offset = read_u16(entries, offset + 2) as usize;
+        let value_inode = read_u32(entries, offset + 4);
+        let value_size = read_u32(entries, offset + 8) as usize;
+
+        let name_start = offset + ENTRY_SIZE;
+        if name_start + name_len > entries.len() {
+            return Err(corrupt(offset));
+        }
+        let name = entries[name_start..name_start + name_len].to_vec();
+
+        // values stored in a separate inode are not supported
+        let value = if value_inode != 0 {
+            Vec::new()
+        } else if value_offset + value_size > entries.len() {
+            return Err(corrupt(offset));
+        } else {
+            entries[value_offset..value_offset + value_size].to_vec()
+        };
+
+        xattrs.push(Xattr::new(name_index, name, value));
+        offset = (name_start + name_len + 3) & !3;
+    }
+
+    Ok(xattrs)
+}
+
+fn corrupt(offset: usize) -> Error {
+    Error::Other(format!(
+        "corrupt in-inode extended attribute entry at offset {}",
+        offset
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use fs::dir::{write_u16, write_u32};
+    use super::*;
+
+    #[test]
+    fn ibody() {
+        let mut area = vec![0_u8; 96];
+        assert_eq!(parse_ibody(&area).unwrap(), vec![]);
+
+        write_u32(&mut area, 0, MAGIC);
+        let entries = &mut area[4..];
+        entries[0] = 4;
+        entries[1] = 1;
+        write_u16(entries, 2, 80);
+        write_u32(entries, 8, 5);
+        entries[16..20].copy_from_slice(b"test");
+        entries[80..85].copy_from_slice(b"value");
+
+        let xattrs = parse_ibody(&area).unwrap();
+        assert_eq!(xattrs.len(), 1);
+        assert_eq!(xattrs[0].name(), b"test");
+        assert_eq!(xattrs[0].full_name(), b"user.test".to_vec());
+        assert_eq!(xattrs[0].value(), b"value");
+
+        write_u16(&mut area[4..], 2, 90);
+        assert!(parse_ibody(&area).is_err());
+    }
+}
@@ -17,6 +17,8 @@ extern crate spin;
 
 #[cfg(any(test, not(feature = "no_std")))]
 extern crate core;
+#[cfg(all(target_os = "linux", any(test, not(feature = "no_std"))))]
+extern crate libc;
 
 pub mod error;
 pub mod sys;
@@ -36,5 +38,6 @@ mod tests {
         assert_eq!(size_of::<Superblock>(), 1024);
         assert_eq!(size_of::<BlockGroupDescriptor>(), 32);
         assert_eq!(size_of::<Inode>(), 128);
+        assert_eq!(size_of::<InodeExtra>(), 32);
     }
 }
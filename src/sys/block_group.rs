@@ -5,7 +5,7 @@ use alloc::Vec;
 
 use error::Error;
 use sector::{Address, SectorSize};
-use volume::Volume;
+use volume::{Volume, VolumeCommit, WriteClass};
 
 /// The Block Group Descriptor Table contains a descriptor for each block group
 /// within the file system. The number of block groups within the file system,
@@ -66,11 +66,10 @@ impl BlockGroupDescriptor {
             });
         }
 
-        let descr = haystack
-            .slice_unchecked(offset..end)
-            .dynamic_cast::<BlockGroupDescriptor>();
-
-        Ok(descr)
+        match haystack.slice(offset..end) {
+            Ok(slice) => Ok(slice.dynamic_cast::<BlockGroupDescriptor>()),
+            Err(err) => Err(err.into()),
+        }
     }
 
     pub unsafe fn find_descriptor_table<S: SectorSize, V: Volume<u8, S>>(
@@ -99,6 +98,35 @@ impl BlockGroupDescriptor {
 
         Ok((vec, offset))
     }
+
+    /// Writes the whole descriptor table to `offset`
+    pub fn store_descriptor_table<S: SectorSize, V: Volume<u8, S>>(
+        table: &[BlockGroupDescriptor],
+        haystack: &mut V,
+        offset: Address<S>,
+    ) -> Result<(), Error> {
+        let size = mem::size_of::<BlockGroupDescriptor>();
+        let end = offset + Address::from(table.len() * size);
+        if haystack.size() < end {
+            return Err(Error::AddressOutOfBounds {
+                sector: end.sector(),
+                offset: end.offset(),
+                size: end.sector_size(),
+            });
+        }
+
+        let mut data = Vec::with_capacity(table.len() * size);
+        for descr in table {
+            data.extend_from_slice(
+                &VolumeCommit::<u8, S>::from_cast(descr, offset).into_inner(),
+            );
+        }
+
+        let commit = VolumeCommit::new(data, offset);
+        haystack
+            .commit_as(Some(commit), WriteClass::Superblock)
+            .map_err(|err| err.into())
+    }
 }
 
 #[cfg(test)]
@@ -124,4 +152,28 @@ mod tests {
         let table = table.unwrap_or_else(|_| unreachable!());
         assert_eq!(table.0.len(), 8);
     }
+
+    #[test]
+    fn store() {
+        let mut volume = vec![0_u8; 4096];
+        let offset = Address::<Size512>::new(4, 0);
+        let mut table = unsafe {
+            BlockGroupDescriptor::find_descriptor_table(&volume, offset, 8)
+                .unwrap()
+                .0
+        };
+        table[1].inode_table_block = 0x1234;
+        table[7].dirs_count = 5;
+
+        assert!(
+            BlockGroupDescriptor::store_descriptor_table(
+                &table,
+                &mut volume,
+                offset,
+            ).is_ok()
+        );
+        assert_eq!(volume[2048 + 32 + 8], 0x34);
+        assert_eq!(volume[2048 + 32 + 9], 0x12);
+        assert_eq!(volume[2048 + 7 * 32 + 16], 5);
+    }
 }
@@ -1,10 +1,17 @@
 use core::mem;
+use core::slice;
 use core::fmt::{self, Debug};
 
 use error::Error;
 use sector::{Address, SectorSize};
 use volume::Volume;
 
+/// Size of the block pointer area of an inode, which fast symbolic links use
+/// to store their target in place
+pub const INLINE_DATA_SIZE: usize = 60;
+/// Offset of the block pointer area in an inode
+const INLINE_DATA_OFFSET: usize = 40;
+
 /// An inode is a structure on the disk that represents a file, directory,
 /// symbolic link, etc. Inodes do not contain the data of the file / directory /
 /// etc. that they represent. Instead, they link to the blocks that actually
@@ -23,7 +30,7 @@ pub struct Inode {
     pub size_low: u32,
     /// Last Access Time (in POSIX time)
     pub atime: u32,
-    /// Creation Time (in POSIX time)
+    /// Last Inode Change Time (in POSIX time)
     pub ctime: u32,
     /// Last Modification time (in POSIX time)
     pub mtime: u32,
@@ -102,11 +109,106 @@ impl Inode {
         offset: Address<S>,
         size: usize,
     ) -> Result<(Inode, Address<S>), Error> {
-        if size != mem::size_of::<Inode>() {
-            unimplemented!("inodes with a size != 128");
+        if size < mem::size_of::<Inode>() {
+            return Err(Error::BadInodeSize { size });
+        }
+
+        // only the first 128 bytes are common to all revisions, the rest is
+        // read by `InodeExtra::find_extra`
+        let end = offset + Address::from(mem::size_of::<Inode>());
+        if haystack.size() < end {
+            return Err(Error::AddressOutOfBounds {
+                sector: end.sector(),
+                offset: end.offset(),
+                size: end.sector_size(),
+            });
+        }
+
+        match haystack.slice(offset..end) {
+            Ok(slice) => Ok(slice.dynamic_cast::<Inode>()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The block pointers viewed as raw bytes, as used by fast symbolic links
+    pub fn inline_data(&self) -> &[u8] {
+        unsafe {
+            let ptr = (self as *const Inode as *const u8)
+                .offset(INLINE_DATA_OFFSET as isize);
+            slice::from_raw_parts(ptr, INLINE_DATA_SIZE)
+        }
+    }
+
+    pub fn inline_data_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            let ptr = (self as *mut Inode as *mut u8)
+                .offset(INLINE_DATA_OFFSET as isize);
+            slice::from_raw_parts_mut(ptr, INLINE_DATA_SIZE)
+        }
+    }
+}
+
+/// The extended part of an inode, following the first 128 bytes when the
+/// superblock's `inode_size` is larger than that. Only the first
+/// `extra_isize` bytes are in use, fields past it read as zero. The space
+/// between the end of this structure and the end of the inode holds
+/// in-inode extended attributes.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct InodeExtra {
+    /// Size of this structure in bytes
+    pub extra_isize: u16,
+    /// Upper 16 bits of the inode checksum
+    pub checksum_high: u16,
+    /// Extra change time bits: epoch in the lower 2 bits, nanoseconds in
+    /// the upper 30 bits
+    pub ctime_extra: u32,
+    /// Extra modification time bits (see `ctime_extra`)
+    pub mtime_extra: u32,
+    /// Extra access time bits (see `ctime_extra`)
+    pub atime_extra: u32,
+    /// File creation time (in POSIX time)
+    pub crtime: u32,
+    /// Extra file creation time bits (see `ctime_extra`)
+    pub crtime_extra: u32,
+    /// Upper 32 bits of the version
+    pub version_high: u32,
+    /// Project ID
+    pub project_id: u32,
+}
+
+impl Debug for InodeExtra {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InodeExtra")
+            .field("extra_isize", unsafe { &self.extra_isize })
+            .field("checksum_high", unsafe { &self.checksum_high })
+            .field("ctime_extra", unsafe { &self.ctime_extra })
+            .field("mtime_extra", unsafe { &self.mtime_extra })
+            .field("atime_extra", unsafe { &self.atime_extra })
+            .field("crtime", unsafe { &self.crtime })
+            .field("crtime_extra", unsafe { &self.crtime_extra })
+            .field("version_high", unsafe { &self.version_high })
+            .field("project_id", unsafe { &self.project_id })
+            .finish()
+    }
+}
+
+impl InodeExtra {
+    /// Reads the extended part of the inode at `offset`, which is `size`
+    /// bytes long. Returns `None` for 128-byte inodes and for inodes that
+    /// don't use the extended part.
+    pub unsafe fn find_extra<S: SectorSize, V: Volume<u8, S>>(
+        haystack: &V,
+        offset: Address<S>,
+        size: usize,
+    ) -> Result<Option<(InodeExtra, Address<S>)>, Error> {
+        let base_size = mem::size_of::<Inode>();
+        if size <= base_size {
+            return Ok(None);
         }
 
-        let end = offset + Address::from(size);
+        let offset = offset + Address::from(base_size);
+        let end = offset + Address::from(size - base_size);
         if haystack.size() < end {
             return Err(Error::AddressOutOfBounds {
                 sector: end.sector(),
@@ -115,11 +217,35 @@ impl Inode {
             });
         }
 
-        let inode = haystack
-            .slice_unchecked(offset..end)
-            .dynamic_cast::<Inode>();
+        let slice = match haystack.slice(offset..end) {
+            Ok(slice) => slice,
+            Err(err) => return Err(err.into()),
+        };
+        let extra_isize = slice[0] as usize | (slice[1] as usize) << 8;
+        if extra_isize < 4 || extra_isize > slice.len() {
+            return Ok(None);
+        }
 
-        Ok(inode)
+        let mut buf = [0_u8; 32];
+        let len = extra_isize.min(buf.len());
+        buf[..len].copy_from_slice(&slice[..len]);
+        let extra = mem::transmute::<[u8; 32], InodeExtra>(buf);
+        Ok(Some((extra, offset)))
+    }
+
+    /// Combines a timestamp field, read as signed seconds, with the epoch
+    /// bits of its extra field into seconds and nanoseconds. Without an
+    /// extra field, pass 0.
+    pub fn decode_time(seconds: u32, extra: u32) -> (i64, u32) {
+        let epoch = ((extra & 3) as i64) << 32;
+        (seconds as i32 as i64 + epoch, extra >> 2)
+    }
+
+    /// Splits seconds and nanoseconds into a timestamp field and its extra
+    /// field, the inverse of `decode_time`
+    pub fn encode_time(seconds: i64, nsec: u32) -> (u32, u32) {
+        let epoch = ((seconds - seconds as i32 as i64) >> 32) as u32 & 3;
+        (seconds as u32, nsec << 2 | epoch)
     }
 }
 
@@ -185,27 +311,27 @@ bitflags! {
         /// Last accessed time should not updated
         const DONT_ATIME = 0x00000080;
         /// Hash indexed directory
-        const HASH_DIR = 0x00010000;
+        const HASH_DIR = 0x00001000;
         /// AFS directory
-        const AFS_DIR = 0x00020000;
+        const AFS_DIR = 0x00002000;
         /// Journal file data
-        const JOURNAL_DATA = 0x00040000;
+        const JOURNAL_DATA = 0x00004000;
     }
 }
 
 /// Unknown entry type
 pub const UNKNOWN: u8 = 0;
-/// FIFO entry type
-pub const FIFO: u8 = 1;
-/// Character device entry type
-pub const CHAR_DEVICE: u8 = 2;
+/// Regular file entry type
+pub const FILE: u8 = 1;
 /// Directory entry type
-pub const DIRECTORY: u8 = 3;
+pub const DIRECTORY: u8 = 2;
+/// Character device entry type
+pub const CHAR_DEVICE: u8 = 3;
 /// Block device entry type
 pub const BLOCK_DEVICE: u8 = 4;
-/// Regular file entry type
-pub const FILE: u8 = 5;
-/// Symbolic link entry type
-pub const SYMLINK: u8 = 6;
+/// FIFO entry type
+pub const FIFO: u8 = 5;
 /// Unix socket entry type
-pub const SOCKET: u8 = 7;
+pub const SOCKET: u8 = 6;
+/// Symbolic link entry type
+pub const SYMLINK: u8 = 7;
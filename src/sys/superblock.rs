@@ -1,9 +1,11 @@
 use core::mem;
 use core::fmt::{self, Debug};
 
+use alloc::Vec;
+
 use error::Error;
 use sector::{Address, SectorSize};
-use volume::Volume;
+use volume::{Volume, VolumeCommit, WriteClass};
 
 /// Ext2 signature (0xef53), used to help confirm the presence of Ext2 on a
 /// volume
@@ -132,8 +134,9 @@ pub struct Superblock {
     pub prealloc_blocks_files: u8,
     /// Number of blocks to preallocate for directories
     pub prealloc_blocks_dirs: u8,
-    #[doc(hidden)]
-    _unused: [u8; 2],
+    /// Number of blocks reserved after each descriptor table for growing
+    /// the filesystem (see `FeaturesOptional::SELF_RESIZE`)
+    pub reserved_gdt_blocks: u16,
     /// Journal ID (same style as the File system ID above)
     pub journal_id: [u8; 16],
     /// Journal inode
@@ -142,8 +145,18 @@ pub struct Superblock {
     pub journal_dev: u32,
     /// Head of orphan inode list
     pub journal_orphan_head: u32,
+    /// Seed of the directory hashes (see `FeaturesOptional::HASH_INDEX`)
+    pub hash_seed: [u32; 4],
+    /// Default hash version of new directory indexes
+    pub def_hash_version: u8,
+    #[doc(hidden)]
+    _reserved_pad: [u8; 3],
+    #[doc(hidden)]
+    _reserved_ext: [u8; 96],
+    /// Miscellaneous flags
+    pub flags: FsFlags,
     #[doc(hidden)]
-    _reserved: [u8; 788],
+    _reserved: [u8; 668],
 }
 
 impl Debug for Superblock {
@@ -186,10 +199,14 @@ impl Debug for Superblock {
             .field("compression", unsafe { &self.compression })
             .field("prealloc_blocks_files", &self.prealloc_blocks_files)
             .field("prealloc_blocks_dirs", &self.prealloc_blocks_dirs)
+            .field("reserved_gdt_blocks", unsafe { &self.reserved_gdt_blocks })
             .field("journal_id", &self.journal_id)
             .field("journal_inode", unsafe { &self.journal_inode })
             .field("journal_dev", unsafe { &self.journal_dev })
             .field("journal_orphan_head", unsafe { &self.journal_orphan_head })
+            .field("hash_seed", unsafe { &self.hash_seed })
+            .field("def_hash_version", &self.def_hash_version)
+            .field("flags", unsafe { &self.flags })
             .finish()
     }
 }
@@ -198,7 +215,15 @@ impl Superblock {
     pub unsafe fn find<S: SectorSize, V: Volume<u8, S>>(
         haystack: &V,
     ) -> Result<(Superblock, Address<S>), Error> {
-        let offset = Address::from(1024_usize);
+        Superblock::find_at(haystack, Address::from(1024_usize))
+    }
+
+    /// Reads the superblock at `offset`, which is either the primary copy at
+    /// byte 1024 or a backup at the start of a block group
+    pub unsafe fn find_at<S: SectorSize, V: Volume<u8, S>>(
+        haystack: &V,
+        offset: Address<S>,
+    ) -> Result<(Superblock, Address<S>), Error> {
         let end = offset + Address::from(mem::size_of::<Superblock>());
         if haystack.size() < end {
             return Err(Error::AddressOutOfBounds {
@@ -208,10 +233,9 @@ impl Superblock {
             });
         }
 
-        let superblock = {
-            haystack
-                .slice_unchecked(offset..end)
-                .dynamic_cast::<Superblock>()
+        let superblock = match haystack.slice(offset..end) {
+            Ok(slice) => slice.dynamic_cast::<Superblock>(),
+            Err(err) => return Err(err.into()),
         };
 
         if superblock.0.magic != EXT2_MAGIC {
@@ -223,6 +247,84 @@ impl Superblock {
         }
     }
 
+    /// Scans the volume for backup superblocks, which are useful if the
+    /// primary copy is corrupt.
+    ///
+    /// For every block size from 1 KiB to 64 KiB, the first block of each of
+    /// the first `DENSE_SCAN_GROUPS` groups and of every sparse group after
+    /// them is checked, assuming the default of `8 * block_size` blocks per
+    /// group, e.g. blocks 8193, 16385, 24577... with 1 KiB blocks. A backup
+    /// is only returned if its block size and group size match its location.
+    pub unsafe fn find_backups<S: SectorSize, V: Volume<u8, S>>(
+        haystack: &V,
+    ) -> Vec<(Superblock, Address<S>)> {
+        let mut backups = Vec::new();
+        for log_block_size in 0..7 {
+            let block_size = 1024_u64 << log_block_size;
+            let blocks_per_group = block_size as u32 * 8;
+            let first_data_block = if log_block_size == 0 { 1 } else { 0 };
+
+            for group in 1_u64.. {
+                let block =
+                    first_data_block as u64 + group * blocks_per_group as u64;
+                let offset = Address::from(block * block_size);
+                let end = offset + Address::from(mem::size_of::<Superblock>());
+                if haystack.size() < end {
+                    break;
+                }
+
+                let group = group as u32;
+                if group >= DENSE_SCAN_GROUPS && !is_sparse_group(group) {
+                    continue;
+                }
+
+                let backup = Superblock::find_at(haystack, offset);
+                if let Ok((superblock, offset)) = backup {
+                    if superblock.log_block_size == log_block_size
+                        && superblock.blocks_per_group == blocks_per_group
+                        && superblock.first_data_block == first_data_block
+                    {
+                        backups.push((superblock, offset));
+                    }
+                }
+            }
+        }
+
+        backups.sort_by_key(|&(_, offset)| offset.into_index());
+        backups
+    }
+
+    /// Writes the superblock to `offset`, e.g. 1024 for the primary copy or
+    /// the first block of a block group for a backup
+    pub fn store<S: SectorSize, V: Volume<u8, S>>(
+        &self,
+        haystack: &mut V,
+        offset: Address<S>,
+    ) -> Result<(), Error> {
+        let end = offset + Address::from(mem::size_of::<Superblock>());
+        if haystack.size() < end {
+            return Err(Error::AddressOutOfBounds {
+                sector: end.sector(),
+                offset: end.offset(),
+                size: end.sector_size(),
+            });
+        }
+
+        let commit = VolumeCommit::from_cast(self, offset);
+        haystack
+            .commit_as(Some(commit), WriteClass::Superblock)
+            .map_err(|err| err.into())
+    }
+
+    /// Whether the block group `group` holds a backup of the superblock and
+    /// of the block group descriptor table. With sparse superblocks, only
+    /// groups 0, 1 and powers of 3, 5 and 7 do, otherwise every group does.
+    pub fn has_backup(&self, group: u32) -> bool {
+        let sparse = self.rev_major > 0
+            && self.features_ronly.contains(FeaturesROnly::RONLY_SPARSE);
+        !sparse || is_sparse_group(group)
+    }
+
     #[inline]
     pub fn block_size(&self) -> usize {
         1024 << self.log_block_size
@@ -234,11 +336,13 @@ impl Superblock {
     }
 
     pub fn block_group_count(&self) -> Result<u32, (u32, u32)> {
-        let blocks_mod = self.blocks_count % self.blocks_per_group;
+        // with 1 KiB blocks, block 0 doesn't belong to any group
+        let blocks_count = self.blocks_count - self.first_data_block;
+        let blocks_mod = blocks_count % self.blocks_per_group;
         let inodes_mod = self.inodes_count % self.inodes_per_group;
         let blocks_inc = if blocks_mod == 0 { 0 } else { 1 };
         let inodes_inc = if inodes_mod == 0 { 0 } else { 1 };
-        let by_blocks = self.blocks_count / self.blocks_per_group + blocks_inc;
+        let by_blocks = blocks_count / self.blocks_per_group + blocks_inc;
         let by_inodes = self.inodes_count / self.inodes_per_group + inodes_inc;
         if by_blocks == by_inodes {
             Ok(by_blocks)
@@ -248,6 +352,25 @@ impl Superblock {
     }
 }
 
+/// Number of consecutive block groups scanned by `Superblock::find_backups`,
+/// even if they're not sparse groups
+pub const DENSE_SCAN_GROUPS: u32 = 16;
+
+/// Whether `group` is 0, 1 or a power of 3, 5 or 7
+pub fn is_sparse_group(group: u32) -> bool {
+    fn is_power_of(mut n: u32, base: u32) -> bool {
+        while n > 1 && n % base == 0 {
+            n /= base;
+        }
+        n == 1
+    }
+
+    group <= 1
+        || is_power_of(group, 3)
+        || is_power_of(group, 5)
+        || is_power_of(group, 7)
+}
+
 bitflags! {
     /// Optional features
     pub struct FeaturesOptional: u32 {
@@ -293,6 +416,18 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Miscellaneous filesystem flags
+    pub struct FsFlags: u32 {
+        /// Directory hashes treat names as signed characters
+        const SIGNED_HASH = 0x0001;
+        /// Directory hashes treat names as unsigned characters
+        const UNSIGNED_HASH = 0x0002;
+        /// Filesystem is used to test development code
+        const TEST_FILESYS = 0x0004;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use sector::Size512;
@@ -312,6 +447,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn sparse() {
+        let groups = (0..50).filter(|&group| is_sparse_group(group));
+        assert_eq!(
+            groups.collect::<Vec<_>>(),
+            vec![0, 1, 3, 5, 7, 9, 25, 27, 49]
+        );
+    }
+
+    #[test]
+    fn store() {
+        let mut volume = vec![0_u8; 4096];
+        volume[1024 + 56] = EXT2_MAGIC as u8;
+        volume[1024 + 57] = (EXT2_MAGIC >> 8) as u8;
+        let (mut superblock, _) =
+            unsafe { Superblock::find::<Size512, _>(&volume).unwrap() };
+        superblock.block_group = 3;
+
+        let backup = Address::<Size512>::from(2048_u64);
+        assert!(superblock.store(&mut volume, backup).is_ok());
+        assert_eq!(volume[2048 + 56], EXT2_MAGIC as u8);
+        assert_eq!(volume[2048 + 90], 3);
+
+        let past_end = Address::<Size512>::from(3584_u64);
+        assert!(superblock.store(&mut volume, past_end).is_err());
+    }
+
     #[test]
     fn superblock() {
         use std::cell::RefCell;
@@ -0,0 +1,435 @@
+//! A read cache keeping the most recently used blocks of a volume in memory
+
+use core::cmp;
+use core::cell::RefCell;
+use core::marker::PhantomData;
+use core::ops::Range;
+
+use alloc::{BTreeMap, Vec};
+
+use sector::{Address, SectorSize};
+
+use super::{Volume, VolumeCommit, VolumeSlice, WriteClass};
+use super::size::Size;
+
+/// Counters of a `BlockCache`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CacheStats {
+    /// Blocks found in the cache
+    pub hits: u64,
+    /// Blocks read from the underlying volume
+    pub misses: u64,
+    /// Blocks dropped to make room for others
+    pub evictions: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    data: Vec<u8>,
+    /// Time of the last use
+    stamp: u64,
+}
+
+/// Cached blocks, indexed by their number and by the time of their last use
+#[derive(Debug, Clone)]
+struct Lru {
+    capacity: usize,
+    blocks: BTreeMap<u64, Entry>,
+    /// Numbers of cached blocks, least recently used first
+    order: BTreeMap<u64, u64>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Lru {
+        Lru {
+            capacity,
+            blocks: BTreeMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Looks up `block`, marking it as the most recently used one
+    fn get(&mut self, block: u64) -> Option<&[u8]> {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.blocks.get_mut(&block) {
+            Some(entry) => {
+                self.order.remove(&entry.stamp);
+                self.order.insert(clock, block);
+                entry.stamp = clock;
+                self.stats.hits += 1;
+                Some(&entry.data)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches `block`, evicting the least recently used blocks if the cache
+    /// is full
+    fn insert(&mut self, block: u64, data: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.remove(block);
+        while self.blocks.len() >= self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(&stamp) => stamp,
+                None => break,
+            };
+            if let Some(victim) = self.order.remove(&oldest) {
+                self.blocks.remove(&victim);
+                self.stats.evictions += 1;
+            }
+        }
+
+        self.clock += 1;
+        self.order.insert(self.clock, block);
+        let stamp = self.clock;
+        self.blocks.insert(block, Entry { data, stamp });
+    }
+
+    fn remove(&mut self, block: u64) {
+        if let Some(entry) = self.blocks.remove(&block) {
+            self.order.remove(&entry.stamp);
+        }
+    }
+}
+
+/// A `Volume` adaptor keeping up to `capacity` blocks of the underlying
+/// volume in memory, evicting the least recently used ones first.
+///
+/// Reads are served block by block, so that repeated reads of the same
+/// metadata (indirect blocks, descriptors, inode tables) don't reach the
+/// volume. Writes go straight through to the volume and update the cached
+/// copies of the blocks they touch.
+pub struct BlockCache<S: SectorSize, V: Volume<u8, S>> {
+    volume: V,
+    log_block_size: u32,
+    lru: RefCell<Lru>,
+    _phantom: PhantomData<S>,
+}
+
+impl<S: SectorSize, V: Volume<u8, S>> BlockCache<S, V> {
+    /// Wraps `volume` in a cache of `capacity` blocks of `block_size` bytes.
+    /// `block_size` should match the block size of the filesystem on
+    /// `volume`, but any power of two works.
+    pub fn new(
+        volume: V,
+        block_size: usize,
+        capacity: usize,
+    ) -> BlockCache<S, V> {
+        assert!(
+            block_size.is_power_of_two(),
+            "cache block size is not a power of two"
+        );
+        BlockCache {
+            volume,
+            log_block_size: block_size.trailing_zeros(),
+            lru: RefCell::new(Lru::new(capacity)),
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        1 << self.log_block_size
+    }
+
+    /// Maximum number of cached blocks
+    pub fn capacity(&self) -> usize {
+        self.lru.borrow().capacity
+    }
+
+    /// Number of currently cached blocks
+    pub fn len(&self) -> usize {
+        self.lru.borrow().blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.lru.borrow().stats
+    }
+
+    pub fn reset_stats(&self) {
+        self.lru.borrow_mut().stats = CacheStats::default();
+    }
+
+    /// Drops the cached copies of every block overlapping `range`, e.g.
+    /// after the volume was modified behind the back of the cache
+    pub fn invalidate(&self, range: Range<Address<S>>) {
+        let start = range.start.into_index();
+        let end = range.end.into_index();
+        if start >= end {
+            return;
+        }
+
+        let first = start >> self.log_block_size;
+        let last = (end - 1) >> self.log_block_size;
+        let mut lru = self.lru.borrow_mut();
+        let blocks = lru.blocks
+            .range(first..last + 1)
+            .map(|(&block, _)| block)
+            .collect::<Vec<_>>();
+        for block in blocks {
+            lru.remove(block);
+        }
+    }
+
+    /// Drops every cached block
+    pub fn clear(&self) {
+        let mut lru = self.lru.borrow_mut();
+        lru.blocks.clear();
+        lru.order.clear();
+    }
+
+    pub fn inner(&self) -> &V {
+        &self.volume
+    }
+
+    pub fn into_inner(self) -> V {
+        self.volume
+    }
+
+    /// Reads `range` block by block, loading missing blocks with `fetch`
+    fn read<E, F>(
+        &self,
+        range: Range<Address<S>>,
+        mut fetch: F,
+    ) -> Result<Vec<u8>, E>
+    where
+        F: FnMut(Range<Address<S>>) -> Result<Vec<u8>, E>,
+    {
+        let start = range.start.into_index();
+        let end = range.end.into_index();
+        let block_size = 1 << self.log_block_size;
+        // the last block of the volume might be shorter than the others
+        let size = match self.volume.size().try_len() {
+            Some(size) => size.into_index(),
+            None => u64::max_value(),
+        };
+
+        let mut data = Vec::with_capacity(end.saturating_sub(start) as usize);
+        let mut block = start >> self.log_block_size;
+        while block << self.log_block_size < end {
+            let block_start = block << self.log_block_size;
+            let from = (cmp::max(start, block_start) - block_start) as usize;
+            let to = (cmp::min(end, block_start + block_size) - block_start)
+                as usize;
+
+            let hit = {
+                let mut lru = self.lru.borrow_mut();
+                match lru.get(block) {
+                    Some(cached) => {
+                        data.extend_from_slice(&cached[from..to]);
+                        true
+                    }
+                    None => false,
+                }
+            };
+
+            if !hit {
+                let block_end = cmp::min(block_start + block_size, size);
+                let fetched = fetch(
+                    Address::from(block_start)..Address::from(block_end),
+                )?;
+                data.extend_from_slice(&fetched[from..to]);
+                self.lru.borrow_mut().insert(block, fetched);
+            }
+
+            block += 1;
+        }
+
+        Ok(data)
+    }
+
+    /// Copies `data`, about to be written at byte `start`, into the cached
+    /// blocks it overlaps
+    fn update(&self, start: u64, data: &[u8]) {
+        let end = start + data.len() as u64;
+        if start >= end {
+            return;
+        }
+
+        let first = start >> self.log_block_size;
+        let last = (end - 1) >> self.log_block_size;
+        let mut lru = self.lru.borrow_mut();
+        for (&block, entry) in lru.blocks.range_mut(first..last + 1) {
+            let block_start = block << self.log_block_size;
+            let from = cmp::max(start, block_start);
+            let to = cmp::min(end, block_start + entry.data.len() as u64);
+            if from < to {
+                let dst =
+                    (from - block_start) as usize..(to - block_start) as usize;
+                let src = (from - start) as usize..(to - start) as usize;
+                entry.data[dst].copy_from_slice(&data[src]);
+            }
+        }
+    }
+}
+
+impl<S: SectorSize, V: Volume<u8, S>> Volume<u8, S> for BlockCache<S, V> {
+    type Error = V::Error;
+
+    fn size(&self) -> Size<S> {
+        self.volume.size()
+    }
+
+    fn commit(
+        &mut self,
+        slice: Option<VolumeCommit<u8, S>>,
+    ) -> Result<(), Self::Error> {
+        self.commit_as(slice, WriteClass::Data)
+    }
+
+    fn commit_as(
+        &mut self,
+        slice: Option<VolumeCommit<u8, S>>,
+        class: WriteClass,
+    ) -> Result<(), Self::Error> {
+        let range = match slice {
+            Some(ref slice) => {
+                let start = slice.address();
+                self.update(start.into_index(), slice.as_ref());
+                Some(start..start + Address::from(slice.len()))
+            }
+            None => None,
+        };
+
+        let result = self.volume.commit_as(slice, class);
+        // the cached copies can't be trusted after a failed write
+        if let (true, Some(range)) = (result.is_err(), range) {
+            self.invalidate(range);
+        }
+        result
+    }
+
+    unsafe fn slice_unchecked<'a>(
+        &'a self,
+        range: Range<Address<S>>,
+    ) -> VolumeSlice<'a, u8, S> {
+        let index = range.start;
+        let data = self.read::<(), _>(range, |range| {
+            Ok(self.volume.slice_unchecked(range).to_vec())
+        });
+        match data {
+            Ok(data) => VolumeSlice::new_owned(data, index),
+            Err(()) => unreachable!(),
+        }
+    }
+
+    fn slice<'a>(
+        &'a self,
+        range: Range<Address<S>>,
+    ) -> Result<VolumeSlice<'a, u8, S>, Self::Error> {
+        let index = range.start;
+        // let the volume report out of bounds reads
+        if self.size() < range.end {
+            return self.volume
+                .slice(range)
+                .map(|slice| VolumeSlice::new_owned(slice.to_vec(), index));
+        }
+
+        self.read(range, |range| {
+            self.volume.slice(range).map(|slice| slice.to_vec())
+        }).map(|data| VolumeSlice::new_owned(data, index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ops::Range;
+
+    use genfs::Fs;
+
+    use sector::{Address, Size512};
+    use volume::Volume;
+    use fs::Ext2;
+    use fs::mkfs::FormatOptions;
+    use fs::mkfs::tests::formatted;
+    use fs::sync::Synced;
+
+    use super::{BlockCache, CacheStats};
+
+    fn range(start: u64, end: u64) -> Range<Address<Size512>> {
+        Address::from(start)..Address::from(end)
+    }
+
+    #[test]
+    fn lru() {
+        let volume = (0..4096).map(|i| (i / 1024) as u8).collect::<Vec<_>>();
+        let cache = BlockCache::<Size512, _>::new(volume, 1024, 2);
+
+        // spans blocks 0 and 1
+        let slice = cache.slice(range(1000, 1100)).unwrap();
+        assert_eq!(&slice[..24], &[0; 24][..]);
+        assert_eq!(&slice[24..], &[1; 76][..]);
+        assert_eq!(cache.len(), 2);
+
+        assert!(cache.slice(range(0, 10)).is_ok());
+        // evicts block 1, the least recently used one
+        assert!(cache.slice(range(2048, 2049)).is_ok());
+        assert!(cache.slice(range(0, 10)).is_ok());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 3,
+                evictions: 1,
+            }
+        );
+        assert!(cache.slice(range(1024, 1025)).is_ok());
+        assert_eq!(cache.stats().misses, 4);
+
+        assert!(cache.slice(range(4000, 4097)).is_err());
+    }
+
+    #[test]
+    fn write_through() {
+        let volume = vec![0_u8; 4096];
+        let mut cache = BlockCache::<Size512, _>::new(volume, 1024, 4);
+        assert!(cache.slice(range(0, 2048)).is_ok());
+
+        let commit = {
+            let mut slice = cache.slice(range(1020, 1030)).unwrap();
+            slice.iter_mut().for_each(|x| *x = 7);
+            slice.commit()
+        };
+        assert!(cache.commit(commit).is_ok());
+        assert_eq!(&cache.inner()[1020..1030], &[7; 10][..]);
+        assert_eq!(
+            &cache.slice(range(1018, 1032)).unwrap()[1..13],
+            &[0, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 0][..]
+        );
+
+        cache.reset_stats();
+        cache.invalidate(range(1023, 1024));
+        assert_eq!(cache.len(), 1);
+        assert!(cache.slice(range(0, 2048)).is_ok());
+        assert_eq!(cache.stats().misses, 1);
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn mounted() {
+        let volume = formatted(1024 * 1024, &FormatOptions::new());
+        let cache = BlockCache::new(volume, 1024, 16);
+        let fs = Synced::<Ext2<Size512, _>>::new(cache).unwrap();
+        for _ in 0..2 {
+            assert!(fs.metadata(b"/lost+found").is_ok());
+        }
+        let stats = fs.inner().volume().stats();
+        assert!(stats.hits > 0);
+        assert!(stats.misses > 0);
+    }
+}
@@ -0,0 +1,324 @@
+//! Geometry of the files and block devices backing a volume
+//!
+//! The metadata of a block special file, e.g. `/dev/sda1` or `/dev/loop0`,
+//! reports a length of 0, so the size of a device is asked to the kernel
+//! instead, falling back to seeking to its end.
+//!
+//! A `RefCell<File>` volume asks for its size whenever it's needed. `Device`
+//! asks once, when it's opened.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::ops::Range;
+
+use error::Error;
+use sector::{Address, SectorSize};
+
+use super::{Volume, VolumeCommit, VolumeSlice};
+use super::size::Size;
+use super::stream::{read_range, read_range_lossy, write_range};
+
+/// Sector sizes of a block device, in bytes. The logical sector size is the
+/// smallest unit the device can address; writes smaller than the physical
+/// sector size make the device read, modify and write back a whole sector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SectorSizes {
+    pub logical: u32,
+    pub physical: u32,
+}
+
+impl SectorSizes {
+    /// Whether sectors of `S` are a whole number of logical sectors, i.e.
+    /// whether `S` can be used to address the device
+    pub fn fits<S: SectorSize>(&self) -> bool {
+        S::SIZE % self.logical as usize == 0
+    }
+
+    /// Whether sectors of `S` are a whole number of physical sectors, so
+    /// that committing a sector never takes a read-modify-write cycle
+    pub fn fits_physical<S: SectorSize>(&self) -> bool {
+        S::SIZE % self.physical as usize == 0
+    }
+}
+
+impl Default for SectorSizes {
+    /// Regular files can be addressed by the byte; 512 is the smallest
+    /// sector size
+    fn default() -> SectorSizes {
+        SectorSizes {
+            logical: 512,
+            physical: 512,
+        }
+    }
+}
+
+/// A volume on a file or block device whose size and sector sizes are
+/// queried once when it's opened, so that they don't cost system calls on
+/// every access. The size is assumed not to change meanwhile.
+#[derive(Debug)]
+pub struct Device {
+    file: RefCell<File>,
+    len: u64,
+    sector_sizes: SectorSizes,
+}
+
+impl Device {
+    pub fn open(file: File) -> io::Result<Device> {
+        Ok(Device {
+            len: len(&file)?,
+            sector_sizes: sector_sizes(&file)?,
+            file: RefCell::new(file),
+        })
+    }
+
+    /// Size of the file or device in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn sector_sizes(&self) -> SectorSizes {
+        self.sector_sizes
+    }
+
+    pub fn get_mut(&mut self) -> &mut File {
+        self.file.get_mut()
+    }
+
+    pub fn into_inner(self) -> File {
+        self.file.into_inner()
+    }
+}
+
+impl<S: SectorSize> Volume<u8, S> for Device {
+    type Error = Error;
+
+    fn size(&self) -> Size<S> {
+        Size::Bounded(Address::from(self.len))
+    }
+
+    fn commit(
+        &mut self,
+        slice: Option<VolumeCommit<u8, S>>,
+    ) -> Result<(), Self::Error> {
+        slice
+            .map(|slice| write_range(self.file.get_mut(), &slice))
+            .unwrap_or(Ok(()))
+    }
+
+    /// Never panics: bytes which can't be read are left zeroed. Use `slice`
+    /// to have such failures reported.
+    unsafe fn slice_unchecked<'a>(
+        &'a self,
+        range: Range<Address<S>>,
+    ) -> VolumeSlice<'a, u8, S> {
+        let index = range.start;
+        let vec = read_range_lossy(&mut *self.file.borrow_mut(), range);
+        VolumeSlice::new_owned(vec, index)
+    }
+
+    fn slice<'a>(
+        &'a self,
+        range: Range<Address<S>>,
+    ) -> Result<VolumeSlice<'a, u8, S>, Self::Error> {
+        let index = range.start;
+        read_range(&mut *self.file.borrow_mut(), range)
+            .map(move |vec| VolumeSlice::new_owned(vec, index))
+    }
+}
+
+/// Whether `file` is a block special file
+pub fn is_block_device(file: &File) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        file.metadata()
+            .map(|data| data.file_type().is_block_device())
+    }
+    #[cfg(not(unix))]
+    {
+        file.metadata().map(|_| false)
+    }
+}
+
+/// Length of `file` in bytes, which for a block device is the size of the
+/// device
+pub fn len(file: &File) -> io::Result<u64> {
+    if !is_block_device(file)? {
+        return file.metadata().map(|data| data.len());
+    }
+
+    match ioctl::size(file) {
+        Ok(len) => Ok(len),
+        Err(_) => seek_len(file),
+    }
+}
+
+/// Sector sizes of `file`. Regular files get the default of 512 bytes.
+pub fn sector_sizes(file: &File) -> io::Result<SectorSizes> {
+    if is_block_device(file)? {
+        ioctl::sector_sizes(file)
+    } else {
+        Ok(SectorSizes::default())
+    }
+}
+
+/// Length of `file` found by seeking to its end. The position of the file
+/// is restored afterwards.
+fn seek_len(mut file: &File) -> io::Result<u64> {
+    let position = file.seek(SeekFrom::Current(0))?;
+    let len = file.seek(SeekFrom::End(0))?;
+    if position != len {
+        file.seek(SeekFrom::Start(position))?;
+    }
+    Ok(len)
+}
+
+#[cfg(target_os = "linux")]
+mod ioctl {
+    use std::fs::File;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    use libc::{self, c_int, BLKPBSZGET, BLKSSZGET};
+
+    use super::SectorSizes;
+
+    // the direction bits of ioctl numbers, from <asm/ioctl.h>
+    #[cfg(any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    ))]
+    const IOC_READ: u32 = 2 << 29;
+    #[cfg(not(any(
+        target_arch = "mips",
+        target_arch = "mips64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64",
+        target_arch = "sparc",
+        target_arch = "sparc64"
+    )))]
+    const IOC_READ: u32 = 2 << 30;
+
+    /// `_IOR(0x12, 114, size_t)` from <linux/fs.h>, which libc doesn't
+    /// provide
+    fn blkgetsize64() -> u32 {
+        IOC_READ | (mem::size_of::<usize>() as u32) << 16 | 0x12 << 8 | 114
+    }
+
+    pub fn size(file: &File) -> io::Result<u64> {
+        let mut size: u64 = 0;
+        let result = unsafe {
+            libc::ioctl(file.as_raw_fd(), blkgetsize64() as _, &mut size)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(size)
+    }
+
+    fn sector_size(file: &File, request: u32) -> io::Result<u32> {
+        let mut size: c_int = 0;
+        let result =
+            unsafe { libc::ioctl(file.as_raw_fd(), request as _, &mut size) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(size as u32)
+    }
+
+    pub fn sector_sizes(file: &File) -> io::Result<SectorSizes> {
+        Ok(SectorSizes {
+            logical: sector_size(file, BLKSSZGET as u32)?,
+            physical: sector_size(file, BLKPBSZGET as u32)?,
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod ioctl {
+    use std::fs::File;
+    use std::io;
+
+    use super::SectorSizes;
+
+    pub fn size(_file: &File) -> io::Result<u64> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "querying the size of a block device needs Linux",
+        ))
+    }
+
+    pub fn sector_sizes(_file: &File) -> io::Result<SectorSizes> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "querying the sector sizes of a block device needs Linux",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::process;
+
+    use sector::{Address, Size1024, Size512};
+    use volume::Volume;
+
+    use super::*;
+
+    #[test]
+    fn regular_file() {
+        let path =
+            env::temp_dir().join(format!("ext2-device-test-{}", process::id()));
+        {
+            let mut file = File::create(&path).unwrap();
+            file.write_all(&[0; 3000]).unwrap();
+        }
+
+        let mut file = OpenOptions::new().read(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(42)).unwrap();
+        assert!(!is_block_device(&file).unwrap());
+        assert_eq!(len(&file).unwrap(), 3000);
+        assert_eq!(seek_len(&file).unwrap(), 3000);
+        assert_eq!(file.seek(SeekFrom::Current(0)).unwrap(), 42);
+        assert_eq!(sector_sizes(&file).unwrap(), SectorSizes::default());
+
+        let device = Device::open(file).unwrap();
+        assert_eq!(device.len(), 3000);
+        assert_eq!(device.sector_sizes(), SectorSizes::default());
+        let start = Address::<Size512>::from(0_u64);
+        assert!(device.slice(start..Address::from(3000_u64)).is_ok());
+        assert!(device.slice(start..Address::from(3001_u64)).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fits() {
+        let sizes = SectorSizes {
+            logical: 512,
+            physical: 4096,
+        };
+        assert!(sizes.fits::<Size512>());
+        assert!(sizes.fits::<Size1024>());
+        assert!(!sizes.fits_physical::<Size1024>());
+
+        let sizes = SectorSizes {
+            logical: 1024,
+            physical: 1024,
+        };
+        assert!(!sizes.fits::<Size512>());
+        assert!(sizes.fits_physical::<Size1024>());
+    }
+}
@@ -10,6 +10,12 @@ use error::Error;
 use sector::{Address, SectorSize};
 
 pub mod size;
+pub mod cache;
+pub mod writeback;
+#[cfg(any(test, not(feature = "no_std")))]
+pub mod stream;
+#[cfg(any(test, not(feature = "no_std")))]
+pub mod device;
 use self::size::Size;
 
 pub trait Volume<T: Clone, S: SectorSize> {
@@ -20,6 +26,15 @@ pub trait Volume<T: Clone, S: SectorSize> {
         &mut self,
         slice: Option<VolumeCommit<T, S>>,
     ) -> Result<(), Self::Error>;
+    /// Like `commit`, telling the volume what `slice` holds. Only write-back
+    /// layers care about it, other volumes just commit the slice.
+    fn commit_as(
+        &mut self,
+        slice: Option<VolumeCommit<T, S>>,
+        _class: WriteClass,
+    ) -> Result<(), Self::Error> {
+        self.commit(slice)
+    }
     unsafe fn slice_unchecked<'a>(
         &'a self,
         range: Range<Address<S>>,
@@ -31,6 +46,20 @@ pub trait Volume<T: Clone, S: SectorSize> {
     ) -> Result<VolumeSlice<'a, T, S>, Self::Error>;
 }
 
+/// The kind of data held by a commit. Write-back layers flush dirty blocks
+/// in this order, so that metadata never points to blocks that weren't
+/// written yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum WriteClass {
+    /// File contents and indirect blocks
+    Data,
+    Inode,
+    Directory,
+    Bitmap,
+    /// The superblock and the block group descriptors
+    Superblock,
+}
+
 #[derive(Debug, Clone, PartialEq, Hash)]
 pub struct VolumeSlice<'a, T: 'a + Clone, S: SectorSize> {
     inner: Cow<'a, [T]>,
@@ -159,6 +188,16 @@ impl<T: Clone, S: SectorSize> VolumeCommit<T, S> {
     }
 }
 
+impl<S: SectorSize> VolumeCommit<u8, S> {
+    pub fn from_cast<T: Copy>(
+        cast: &T,
+        index: Address<S>,
+    ) -> VolumeCommit<u8, S> {
+        let inner = VolumeSlice::from_cast(cast, index).to_vec();
+        VolumeCommit::new(inner, index)
+    }
+}
+
 impl<T: Clone, S: SectorSize> AsRef<[T]> for VolumeCommit<T, S> {
     fn as_ref(&self) -> &[T] {
         self.inner.as_ref()
@@ -258,23 +297,27 @@ impl_slice!(Box<[T]>);
 #[cfg(any(test, not(feature = "no_std")))]
 mod file {
     use std::ops::Range;
-    use std::io::{self, Read, Seek, SeekFrom, Write};
     use std::fs::File;
     use std::cell::RefCell;
 
+    use error::Error;
     use sector::{Address, SectorSize};
 
     use super::{Volume, VolumeCommit, VolumeSlice};
     use super::size::Size;
+    use super::device;
+    use super::stream::{read_range, read_range_lossy, write_range};
 
     impl<S: SectorSize> Volume<u8, S> for RefCell<File> {
-        type Error = io::Error;
+        type Error = Error;
 
+        /// The length of the file, or the size of the device if it's a
+        /// block special file. It's queried on every call; `device::Device`
+        /// queries it once instead.
         fn size(&self) -> Size<S> {
             Size::Bounded(
-                self.borrow()
-                    .metadata()
-                    .map(|data| Address::from(data.len()))
+                device::len(&self.borrow())
+                    .map(Address::from)
                     .unwrap_or(Address::new(0, 0)),
             )
         }
@@ -284,32 +327,19 @@ mod file {
             slice: Option<VolumeCommit<u8, S>>,
         ) -> Result<(), Self::Error> {
             slice
-                .map(|slice| {
-                    let index = slice.address();
-                    let mut refmut = self.borrow_mut();
-                    refmut
-                        .seek(SeekFrom::Start(index.into_index()))
-                        .and_then(|_| refmut.write(slice.as_ref()))
-                        .map(|_| ())
-                })
+                .map(|slice| write_range(self.get_mut(), &slice))
                 .unwrap_or(Ok(()))
         }
 
+        /// Never panics: bytes which can't be read, e.g. past the end of a
+        /// file truncated since its size was checked, are left zeroed. Use
+        /// `slice` to have such failures reported.
         unsafe fn slice_unchecked<'a>(
             &'a self,
             range: Range<Address<S>>,
         ) -> VolumeSlice<'a, u8, S> {
             let index = range.start;
-            let len = range.end - range.start;
-            let mut vec = Vec::with_capacity(len.into_index() as usize);
-            vec.set_len(len.into_index() as usize);
-            let mut refmut = self.borrow_mut();
-            refmut
-                .seek(SeekFrom::Start(index.into_index()))
-                .and_then(|_| refmut.read_exact(&mut vec[..]))
-                .unwrap_or_else(|err| {
-                    panic!("could't read from File Volume: {:?}", err)
-                });
+            let vec = read_range_lossy(&mut *self.borrow_mut(), range);
             VolumeSlice::new_owned(vec, index)
         }
 
@@ -318,17 +348,8 @@ mod file {
             range: Range<Address<S>>,
         ) -> Result<VolumeSlice<'a, u8, S>, Self::Error> {
             let index = range.start;
-            let mut vec = Vec::with_capacity((range.end - range.start)
-                .into_index()
-                as usize);
-            unsafe {
-                vec.set_len((range.end - range.start).into_index() as usize);
-            }
-            let mut refmut = self.borrow_mut();
-            refmut
-                .seek(SeekFrom::Start(index.into_index()))
-                .and_then(|_| refmut.read_exact(&mut vec[..]))
-                .map(move |_| VolumeSlice::new_owned(vec, index))
+            read_range(&mut *self.borrow_mut(), range)
+                .map(move |vec| VolumeSlice::new_owned(vec, index))
         }
     }
 }
@@ -0,0 +1,326 @@
+//! Volumes backed by any seekable `std::io` stream
+
+use std::cell::RefCell;
+use std::fmt::{self, Debug};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use error::Error;
+use sector::{Address, SectorSize};
+
+use super::{Volume, VolumeCommit, VolumeSlice};
+use super::size::Size;
+
+/// A volume reading from a `Read + Seek` stream, e.g. a `Cursor<Vec<u8>>` or
+/// an open block device. Streams created with `new` are committed to as
+/// well; commits to those created with `read_only` fail with
+/// `Error::ReadOnly`.
+///
+/// The size of the volume is the length of the stream, found by seeking to
+/// its end when the volume is created, so that it works for anything
+/// seekable and not just for files. It only changes through commits past
+/// the end.
+pub struct Stream<T> {
+    inner: RefCell<T>,
+    len: u64,
+    writer: Option<Writer<T>>,
+}
+
+/// Writing half of a stream, only available if it's `Write`
+struct Writer<T> {
+    write: fn(&mut T, u64, &[u8]) -> io::Result<()>,
+    flush: fn(&mut T) -> io::Result<()>,
+}
+
+impl<T: Read + Write + Seek> Stream<T> {
+    pub fn new(inner: T) -> io::Result<Stream<T>> {
+        let mut stream = Stream::read_only(inner)?;
+        stream.writer = Some(Writer {
+            write: write_at::<T>,
+            flush: T::flush,
+        });
+        Ok(stream)
+    }
+}
+
+impl<T: Read + Seek> Stream<T> {
+    pub fn read_only(mut inner: T) -> io::Result<Stream<T>> {
+        let len = stream_len(&mut inner)?;
+        Ok(Stream {
+            inner: RefCell::new(inner),
+            len,
+            writer: None,
+        })
+    }
+}
+
+impl<T> Stream<T> {
+    /// Length of the stream in bytes, as found when the volume was created
+    /// and extended by commits past its end
+    pub fn stream_len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.writer.is_none()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: Debug> Debug for Stream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stream")
+            .field("inner", &self.inner)
+            .field("len", &self.len)
+            .field("read_only", &self.is_read_only())
+            .finish()
+    }
+}
+
+/// Length of `inner` in bytes. Its position is restored afterwards.
+fn stream_len<T: Seek>(inner: &mut T) -> io::Result<u64> {
+    let position = inner.seek(SeekFrom::Current(0))?;
+    let len = inner.seek(SeekFrom::End(0))?;
+    if position != len {
+        inner.seek(SeekFrom::Start(position))?;
+    }
+    Ok(len)
+}
+
+impl<S, T> Volume<u8, S> for Stream<T>
+where
+    S: SectorSize,
+    T: Read + Seek,
+{
+    type Error = Error;
+
+    fn size(&self) -> Size<S> {
+        Size::Bounded(Address::from(self.len))
+    }
+
+    fn commit(
+        &mut self,
+        slice: Option<VolumeCommit<u8, S>>,
+    ) -> Result<(), Self::Error> {
+        let writer = match self.writer {
+            Some(ref writer) => writer,
+            None if slice.is_none() => return Ok(()),
+            None => return Err(Error::ReadOnly),
+        };
+        let inner = self.inner.get_mut();
+        match slice {
+            Some(slice) => {
+                let offset = slice.address().into_index();
+                (writer.write)(inner, offset, slice.as_ref())?;
+                self.len = self.len.max(offset + slice.len() as u64);
+                Ok(())
+            }
+            None => (writer.flush)(inner).map_err(|err| err.into()),
+        }
+    }
+
+    /// Never panics: bytes which can't be read are left zeroed. Use `slice`
+    /// to have such failures reported.
+    unsafe fn slice_unchecked<'a>(
+        &'a self,
+        range: Range<Address<S>>,
+    ) -> VolumeSlice<'a, u8, S> {
+        let index = range.start;
+        let vec = read_range_lossy(&mut *self.inner.borrow_mut(), range);
+        VolumeSlice::new_owned(vec, index)
+    }
+
+    fn slice<'a>(
+        &'a self,
+        range: Range<Address<S>>,
+    ) -> Result<VolumeSlice<'a, u8, S>, Self::Error> {
+        let index = range.start;
+        read_range(&mut *self.inner.borrow_mut(), range)
+            .map(move |vec| VolumeSlice::new_owned(vec, index))
+    }
+}
+
+/// Fills `buf` from `offset` onwards, retrying interrupted reads. Returns
+/// how many bytes were read before the end of the stream.
+fn read_at<T: Read + Seek>(
+    inner: &mut T,
+    offset: u64,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    inner.seek(SeekFrom::Start(offset))?;
+    let mut read = 0;
+    while read < buf.len() {
+        match inner.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+/// Reads `range` from `inner`. Hitting the end of the stream before the end
+/// of the range is an `Error::ShortRead`.
+pub(crate) fn read_range<S: SectorSize, T: Read + Seek>(
+    inner: &mut T,
+    range: Range<Address<S>>,
+) -> Result<Vec<u8>, Error> {
+    let len = (range.end - range.start).into_index() as usize;
+    let mut vec = vec![0; len];
+    let read = read_at(inner, range.start.into_index(), &mut vec[..])?;
+    if read < len {
+        return Err(Error::ShortRead {
+            sector: range.start.sector(),
+            offset: range.start.offset(),
+            expected: len,
+            read,
+        });
+    }
+    Ok(vec)
+}
+
+/// Reads `range` from `inner`, leaving whatever couldn't be read zeroed
+pub(crate) fn read_range_lossy<S: SectorSize, T: Read + Seek>(
+    inner: &mut T,
+    range: Range<Address<S>>,
+) -> Vec<u8> {
+    let len = (range.end - range.start).into_index() as usize;
+    let mut vec = vec![0; len];
+    let _ = read_at(inner, range.start.into_index(), &mut vec[..]);
+    vec
+}
+
+/// Writes all of `buf` to `inner` at `offset`
+fn write_at<T: Write + Seek>(
+    inner: &mut T,
+    offset: u64,
+    buf: &[u8],
+) -> io::Result<()> {
+    inner.seek(SeekFrom::Start(offset))?;
+    inner.write_all(buf)
+}
+
+/// Writes all of `slice` to `inner` at its address
+pub(crate) fn write_range<S: SectorSize, T: Write + Seek>(
+    inner: &mut T,
+    slice: &VolumeCommit<u8, S>,
+) -> Result<(), Error> {
+    write_at(inner, slice.address().into_index(), slice.as_ref())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+    use genfs::{DirOptions, Fs};
+
+    use error::Error;
+    use sector::{Address, Size512};
+    use volume::{Volume, VolumeCommit};
+    use fs::Ext2;
+    use fs::mkfs::{format, FormatOptions};
+    use fs::mkfs::tests::{formatted, mount_rw};
+    use fs::sync::Synced;
+
+    use super::Stream;
+
+    #[test]
+    fn cursor() {
+        let mut cursor = Cursor::new(vec![0_u8; 1024 * 1024]);
+        cursor.seek(SeekFrom::Start(42)).unwrap();
+        let mut volume = Stream::new(cursor).unwrap();
+        assert_eq!(volume.stream_len(), 1024 * 1024);
+        assert_eq!(volume.get_mut().position(), 42);
+
+        // formatted through the stream itself
+        let options = FormatOptions::new();
+        assert!(format::<Size512, _>(&mut volume, &options).is_ok());
+        let mut fs = mount_rw(volume);
+        assert!(fs.create_dir(b"/foo", &DirOptions::new()).is_ok());
+        assert!(fs.inner().unmount().is_ok());
+        assert!(fs.find_inode(b"/foo").unwrap().is_dir());
+    }
+
+    #[test]
+    fn read_only() {
+        let data = formatted(1024 * 1024, &FormatOptions::new());
+        // a cursor over a slice can't be written to
+        let mut volume = Stream::read_only(Cursor::new(&data[..])).unwrap();
+        assert!(volume.is_read_only());
+        let end = Address::<Size512>::from(1024 * 1024 + 1_u64);
+        let start = Address::<Size512>::from(0_u64);
+        assert!(volume.slice(start..end).is_err());
+        let commit = VolumeCommit::new(vec![1; 4], start);
+        match volume.commit(Some(commit)) {
+            Err(Error::ReadOnly) => (),
+            other => panic!("expected a read-only error, got {:?}", other),
+        }
+        assert!(Volume::<u8, Size512>::commit(&mut volume, None).is_ok());
+
+        let fs = Synced::<Ext2<Size512, _>>::new(volume).unwrap();
+        assert!(fs.find_inode(b"/lost+found").unwrap().is_dir());
+    }
+
+    /// A stream claiming to be longer than it is, like a file truncated
+    /// after its size was checked
+    struct Truncated(Cursor<Vec<u8>>);
+
+    impl Read for Truncated {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Truncated {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Truncated {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            match pos {
+                SeekFrom::End(_) => Ok(1024 * 1024),
+                pos => self.0.seek(pos),
+            }
+        }
+    }
+
+    #[test]
+    fn short_read() {
+        let mut data = formatted(1024 * 1024, &FormatOptions::new());
+        data.truncate(1500);
+
+        let volume = Stream::new(Truncated(Cursor::new(data))).unwrap();
+        let start = Address::<Size512>::from(1024_u64);
+        let end = Address::<Size512>::from(2048_u64);
+        match volume.slice(start..end) {
+            Err(Error::ShortRead {
+                expected: 1024,
+                read: 476,
+                ..
+            }) => (),
+            other => panic!("expected a short read, got {:?}", other.err()),
+        }
+        let slice = unsafe { volume.slice_unchecked(start..end) };
+        assert!(slice[476..].iter().all(|&x| x == 0));
+
+        match Ext2::<Size512, _>::new(volume) {
+            Err(Error::ShortRead { .. }) => (),
+            other => panic!("expected a short read, got {:?}", other.err()),
+        }
+    }
+}
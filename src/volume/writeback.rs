@@ -0,0 +1,453 @@
+//! A write-back layer buffering commits in memory until they're flushed
+
+use core::cmp;
+use core::marker::PhantomData;
+use core::ops::Range;
+
+use alloc::{BTreeMap, Vec};
+
+use sector::{Address, SectorSize};
+
+use super::{Volume, VolumeCommit, VolumeSlice, WriteClass};
+use super::size::Size;
+
+#[derive(Debug, Clone)]
+struct Dirty {
+    data: Vec<u8>,
+    class: WriteClass,
+    /// Time of the last write
+    stamp: u64,
+}
+
+/// A `Volume` adaptor keeping commits in memory as dirty blocks instead of
+/// writing them immediately.
+///
+/// Commits touching the same blocks are merged, and adjacent dirty blocks
+/// of the same `WriteClass` are written with a single commit. Dirty blocks
+/// are written when the layer is flushed, either with `flush` or by
+/// committing `None` (which `Ext2::unmount` does), and when more than
+/// `capacity` blocks are dirty. In both cases they're written in the order
+/// of their `WriteClass`: a block is never written before the dirty blocks
+/// of the classes preceding its own.
+pub struct WriteBack<S: SectorSize, V: Volume<u8, S>> {
+    volume: V,
+    log_block_size: u32,
+    capacity: usize,
+    dirty: BTreeMap<u64, Dirty>,
+    /// Numbers of dirty blocks, least recently written first
+    order: BTreeMap<u64, u64>,
+    clock: u64,
+    _phantom: PhantomData<S>,
+}
+
+impl<S: SectorSize, V: Volume<u8, S>> WriteBack<S, V> {
+    /// Buffers up to `capacity` dirty blocks of `block_size` bytes in front
+    /// of `volume`
+    pub fn new(
+        volume: V,
+        block_size: usize,
+        capacity: usize,
+    ) -> WriteBack<S, V> {
+        assert!(
+            block_size.is_power_of_two(),
+            "write-back block size is not a power of two"
+        );
+        WriteBack {
+            volume,
+            log_block_size: block_size.trailing_zeros(),
+            capacity,
+            dirty: BTreeMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        1 << self.log_block_size
+    }
+
+    /// Maximum number of dirty blocks kept in memory
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of blocks waiting to be written
+    pub fn dirty_count(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// Class of the dirty block `block`, `None` if it's clean
+    pub fn dirty_class(&self, block: u64) -> Option<WriteClass> {
+        self.dirty.get(&block).map(|dirty| dirty.class)
+    }
+
+    pub fn inner(&self) -> &V {
+        &self.volume
+    }
+
+    /// Flushes every dirty block and returns the underlying volume
+    pub fn into_inner(mut self) -> Result<V, V::Error> {
+        self.flush()?;
+        Ok(self.volume)
+    }
+
+    /// Writes every dirty block to the volume, in the order of their
+    /// `WriteClass`, and flushes the volume itself
+    pub fn flush(&mut self) -> Result<(), V::Error> {
+        self.write_out(None)?;
+        self.volume.commit(None)
+    }
+
+    /// Writes the dirty blocks of the classes preceding `before`, or every
+    /// dirty block if it's `None`
+    fn write_out(
+        &mut self,
+        before: Option<WriteClass>,
+    ) -> Result<(), V::Error> {
+        let mut blocks = self.dirty
+            .iter()
+            .map(|(&block, dirty)| (dirty.class, block))
+            .filter(|&(class, _)| before.map_or(true, |before| class < before))
+            .collect::<Vec<_>>();
+        blocks.sort();
+
+        let mut start = 0;
+        while start < blocks.len() {
+            let (class, first) = blocks[start];
+            let mut end = start + 1;
+            while end < blocks.len()
+                && blocks[end] == (class, first + (end - start) as u64)
+            {
+                end += 1;
+            }
+
+            let run = blocks[start..end]
+                .iter()
+                .map(|&(_, block)| block)
+                .collect::<Vec<_>>();
+            self.write_run(&run, class)?;
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the consecutive dirty blocks `run` with a single commit
+    fn write_run(
+        &mut self,
+        run: &[u64],
+        class: WriteClass,
+    ) -> Result<(), V::Error> {
+        let mut data = Vec::with_capacity(run.len() << self.log_block_size);
+        for block in run {
+            data.extend_from_slice(&self.dirty[block].data);
+        }
+
+        let addr = Address::from(run[0] << self.log_block_size);
+        self.volume
+            .commit_as(Some(VolumeCommit::new(data, addr)), class)?;
+
+        for block in run {
+            if let Some(dirty) = self.dirty.remove(block) {
+                self.order.remove(&dirty.stamp);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the least recently written blocks until at most `capacity`
+    /// blocks are dirty
+    fn evict(&mut self) -> Result<(), V::Error> {
+        while self.dirty.len() > self.capacity {
+            let victim = match self.order.values().next() {
+                Some(&block) => block,
+                None => break,
+            };
+            let class = self.dirty[&victim].class;
+
+            // everything the victim might depend on goes first
+            self.write_out(Some(class))?;
+            self.write_run(&[victim], class)?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the dirty blocks overlapping `data`, read from byte `start`
+    fn overlay(&self, start: u64, data: &mut [u8]) {
+        let end = start + data.len() as u64;
+        if start >= end {
+            return;
+        }
+
+        let first = start >> self.log_block_size;
+        let last = (end - 1) >> self.log_block_size;
+        for (&block, dirty) in self.dirty.range(first..last + 1) {
+            let block_start = block << self.log_block_size;
+            let from = cmp::max(start, block_start);
+            let to = cmp::min(end, block_start + dirty.data.len() as u64);
+            if from < to {
+                let src =
+                    (from - block_start) as usize..(to - block_start) as usize;
+                let dst = (from - start) as usize..(to - start) as usize;
+                data[dst].copy_from_slice(&dirty.data[src]);
+            }
+        }
+    }
+}
+
+impl<S: SectorSize, V: Volume<u8, S>> Volume<u8, S> for WriteBack<S, V> {
+    type Error = V::Error;
+
+    fn size(&self) -> Size<S> {
+        self.volume.size()
+    }
+
+    fn commit(
+        &mut self,
+        slice: Option<VolumeCommit<u8, S>>,
+    ) -> Result<(), Self::Error> {
+        self.commit_as(slice, WriteClass::Data)
+    }
+
+    fn commit_as(
+        &mut self,
+        slice: Option<VolumeCommit<u8, S>>,
+        class: WriteClass,
+    ) -> Result<(), Self::Error> {
+        let slice = match slice {
+            Some(slice) => slice,
+            None => return self.flush(),
+        };
+
+        let start = slice.address().into_index();
+        let end = start + slice.len() as u64;
+        // let the volume deal with commits past its end
+        if self.size() < Address::from(end) {
+            return self.volume.commit_as(Some(slice), class);
+        }
+
+        let block_size = 1 << self.log_block_size;
+        // the last block of the volume might be shorter than the others
+        let size = match self.volume.size().try_len() {
+            Some(size) => size.into_index(),
+            None => u64::max_value(),
+        };
+
+        let mut block = start >> self.log_block_size;
+        while block << self.log_block_size < end {
+            let block_start = block << self.log_block_size;
+            let block_end = cmp::min(block_start + block_size, size);
+            let from = cmp::max(start, block_start);
+            let to = cmp::min(end, block_end);
+
+            if !self.dirty.contains_key(&block) {
+                // a block overwritten as a whole doesn't need to be read
+                let data = if from == block_start && to == block_end {
+                    vec![0; (block_end - block_start) as usize]
+                } else {
+                    let range =
+                        Address::from(block_start)..Address::from(block_end);
+                    self.volume.slice(range)?.to_vec()
+                };
+                let dirty = Dirty {
+                    data,
+                    class,
+                    stamp: 0,
+                };
+                self.dirty.insert(block, dirty);
+            }
+
+            self.clock += 1;
+            let clock = self.clock;
+            {
+                let dirty = self.dirty.get_mut(&block).unwrap();
+                self.order.remove(&dirty.stamp);
+                dirty.stamp = clock;
+                dirty.class = cmp::max(dirty.class, class);
+                let dst =
+                    (from - block_start) as usize..(to - block_start) as usize;
+                let src = (from - start) as usize..(to - start) as usize;
+                dirty.data[dst].copy_from_slice(&slice[src]);
+            }
+            self.order.insert(clock, block);
+
+            block += 1;
+        }
+
+        self.evict()
+    }
+
+    unsafe fn slice_unchecked<'a>(
+        &'a self,
+        range: Range<Address<S>>,
+    ) -> VolumeSlice<'a, u8, S> {
+        let index = range.start;
+        let mut data = self.volume.slice_unchecked(range).to_vec();
+        self.overlay(index.into_index(), &mut data);
+        VolumeSlice::new_owned(data, index)
+    }
+
+    fn slice<'a>(
+        &'a self,
+        range: Range<Address<S>>,
+    ) -> Result<VolumeSlice<'a, u8, S>, Self::Error> {
+        let index = range.start;
+        let mut data = self.volume.slice(range)?.to_vec();
+        self.overlay(index.into_index(), &mut data);
+        Ok(VolumeSlice::new_owned(data, index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ops::Range;
+
+    use genfs::{DirOptions, Fs};
+
+    use error::Error;
+    use sector::{Address, Size512};
+    use volume::{Volume, VolumeCommit, VolumeSlice, WriteClass};
+    use volume::size::Size;
+    use fs::check::check;
+    use fs::mkfs::FormatOptions;
+    use fs::mkfs::tests::{formatted, mount_rw};
+
+    use super::WriteBack;
+
+    /// A volume recording the address, length and class of every commit
+    struct Recorder {
+        inner: Vec<u8>,
+        commits: Vec<(u64, usize, WriteClass)>,
+    }
+
+    impl Volume<u8, Size512> for Recorder {
+        type Error = Error;
+
+        fn size(&self) -> Size<Size512> {
+            self.inner.size()
+        }
+
+        fn commit(
+            &mut self,
+            slice: Option<VolumeCommit<u8, Size512>>,
+        ) -> Result<(), Error> {
+            self.commit_as(slice, WriteClass::Data)
+        }
+
+        fn commit_as(
+            &mut self,
+            slice: Option<VolumeCommit<u8, Size512>>,
+            class: WriteClass,
+        ) -> Result<(), Error> {
+            if let Some(ref slice) = slice {
+                let addr = slice.address().into_index();
+                self.commits.push((addr, slice.len(), class));
+            }
+            self.inner.commit(slice)
+        }
+
+        unsafe fn slice_unchecked<'a>(
+            &'a self,
+            range: Range<Address<Size512>>,
+        ) -> VolumeSlice<'a, u8, Size512> {
+            self.inner.slice_unchecked(range)
+        }
+
+        fn slice<'a>(
+            &'a self,
+            range: Range<Address<Size512>>,
+        ) -> Result<VolumeSlice<'a, u8, Size512>, Error> {
+            self.inner.slice(range)
+        }
+    }
+
+    fn recorder() -> Recorder {
+        Recorder {
+            inner: vec![0; 8 * 1024],
+            commits: Vec::new(),
+        }
+    }
+
+    fn commit(addr: u64, len: usize, byte: u8) -> VolumeCommit<u8, Size512> {
+        VolumeCommit::new(vec![byte; len], Address::from(addr))
+    }
+
+    #[test]
+    fn buffered() {
+        let mut volume = WriteBack::new(recorder(), 1024, 8);
+        assert!(volume.commit(Some(commit(1020, 10, 1))).is_ok());
+        assert!(volume.commit(Some(commit(1022, 4, 2))).is_ok());
+        assert_eq!(volume.dirty_count(), 2);
+        assert!(volume.inner().commits.is_empty());
+
+        let range = Address::from(1018_u64)..Address::from(1032_u64);
+        assert_eq!(
+            &volume.slice(range).unwrap()[..],
+            &[0, 0, 1, 1, 2, 2, 2, 2, 1, 1, 1, 1, 0, 0][..]
+        );
+
+        let volume = volume.into_inner().unwrap();
+        // both blocks go out in a single commit
+        assert_eq!(volume.commits, [(0, 2048, WriteClass::Data)]);
+        assert_eq!(&volume.inner[1020..1030], &[1, 1, 2, 2, 2, 2, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn ordered() {
+        let mut volume = WriteBack::new(recorder(), 1024, 8);
+        let commits = [
+            (1024, WriteClass::Superblock),
+            (3072, WriteClass::Bitmap),
+            (5120, WriteClass::Directory),
+            (4096, WriteClass::Inode),
+            (7168, WriteClass::Data),
+            (6144, WriteClass::Data),
+        ];
+        for &(addr, class) in commits.iter() {
+            let slice = Some(commit(addr, 1024, 1));
+            assert!(volume.commit_as(slice, class).is_ok());
+        }
+        assert!(volume.flush().is_ok());
+        assert_eq!(
+            volume.inner().commits,
+            [
+                (6144, 2048, WriteClass::Data),
+                (4096, 1024, WriteClass::Inode),
+                (5120, 1024, WriteClass::Directory),
+                (3072, 1024, WriteClass::Bitmap),
+                (1024, 1024, WriteClass::Superblock),
+            ]
+        );
+
+        // evicting the superblock writes the inode it depends on first
+        let mut volume = WriteBack::new(recorder(), 1024, 1);
+        let slice = Some(commit(1024, 16, 1));
+        assert!(volume.commit_as(slice, WriteClass::Superblock).is_ok());
+        let slice = Some(commit(4096, 128, 2));
+        assert!(volume.commit_as(slice, WriteClass::Inode).is_ok());
+        assert_eq!(volume.dirty_count(), 0);
+        assert_eq!(
+            volume.inner().commits,
+            [
+                (4096, 1024, WriteClass::Inode),
+                (1024, 1024, WriteClass::Superblock),
+            ]
+        );
+    }
+
+    #[test]
+    fn mounted() {
+        let volume = formatted(1024 * 1024, &FormatOptions::new());
+        let mut fs = mount_rw(WriteBack::new(volume, 1024, 64));
+        assert!(fs.create_dir(b"/foo", &DirOptions::new()).is_ok());
+        assert!(fs.find_inode(b"/foo").unwrap().is_dir());
+        assert!(fs.inner().volume().dirty_count() > 0);
+
+        assert!(fs.inner().unmount().is_ok());
+        assert_eq!(fs.inner().volume().dirty_count(), 0);
+        let report = check(&fs).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+}